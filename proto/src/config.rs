@@ -0,0 +1,161 @@
+// Startup configuration shared by every sentinel binary.
+//
+// All services read the same TOML file, named by `SENTINEL_CONFIG`, each
+// from its own table (`[star]`, `[store]`, `[sonar]`, `[satellite]`). Any
+// key can be overridden by an environment variable named after the table
+// and the key, e.g. `STAR_MAX_KEY_LEN` or `SATELLITE_LISTEN`. Relative
+// paths resolve against the top-level `dir` (`SENTINEL_DIR`), which
+// defaults to `/tmp/sentinel`, so pointing `SENTINEL_DIR` elsewhere is
+// enough to run a second, isolated stack.
+//
+// Every table may set `socket`, the path of that service's socket, so
+// satellite finds the daemons where they listen.
+
+use std::{
+    env, fmt, fs,
+    path::{Path, PathBuf},
+};
+use serde::{de::DeserializeOwned, Serialize};
+use toml::{Table, Value};
+
+pub const DEFAULT_DIR: &str = "/tmp/sentinel";
+const SOCKET_KEY: &str = "socket";
+
+#[derive(Debug)]
+pub struct ConfigError(String);
+
+impl ConfigError {
+    pub fn new(message: impl Into<String>) -> Self {
+        ConfigError(message.into())
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// The parsed config file, with `dir` already overridden from the
+/// environment.
+#[derive(Debug)]
+pub struct Settings {
+    dir: PathBuf,
+    file: Table,
+}
+
+impl Settings {
+    /// Reads `SENTINEL_CONFIG` if set; without it every service runs on
+    /// defaults and environment overrides.
+    pub fn load() -> Result<Settings, ConfigError> {
+        let mut file = match env::var("SENTINEL_CONFIG") {
+            Ok(path) => {
+                let text = fs::read_to_string(&path)
+                    .map_err(|err| ConfigError(format!("failed to read {}: {}", path, err)))?;
+                text.parse::<Table>().map_err(|err| ConfigError(format!("{}: {}", path, err)))?
+            },
+            Err(_) => Table::new(),
+        };
+        let dir = match (env::var("SENTINEL_DIR"), file.remove("dir")) {
+            (Ok(dir), _) => PathBuf::from(dir),
+            (Err(_), Some(Value::String(dir))) => PathBuf::from(dir),
+            (Err(_), Some(_)) => return Err(ConfigError::new("dir must be a string")),
+            (Err(_), None) => PathBuf::from(DEFAULT_DIR),
+        };
+        if !dir.is_absolute() {
+            return Err(ConfigError(format!("dir must be absolute, got {}", dir.display())));
+        }
+        Ok(Settings { dir, file })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Resolves `path` against `dir` unless it is absolute.
+    pub fn path(&self, path: impl AsRef<Path>) -> PathBuf {
+        self.dir.join(path)
+    }
+
+    /// Deserializes the `service` table on top of `T::default()`, applying
+    /// environment overrides. Keys `T` does not know are rejected, apart
+    /// from `socket`, see `socket`.
+    pub fn section<T: Serialize + DeserializeOwned + Default>(&self, service: &str) -> Result<T, ConfigError> {
+        let mut table = Table::try_from(T::default())
+            .map_err(|err| ConfigError(format!("[{}] defaults: {}", service, err)))?;
+        if let Some(file) = self.table(service)? {
+            for (key, value) in file {
+                if key == SOCKET_KEY {
+                    continue;
+                }
+                if !table.contains_key(key) {
+                    return Err(ConfigError(format!("[{}] has no key {}", service, key)));
+                }
+                table.insert(key.clone(), value.clone());
+            }
+        }
+        for (key, value) in table.iter_mut() {
+            if let Some(raw) = env_override(service, key) {
+                *value = parse_override(&raw);
+            }
+        }
+        Value::Table(table)
+            .try_into()
+            .map_err(|err| ConfigError(format!("[{}]: {}", service, err)))
+    }
+
+    /// Socket path of `service`, `<dir>/<service>.sock` unless configured.
+    pub fn socket(&self, service: &str) -> Result<PathBuf, ConfigError> {
+        let configured = match self.table(service)?.and_then(|table| table.get(SOCKET_KEY)) {
+            Some(Value::String(path)) => Some(path.clone()),
+            Some(_) => return Err(ConfigError(format!("[{}] socket must be a string", service))),
+            None => None,
+        };
+        let socket = env_override(service, SOCKET_KEY)
+            .or(configured)
+            .unwrap_or_else(|| format!("{}.sock", service));
+        Ok(self.path(socket))
+    }
+
+    fn table(&self, service: &str) -> Result<Option<&Table>, ConfigError> {
+        match self.file.get(service) {
+            Some(Value::Table(table)) => Ok(Some(table)),
+            Some(_) => Err(ConfigError(format!("{} must be a table", service))),
+            None => Ok(None),
+        }
+    }
+}
+
+fn env_override(service: &str, key: &str) -> Option<String> {
+    env::var(format!("{}_{}", service, key).to_uppercase()).ok()
+}
+
+/// Numbers and booleans are taken as such, anything else as a string.
+fn parse_override(raw: &str) -> Value {
+    if let Ok(number) = raw.parse::<i64>() {
+        return Value::Integer(number);
+    }
+    match raw {
+        "true" => Value::Boolean(true),
+        "false" => Value::Boolean(false),
+        _ => Value::String(raw.to_owned()),
+    }
+}
+
+/// Creates the directory `path` lives in.
+pub fn ensure_parent(path: &Path) -> Result<(), ConfigError> {
+    match path.parent() {
+        Some(parent) => fs::create_dir_all(parent)
+            .map_err(|err| ConfigError(format!("failed to create {}: {}", parent.display(), err))),
+        None => Ok(()),
+    }
+}
+
+/// Fails unless `value` is at least 1, for sizes and timeouts where 0
+/// would stall the service.
+pub fn require_positive(service: &str, key: &str, value: u64) -> Result<(), ConfigError> {
+    if value == 0 {
+        return Err(ConfigError(format!("[{}] {} must be greater than 0", service, key)));
+    }
+    Ok(())
+}
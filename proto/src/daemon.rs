@@ -0,0 +1,319 @@
+// Socket lifecycle shared by the daemons (star, store and sonar): binding
+// without clobbering a running instance, and graceful shutdown on SIGTERM
+// or SIGINT.
+//
+// On the first signal the accept loop is woken up and stops, every client
+// has the read half of its socket shut so it leaves its loop after the
+// frames it already received, and `drain` waits for them up to a deadline.
+// A second signal exits right away.
+//
+// Accepted connections are served by a fixed set of worker threads, one
+// connection per worker at a time, with a bounded queue in front. A
+// connection that finds both full gets a `Status::Busy` frame and is
+// closed, so a flood costs neither threads nor buffers.
+//
+// A client connection that ends early does so with a `ClientError`, which
+// the daemon logs once before closing it.
+
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    fmt, fs,
+    io::{self, Write},
+    net,
+    os::unix::net::{UnixListener, UnixStream},
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        mpsc::{self, SyncSender, TrySendError},
+        Arc, Condvar, Mutex, PoisonError,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use signal_hook::{consts::{SIGINT, SIGTERM}, iterator::Signals};
+use crate::{frame::{Frame, FrameError}, Status};
+
+/// Why a client connection ended early. None of these take the daemon
+/// down. `E` holds reasons only one daemon has, such as store's idle
+/// transactions.
+#[derive(Debug)]
+pub enum ClientError<E = Infallible> {
+    /// Reading a request failed, the idle timeout included.
+    Read(io::Error),
+    /// Sending a response failed, usually because the client hung up.
+    Write(io::Error),
+    /// The client sent something that is not a frame.
+    Frame(FrameError),
+    Daemon(E),
+}
+
+impl<E: fmt::Display> fmt::Display for ClientError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Read(err) => write!(f, "read failed: {}", err),
+            ClientError::Write(err) => write!(f, "write failed: {}", err),
+            ClientError::Frame(err) => write!(f, "bad frame: {}", err),
+            ClientError::Daemon(err) => err.fmt(f),
+        }
+    }
+}
+
+impl<E> From<FrameError> for ClientError<E> {
+    fn from(err: FrameError) -> Self {
+        ClientError::Frame(err)
+    }
+}
+
+/// Sent to a connection over the limit, under request id 0. Command byte 0
+/// is not used by any daemon, so it cannot be mistaken for a reply.
+const REFUSAL: [u8; 2] = [0, Status::Busy as u8];
+
+/// Whether `payload` is the refusal of a daemon at its connection limit.
+pub fn is_refusal(payload: &[u8]) -> bool {
+    payload == REFUSAL
+}
+
+/// Binds `socket`, removing a stale socket file left by a daemon that did
+/// not shut down cleanly. Fails if a daemon still answers on it.
+pub fn bind(socket: &Path) -> io::Result<UnixListener> {
+    if socket.exists() {
+        if UnixStream::connect(socket).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("{} is in use by a running daemon", socket.display()),
+            ));
+        }
+        fs::remove_file(socket)?;
+    }
+    UnixListener::bind(socket)
+}
+
+#[derive(Default)]
+pub struct Shutdown {
+    stopping: AtomicBool,
+    next_client: AtomicU64,
+    clients: Mutex<HashMap<u64, UnixStream>>,
+    drained: Condvar,
+}
+
+/// Keeps a client registered with `Shutdown` until dropped.
+pub struct ClientGuard {
+    shutdown: Arc<Shutdown>,
+    id: u64,
+}
+
+impl Drop for ClientGuard {
+    fn drop(&mut self) {
+        let mut clients = self.shutdown.clients.lock().unwrap_or_else(PoisonError::into_inner);
+        clients.remove(&self.id);
+        self.shutdown.drained.notify_all();
+    }
+}
+
+impl Shutdown {
+    /// Starts a thread that waits for SIGTERM or SIGINT, then flags the
+    /// shutdown and connects to `socket` to wake up the accept loop.
+    pub fn on_signal(socket: PathBuf) -> io::Result<Arc<Shutdown>> {
+        let shutdown = Arc::new(Shutdown::default());
+        let mut signals = Signals::new([SIGTERM, SIGINT])?;
+        let flag = Arc::clone(&shutdown);
+        thread::spawn(move || {
+            for signal in signals.forever() {
+                if flag.stopping.swap(true, Ordering::SeqCst) {
+                    println!("Got signal {} again, exiting now", signal);
+                    process::exit(1);
+                }
+                println!("Got signal {}, shutting down", signal);
+                let _ = UnixStream::connect(&socket);
+            }
+        });
+        Ok(shutdown)
+    }
+
+    /// Checked by the accept loop after every accepted connection.
+    pub fn is_stopping(&self) -> bool {
+        self.stopping.load(Ordering::SeqCst)
+    }
+
+    pub fn track(self: &Arc<Self>, stream: &UnixStream) -> io::Result<ClientGuard> {
+        let id = self.next_client.fetch_add(1, Ordering::Relaxed);
+        let stream = stream.try_clone()?;
+        self.clients.lock().unwrap_or_else(PoisonError::into_inner).insert(id, stream);
+        Ok(ClientGuard { shutdown: Arc::clone(self), id })
+    }
+
+    /// Stops every client from reading further and waits up to `deadline`
+    /// for them to finish. Returns how many were still running.
+    pub fn drain(&self, deadline: Duration) -> usize {
+        let started = Instant::now();
+        let mut clients = self.clients.lock().unwrap_or_else(PoisonError::into_inner);
+        for stream in clients.values() {
+            let _ = stream.shutdown(net::Shutdown::Read);
+        }
+        while !clients.is_empty() {
+            let Some(left) = deadline.checked_sub(started.elapsed()) else {
+                break;
+            };
+            clients = self.drained.wait_timeout(clients, left).unwrap_or_else(PoisonError::into_inner).0;
+        }
+        clients.len()
+    }
+}
+
+/// Connection counters of a `WorkerPool`.
+#[derive(Default)]
+pub struct Metrics {
+    active: AtomicUsize,
+    queued: AtomicUsize,
+    accepted: AtomicU64,
+    refused: AtomicU64,
+}
+
+impl Metrics {
+    /// Connections a worker is serving right now.
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// Connections waiting for a free worker.
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    pub fn accepted(&self) -> u64 {
+        self.accepted.load(Ordering::Relaxed)
+    }
+
+    pub fn refused(&self) -> u64 {
+        self.refused.load(Ordering::Relaxed)
+    }
+}
+
+impl fmt::Display for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} active, {} queued, {} accepted, {} refused",
+            self.active(),
+            self.queued(),
+            self.accepted(),
+            self.refused(),
+        )
+    }
+}
+
+type Job = (UnixStream, ClientGuard);
+
+pub struct WorkerPool {
+    name: &'static str,
+    queue: SyncSender<Job>,
+    metrics: Arc<Metrics>,
+}
+
+impl WorkerPool {
+    /// Starts `workers` threads running `handler` on one connection each,
+    /// with room for `queued` more connections to wait for a free worker.
+    pub fn new<F>(name: &'static str, workers: usize, queued: usize, handler: F) -> io::Result<WorkerPool>
+    where
+        F: Fn(UnixStream) + Send + Sync + 'static,
+    {
+        let (queue, jobs) = mpsc::sync_channel::<Job>(queued);
+        let jobs = Arc::new(Mutex::new(jobs));
+        let handler = Arc::new(handler);
+        let metrics = Arc::new(Metrics::default());
+        for worker in 0..workers {
+            let jobs = Arc::clone(&jobs);
+            let handler = Arc::clone(&handler);
+            let metrics = Arc::clone(&metrics);
+            thread::Builder::new()
+                .name(format!("{}-worker-{}", name, worker))
+                .spawn(move || loop {
+                    let job = jobs.lock().unwrap_or_else(PoisonError::into_inner).recv();
+                    // Fails once the pool is dropped.
+                    let Ok((stream, client)) = job else {
+                        break;
+                    };
+                    metrics.queued.fetch_sub(1, Ordering::Relaxed);
+                    metrics.active.fetch_add(1, Ordering::Relaxed);
+                    // A panicking client must not take the worker with it.
+                    if panic::catch_unwind(AssertUnwindSafe(|| handler(stream))).is_err() {
+                        println!("{}: client handler panicked", name);
+                    }
+                    drop(client);
+                    metrics.active.fetch_sub(1, Ordering::Relaxed);
+                })?;
+        }
+        Ok(WorkerPool { name, queue, metrics })
+    }
+
+    /// Queues `stream` for the next free worker, or refuses it when every
+    /// worker is busy and the queue is full.
+    pub fn submit(&self, stream: UnixStream, client: ClientGuard) {
+        self.metrics.accepted.fetch_add(1, Ordering::Relaxed);
+        self.metrics.queued.fetch_add(1, Ordering::Relaxed);
+        match self.queue.try_send((stream, client)) {
+            Ok(()) => {},
+            Err(TrySendError::Full((stream, _)) | TrySendError::Disconnected((stream, _))) => {
+                self.metrics.queued.fetch_sub(1, Ordering::Relaxed);
+                self.metrics.refused.fetch_add(1, Ordering::Relaxed);
+                refuse(stream);
+            },
+        }
+    }
+
+    /// Hands every connection `listener` accepts to the workers, with
+    /// `read_timeout` as its idle timeout, until shutdown starts.
+    pub fn accept(&self, listener: &UnixListener, shutdown: &Arc<Shutdown>, read_timeout: Duration) {
+        for stream in listener.incoming() {
+            if shutdown.is_stopping() {
+                break;
+            }
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    println!("{}: accept failed: {}", self.name, err);
+                    continue;
+                }
+            };
+            if let Err(err) = stream.set_read_timeout(Some(read_timeout)) {
+                println!("{}: failed to set read timeout: {}", self.name, err);
+                continue;
+            }
+            match shutdown.track(&stream) {
+                Ok(client) => self.submit(stream, client),
+                Err(err) => println!("{}: failed to track client: {}", self.name, err),
+            }
+        }
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Prints the metrics every `interval` while they keep changing.
+    pub fn report_every(&self, interval: Duration) {
+        let name = self.name;
+        let metrics = Arc::clone(&self.metrics);
+        thread::spawn(move || {
+            let mut last = metrics.to_string();
+            loop {
+                thread::sleep(interval);
+                let line = metrics.to_string();
+                if line != last {
+                    println!("{} connections: {}", name, line);
+                    last = line;
+                }
+            }
+        });
+    }
+}
+
+/// Never blocks the accept loop: a client that does not read its refusal
+/// just sees the connection close.
+fn refuse(mut stream: UnixStream) {
+    let _ = stream.set_nonblocking(true);
+    let _ = stream.write_all(&Frame::encode_response(0, REFUSAL.to_vec()));
+}
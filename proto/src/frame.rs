@@ -0,0 +1,213 @@
+// Wire framing used on every sentinel socket.
+//
+// Every message on the socket is wrapped as:
+//
+//   +-------------+-------------+-----------------+-----------------+
+//   | len: u32 BE | version: u8 | request_id: u32 | payload (len-5) |
+//   +-------------+-------------+-----------------+-----------------+
+//
+// `len` counts everything after itself. Responses echo the `request_id`
+// of the request they answer.
+
+use crate::Status;
+
+pub const VERSION: u8 = 1;
+pub const LEN_SIZE: usize = 4;
+pub const HEADER_SIZE: usize = LEN_SIZE + 1 + 4;
+pub const MAX_FRAME_LEN: usize = 1048594;
+/// Most payload bytes one frame carries.
+pub const MAX_PAYLOAD_LEN: usize = MAX_FRAME_LEN - (HEADER_SIZE - LEN_SIZE);
+
+#[derive(Debug)]
+pub struct Frame {
+    pub request_id: u32,
+    pub payload: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub enum FrameError {
+    TooShort(usize),
+    TooLarge(usize),
+    BadVersion(u8),
+}
+
+impl std::fmt::Display for FrameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FrameError::TooShort(len) => write!(f, "frame of {} bytes is shorter than its header", len),
+            FrameError::TooLarge(len) => write!(f, "frame of {} bytes exceeds {}", len, MAX_FRAME_LEN),
+            FrameError::BadVersion(v) => write!(f, "unsupported frame version {}", v),
+        }
+    }
+}
+
+impl Frame {
+    pub fn new(request_id: u32, payload: Vec<u8>) -> Self {
+        Frame { request_id, payload }
+    }
+
+    /// Fails with `TooLarge` rather than produce a frame the peer's
+    /// decoder would reject.
+    pub fn encode(&self) -> Result<Vec<u8>, FrameError> {
+        let len = HEADER_SIZE - LEN_SIZE + self.payload.len();
+        if len > MAX_FRAME_LEN {
+            return Err(FrameError::TooLarge(len));
+        }
+        let mut out = Vec::with_capacity(HEADER_SIZE + self.payload.len());
+        out.extend_from_slice(&(len as u32).to_be_bytes());
+        out.push(VERSION);
+        out.extend_from_slice(&self.request_id.to_be_bytes());
+        out.extend_from_slice(&self.payload);
+        Ok(out)
+    }
+
+    /// Encodes the answer to `request_id`. Responses start with
+    /// `[cmd, status]`, so one too large for a frame is replaced with
+    /// `[cmd, Status::TooLarge]` and the client still gets an answer.
+    pub fn encode_response(request_id: u32, payload: Vec<u8>) -> Vec<u8> {
+        let cmd = payload.first().copied().unwrap_or(0);
+        Frame::new(request_id, payload).encode().unwrap_or_else(|_| {
+            let refusal = Frame::new(request_id, vec![cmd, Status::TooLarge as u8]);
+            refusal.encode().expect("two bytes fit in a frame")
+        })
+    }
+}
+
+/// Collects bytes from successive reads and hands back complete frames,
+/// so a frame split over several reads or several frames in one read
+/// are both handled.
+#[derive(Debug, Default)]
+pub struct FrameDecoder {
+    buf: Vec<u8>,
+}
+
+impl FrameDecoder {
+    pub fn extend(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// True when no bytes are waiting, not even part of a frame.
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    pub fn next_frame(&mut self) -> Result<Option<Frame>, FrameError> {
+        if self.buf.len() < LEN_SIZE {
+            return Ok(None);
+        }
+        let mut len_bytes = [0; LEN_SIZE];
+        len_bytes.copy_from_slice(&self.buf[..LEN_SIZE]);
+        let len = u32::from_be_bytes(len_bytes) as usize;
+        if len < HEADER_SIZE - LEN_SIZE {
+            return Err(FrameError::TooShort(len));
+        }
+        if len > MAX_FRAME_LEN {
+            return Err(FrameError::TooLarge(len));
+        }
+        if self.buf.len() < LEN_SIZE + len {
+            return Ok(None);
+        }
+        let raw: Vec<u8> = self.buf.drain(..LEN_SIZE + len).collect();
+        let version = raw[LEN_SIZE];
+        if version != VERSION {
+            return Err(FrameError::BadVersion(version));
+        }
+        let mut id_bytes = [0; 4];
+        id_bytes.copy_from_slice(&raw[LEN_SIZE + 1..HEADER_SIZE]);
+        Ok(Some(Frame {
+            request_id: u32::from_be_bytes(id_bytes),
+            payload: raw[HEADER_SIZE..].to_vec(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_all(decoder: &mut FrameDecoder) -> Vec<Frame> {
+        let mut frames = vec![];
+        while let Some(frame) = decoder.next_frame().unwrap() {
+            frames.push(frame);
+        }
+        frames
+    }
+
+    #[test]
+    fn frame_split_over_reads() {
+        let raw = Frame::new(7, b"hello".to_vec()).encode().unwrap();
+        let mut decoder = FrameDecoder::default();
+        for byte in &raw[..raw.len() - 1] {
+            decoder.extend(std::slice::from_ref(byte));
+            assert!(decoder.next_frame().unwrap().is_none());
+        }
+        decoder.extend(&raw[raw.len() - 1..]);
+        let frame = decoder.next_frame().unwrap().unwrap();
+        assert_eq!(frame.request_id, 7);
+        assert_eq!(frame.payload, b"hello");
+        assert!(decoder.next_frame().unwrap().is_none());
+    }
+
+    #[test]
+    fn several_frames_in_one_read() {
+        let mut raw = Frame::new(1, b"first".to_vec()).encode().unwrap();
+        raw.extend(Frame::new(2, vec![]).encode().unwrap());
+        let third = Frame::new(3, b"third".to_vec()).encode().unwrap();
+        raw.extend_from_slice(&third[..4]);
+        let mut decoder = FrameDecoder::default();
+        decoder.extend(&raw);
+        let frames = decode_all(&mut decoder);
+        assert_eq!(frames.len(), 2);
+        assert_eq!((frames[0].request_id, frames[0].payload.as_slice()), (1, &b"first"[..]));
+        assert_eq!((frames[1].request_id, frames[1].payload.as_slice()), (2, &b""[..]));
+        decoder.extend(&third[4..]);
+        let frames = decode_all(&mut decoder);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].payload, b"third");
+    }
+
+    #[test]
+    fn bad_version() {
+        let mut raw = Frame::new(1, b"x".to_vec()).encode().unwrap();
+        raw[LEN_SIZE] = 2;
+        let mut decoder = FrameDecoder::default();
+        decoder.extend(&raw);
+        assert!(matches!(decoder.next_frame(), Err(FrameError::BadVersion(2))));
+    }
+
+    #[test]
+    fn too_large() {
+        let mut decoder = FrameDecoder::default();
+        decoder.extend(&((MAX_FRAME_LEN + 1) as u32).to_be_bytes());
+        assert!(matches!(decoder.next_frame(), Err(FrameError::TooLarge(len)) if len == MAX_FRAME_LEN + 1));
+    }
+
+    #[test]
+    fn encode_refuses_what_decode_would_reject() {
+        let largest = Frame::new(1, vec![7; MAX_PAYLOAD_LEN]).encode().unwrap();
+        let mut decoder = FrameDecoder::default();
+        decoder.extend(&largest);
+        assert_eq!(decoder.next_frame().unwrap().unwrap().payload.len(), MAX_PAYLOAD_LEN);
+        assert!(matches!(
+            Frame::new(1, vec![7; MAX_PAYLOAD_LEN + 1]).encode(),
+            Err(FrameError::TooLarge(len)) if len == MAX_FRAME_LEN + 1
+        ));
+    }
+
+    #[test]
+    fn oversized_response_becomes_too_large() {
+        let raw = Frame::encode_response(9, vec![4; MAX_PAYLOAD_LEN + 1]);
+        let mut decoder = FrameDecoder::default();
+        decoder.extend(&raw);
+        let frame = decoder.next_frame().unwrap().unwrap();
+        assert_eq!(frame.request_id, 9);
+        assert_eq!(frame.payload, [4, Status::TooLarge as u8]);
+    }
+
+    #[test]
+    fn too_short() {
+        let mut decoder = FrameDecoder::default();
+        decoder.extend(&4u32.to_be_bytes());
+        assert!(matches!(decoder.next_frame(), Err(FrameError::TooShort(4))));
+    }
+}
@@ -0,0 +1,29 @@
+// Wire formats, startup configuration and daemon plumbing shared by star,
+// store, sonar and satellite.
+//
+// A request payload always starts with its command byte and a response
+// payload always starts with `[command, status]`, both carried inside a
+// `frame::Frame`.
+
+pub mod config;
+pub mod daemon;
+pub mod frame;
+pub mod sonar;
+pub mod star;
+pub mod status;
+pub mod store;
+
+pub use status::Status;
+
+pub const KEY_LEN: usize = 16;
+
+pub type Key = [u8; KEY_LEN];
+
+pub(crate) fn read_key(buf: &[u8]) -> Result<Key, Status> {
+    let mut key = [0; KEY_LEN];
+    if buf.len() < KEY_LEN {
+        return Err(Status::BadLength);
+    }
+    key.copy_from_slice(&buf[..KEY_LEN]);
+    Ok(key)
+}
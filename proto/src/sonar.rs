@@ -0,0 +1,51 @@
+// Sonar request layout: `[cmd, entry...]`. Sonar never answers, so
+// there is no response type.
+
+use crate::Status;
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    Log = 1,
+}
+
+impl TryFrom<u8> for Command {
+    type Error = Status;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(Command::Log),
+            _ => Err(Status::UnknownCommand),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    Log(Vec<u8>),
+}
+
+impl Request {
+    pub fn command(&self) -> Command {
+        match self {
+            Request::Log(_) => Command::Log,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = vec![self.command() as u8];
+        match self {
+            Request::Log(entry) => out.extend_from_slice(entry),
+        }
+        out
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self, Status> {
+        if buf.is_empty() {
+            return Err(Status::BadLength);
+        }
+        Ok(match Command::try_from(buf[0])? {
+            Command::Log => Request::Log(buf[1..].to_vec()),
+        })
+    }
+}
@@ -0,0 +1,1206 @@
+// Star request layout: `[cmd, bucket, key_len: u16 BE, key, ...]`.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{frame::MAX_PAYLOAD_LEN, status::split_response, Status};
+
+/// Hard limit of the key length field. Star enforces its own, usually
+/// smaller, limit on top of this.
+pub const MAX_KEY_LEN: usize = u16::MAX as usize;
+
+/// Most items a single SCAN page returns, whatever limit was asked for.
+pub const MAX_SCAN_LIMIT: u16 = 1000;
+
+/// Most item bytes a SCAN page or GET All answer carries, which leaves
+/// room for the status, the cursor and the item count in one frame. Star
+/// refuses values that would not fit a page on their own, see `item_fits`.
+pub const MAX_PAGE_BYTES: usize = MAX_PAYLOAD_LEN - 2 - (2 + MAX_KEY_LEN) - 2;
+
+/// Most operations a single TXN may carry.
+pub const MAX_TXN_OPS: usize = 256;
+/// Most distinct buckets a single TXN may touch.
+pub const MAX_TXN_BUCKETS: usize = 3;
+/// Most keys a single MGET, MSET or MDEL may carry. Star runs batches as a
+/// TXN, so this matches `MAX_TXN_OPS`.
+pub const MAX_BATCH_KEYS: usize = MAX_TXN_OPS;
+
+const SCAN_REVERSE: u8 = 1;
+const SCAN_PREFIX: u8 = 2;
+
+const SNAPSHOT_COMPRESS: u8 = 1;
+const SNAPSHOT_OVERWRITE: u8 = 2;
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    Get = 1,
+    Set = 2,
+    Del = 3,
+    GetAll = 4,
+    Scan = 5,
+    Expire = 6,
+    Persist = 7,
+    Ttl = 8,
+    Cas = 9,
+    Txn = 10,
+    Subscribe = 11,
+    MGet = 12,
+    MSet = 13,
+    MDel = 14,
+    Snapshot = 15,
+}
+
+impl TryFrom<u8> for Command {
+    type Error = Status;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(Command::Get),
+            2 => Ok(Command::Set),
+            3 => Ok(Command::Del),
+            4 => Ok(Command::GetAll),
+            5 => Ok(Command::Scan),
+            6 => Ok(Command::Expire),
+            7 => Ok(Command::Persist),
+            8 => Ok(Command::Ttl),
+            9 => Ok(Command::Cas),
+            10 => Ok(Command::Txn),
+            11 => Ok(Command::Subscribe),
+            12 => Ok(Command::MGet),
+            13 => Ok(Command::MSet),
+            14 => Ok(Command::MDel),
+            15 => Ok(Command::Snapshot),
+            _ => Err(Status::UnknownCommand),
+        }
+    }
+}
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bucket {
+    Config = 0,
+    Group = 1,
+    User = 2,
+    Table = 3,
+    Flow = 4,
+    Step = 5,
+    Commit = 6,
+    Param = 7,
+    Query = 8,
+    Migration = 9,
+    Session = 10,
+    Revoked = 11,
+    Kv = 12,
+}
+
+impl Bucket {
+    pub const ALL: [Bucket; 13] = [
+        Bucket::Config,
+        Bucket::Group,
+        Bucket::User,
+        Bucket::Table,
+        Bucket::Flow,
+        Bucket::Step,
+        Bucket::Commit,
+        Bucket::Param,
+        Bucket::Query,
+        Bucket::Migration,
+        Bucket::Session,
+        Bucket::Revoked,
+        Bucket::Kv,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Bucket::Config => "config",
+            Bucket::Group => "group",
+            Bucket::User => "user",
+            Bucket::Table => "table",
+            Bucket::Flow => "flow",
+            Bucket::Step => "step",
+            Bucket::Commit => "commit",
+            Bucket::Param => "param",
+            Bucket::Query => "query",
+            Bucket::Migration => "migration",
+            Bucket::Session => "session",
+            Bucket::Revoked => "revoked",
+            Bucket::Kv => "kv",
+        }
+    }
+}
+
+impl TryFrom<u8> for Bucket {
+    type Error = Status;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Bucket::ALL.get(usize::from(value)).copied().ok_or(Status::InvalidBucket)
+    }
+}
+
+/// An item of a GET All, SCAN or MGET answer.
+/// Layout: `[key_len: u16 BE, key, version: u64 BE, val_len: u32 BE, val]`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyValMap {
+    pub key: Vec<u8>,
+    pub val: Vec<u8>,
+    #[serde(default)]
+    pub version: u64,
+}
+
+impl KeyValMap {
+    pub fn encoded_len(&self) -> usize {
+        item_len(self.key.len(), self.val.len())
+    }
+
+    fn encode(&self, out: &mut Vec<u8>) -> Result<(), Status> {
+        put_key(out, &self.key)?;
+        out.extend_from_slice(&self.version.to_be_bytes());
+        // Values are bounded by the frame size, far below 4 GiB.
+        out.extend_from_slice(&(self.val.len() as u32).to_be_bytes());
+        out.extend_from_slice(&self.val);
+        Ok(())
+    }
+
+    fn decode(buf: &[u8]) -> Result<(KeyValMap, &[u8]), Status> {
+        let (key, rest) = take_key(buf)?;
+        let version = read_u64(rest)?;
+        let (val, rest) = take_value(&rest[8..])?;
+        Ok((KeyValMap { key, val, version }, rest))
+    }
+}
+
+fn item_len(key_len: usize, val_len: usize) -> usize {
+    2 + key_len + 8 + 4 + val_len
+}
+
+/// Whether an item with this key and value fits in a SCAN page by itself.
+/// Star refuses to store values that do not, so every stored key can be
+/// scanned and fetched.
+pub fn item_fits(key: &[u8], val: &[u8]) -> bool {
+    item_len(key.len(), val.len()) <= MAX_PAGE_BYTES
+}
+
+/// Where a SCAN stops.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScanEnd {
+    /// Runs to the end of the bucket.
+    Unbounded,
+    /// Stops before this key.
+    Before(Vec<u8>),
+    /// Only keys starting with this prefix.
+    Prefix(Vec<u8>),
+}
+
+/// SCAN layout after the start key:
+/// `[flags, end_len: u16 BE, end, limit: u16 BE, cursor_len: u16 BE, cursor]`,
+/// where flag 1 walks backwards and flag 2 makes `end` a prefix.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Scan {
+    /// First key, inclusive. Empty starts at the beginning of the bucket.
+    pub start: Vec<u8>,
+    pub end: ScanEnd,
+    pub limit: u16,
+    pub reverse: bool,
+    /// Continuation cursor from the previous page of the same scan.
+    pub cursor: Option<Vec<u8>>,
+}
+
+impl Default for Scan {
+    fn default() -> Self {
+        Scan {
+            start: vec![],
+            end: ScanEnd::Unbounded,
+            limit: MAX_SCAN_LIMIT,
+            reverse: false,
+            cursor: None,
+        }
+    }
+}
+
+/// One SCAN page. `cursor` is set when more items are left.
+/// Layout: `[cursor_len: u16 BE, cursor, count: u16 BE, items]`, an empty
+/// cursor meaning none.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScanPage {
+    pub items: Vec<KeyValMap>,
+    pub cursor: Option<Vec<u8>>,
+}
+
+/// One operation of a TXN. Ops run in order and see the writes of the ops
+/// before them.
+///
+/// Layout: `[op, bucket, key_len: u16 BE, key, ...]`, followed by
+/// `version: u64 BE` for CHECK and `[ttl: u32 BE, value_len: u32 BE, value]`
+/// for SET.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TxnOp {
+    Get { bucket: Bucket, key: Vec<u8> },
+    /// Aborts the TXN unless the key is at `version`, 0 meaning missing.
+    Check { bucket: Bucket, key: Vec<u8>, version: u64 },
+    Set { bucket: Bucket, key: Vec<u8>, value: Vec<u8>, ttl: Option<u32> },
+    Del { bucket: Bucket, key: Vec<u8> },
+}
+
+impl TxnOp {
+    pub fn bucket(&self) -> Bucket {
+        match self {
+            TxnOp::Get { bucket, .. }
+            | TxnOp::Check { bucket, .. }
+            | TxnOp::Set { bucket, .. }
+            | TxnOp::Del { bucket, .. } => *bucket,
+        }
+    }
+
+    pub fn key(&self) -> &[u8] {
+        match self {
+            TxnOp::Get { key, .. }
+            | TxnOp::Check { key, .. }
+            | TxnOp::Set { key, .. }
+            | TxnOp::Del { key, .. } => key,
+        }
+    }
+
+    fn encode(&self, out: &mut Vec<u8>) -> Result<(), Status> {
+        let op = match self {
+            TxnOp::Get { .. } => 1,
+            TxnOp::Check { .. } => 2,
+            TxnOp::Set { .. } => 3,
+            TxnOp::Del { .. } => 4,
+        };
+        out.extend_from_slice(&[op, self.bucket() as u8]);
+        put_key(out, self.key())?;
+        match self {
+            TxnOp::Check { version, .. } => out.extend_from_slice(&version.to_be_bytes()),
+            TxnOp::Set { value, ttl, .. } => {
+                out.extend_from_slice(&ttl.unwrap_or(0).to_be_bytes());
+                // Values are bounded by the frame size, far below 4 GiB.
+                out.extend_from_slice(&(value.len() as u32).to_be_bytes());
+                out.extend_from_slice(value);
+            },
+            TxnOp::Get { .. } | TxnOp::Del { .. } => {},
+        }
+        Ok(())
+    }
+
+    fn decode(buf: &[u8]) -> Result<(TxnOp, &[u8]), Status> {
+        if buf.len() < 2 {
+            return Err(Status::BadLength);
+        }
+        let bucket = Bucket::try_from(buf[1])?;
+        let (key, rest) = take_key(&buf[2..])?;
+        Ok(match buf[0] {
+            1 => (TxnOp::Get { bucket, key }, rest),
+            2 => (TxnOp::Check { bucket, key, version: read_u64(rest)? }, &rest[8..]),
+            3 => {
+                let ttl = read_u32(rest)?;
+                let len = read_u32(&rest[4..])? as usize;
+                let value = rest.get(8..8 + len).ok_or(Status::BadLength)?;
+                let op = TxnOp::Set { bucket, key, value: value.to_vec(), ttl: (ttl > 0).then_some(ttl) };
+                (op, &rest[8 + len..])
+            },
+            4 => (TxnOp::Del { bucket, key }, rest),
+            _ => return Err(Status::Malformed),
+        })
+    }
+}
+
+/// Result of one TXN op. `version` 0 means the key was missing.
+///
+/// Layout: `[op, ...]` with the op numbers of `TxnOp`, followed by
+/// `[version: u64 BE, found, value_len: u32 BE, value]` for GET, the last
+/// two only if found, `[version: u64 BE, ok]` for CHECK, `version: u64 BE`
+/// for SET and `[existed]` for DEL.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum TxnResult {
+    Get { version: u64, value: Option<Vec<u8>> },
+    Check { version: u64, ok: bool },
+    Set { version: u64 },
+    Del { existed: bool },
+}
+
+impl TxnResult {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            TxnResult::Get { version, value } => {
+                out.push(1);
+                out.extend_from_slice(&version.to_be_bytes());
+                out.push(u8::from(value.is_some()));
+                if let Some(value) = value {
+                    // Values are bounded by the frame size, far below 4 GiB.
+                    out.extend_from_slice(&(value.len() as u32).to_be_bytes());
+                    out.extend_from_slice(value);
+                }
+            },
+            TxnResult::Check { version, ok } => {
+                out.push(2);
+                out.extend_from_slice(&version.to_be_bytes());
+                out.push(u8::from(*ok));
+            },
+            TxnResult::Set { version } => {
+                out.push(3);
+                out.extend_from_slice(&version.to_be_bytes());
+            },
+            TxnResult::Del { existed } => out.extend_from_slice(&[4, u8::from(*existed)]),
+        }
+    }
+
+    fn decode(buf: &[u8]) -> Result<(TxnResult, &[u8]), Status> {
+        let (&op, rest) = buf.split_first().ok_or(Status::BadLength)?;
+        Ok(match op {
+            1 => {
+                let version = read_u64(rest)?;
+                let (found, rest) = take_flag(&rest[8..])?;
+                if found {
+                    let (value, rest) = take_value(rest)?;
+                    (TxnResult::Get { version, value: Some(value) }, rest)
+                } else {
+                    (TxnResult::Get { version, value: None }, rest)
+                }
+            },
+            2 => {
+                let version = read_u64(rest)?;
+                let (ok, rest) = take_flag(&rest[8..])?;
+                (TxnResult::Check { version, ok }, rest)
+            },
+            3 => (TxnResult::Set { version: read_u64(rest)? }, &rest[8..]),
+            4 => {
+                let (existed, rest) = take_flag(rest)?;
+                (TxnResult::Del { existed }, rest)
+            },
+            _ => return Err(Status::Malformed),
+        })
+    }
+}
+
+/// TXN answer. When a CHECK fails nothing is applied, `committed` is false
+/// and `results` stops at the failed CHECK.
+/// Layout: `[committed, count: u16 BE, results]`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TxnOutcome {
+    pub committed: bool,
+    pub results: Vec<TxnResult>,
+}
+
+/// What a SNAPSHOT wrote. `checksum` is the CRC-32 stored at the end of
+/// the file.
+/// Layout: `[entries: u64 BE, bytes: u64 BE, checksum: u32 BE]`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotInfo {
+    pub entries: u64,
+    pub bytes: u64,
+    pub checksum: u32,
+}
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeOp {
+    Set = 1,
+    Del = 2,
+}
+
+/// Pushed to SUBSCRIBE clients for every write to a watched key.
+/// Layout: `[op, version: u64 BE, key]`, `version` 0 for deletes.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChangeEvent {
+    pub key: Vec<u8>,
+    pub op: ChangeOp,
+    pub version: u64,
+}
+
+impl ChangeEvent {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.push(self.op as u8);
+        out.extend_from_slice(&self.version.to_be_bytes());
+        out.extend_from_slice(&self.key);
+    }
+
+    fn decode(buf: &[u8]) -> Result<Self, Status> {
+        let op = match buf.first() {
+            Some(1) => ChangeOp::Set,
+            Some(2) => ChangeOp::Del,
+            Some(_) => return Err(Status::Malformed),
+            None => return Err(Status::BadLength),
+        };
+        Ok(ChangeEvent { op, version: read_u64(&buf[1..])?, key: buf[9..].to_vec() })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    Get { bucket: Bucket, key: Vec<u8> },
+    /// `ttl` is in seconds; without one the key never expires.
+    Set { bucket: Bucket, key: Vec<u8>, value: Vec<u8>, ttl: Option<u32> },
+    Del { bucket: Bucket, key: Vec<u8> },
+    GetAll { bucket: Bucket, limit: u8 },
+    Scan { bucket: Bucket, scan: Scan },
+    /// Sets the TTL of an existing key, in seconds.
+    Expire { bucket: Bucket, key: Vec<u8>, ttl: u32 },
+    /// Removes the TTL of an existing key.
+    Persist { bucket: Bucket, key: Vec<u8> },
+    Ttl { bucket: Bucket, key: Vec<u8> },
+    /// Writes only if the key is at `expected`, 0 meaning it must not exist.
+    Cas { bucket: Bucket, key: Vec<u8>, expected: u64, value: Vec<u8>, ttl: Option<u32> },
+    /// Runs all ops atomically. Layout: `[cmd, count: u16 BE, ops]`.
+    Txn(Vec<TxnOp>),
+    /// Turns the connection into a stream of `Response::Event`s, all with
+    /// the request ID of the SUBSCRIBE, after a `Response::Subscribed`.
+    /// Watches the exact key if `exact`, otherwise every key starting with
+    /// `key`, so an empty key watches the whole bucket. The layout puts an
+    /// `exact` flag byte after the key.
+    Subscribe { bucket: Bucket, key: Vec<u8>, exact: bool },
+    /// Batches over one bucket, applied atomically with a single flush.
+    /// Layout: `[cmd, bucket, count: u16 BE, keys]`; MSET puts the batch's
+    /// `ttl: u32 BE` before the count and follows each key with
+    /// `[value_len: u32 BE, value]`.
+    MGet { bucket: Bucket, keys: Vec<Vec<u8>> },
+    MSet { bucket: Bucket, items: Vec<(Vec<u8>, Vec<u8>)>, ttl: Option<u32> },
+    MDel { bucket: Bucket, keys: Vec<Vec<u8>> },
+    /// Admin command: exports every bucket to the file `name` in star's
+    /// `snapshot_dir`. Layout: `[cmd, flags, name_len: u16 BE, name]`, flag 1
+    /// compressing the export and flag 2 replacing an existing file.
+    Snapshot { name: String, compress: bool, overwrite: bool },
+}
+
+impl Request {
+    pub fn command(&self) -> Command {
+        match self {
+            Request::Get { .. } => Command::Get,
+            Request::Set { .. } => Command::Set,
+            Request::Del { .. } => Command::Del,
+            Request::GetAll { .. } => Command::GetAll,
+            Request::Scan { .. } => Command::Scan,
+            Request::Expire { .. } => Command::Expire,
+            Request::Persist { .. } => Command::Persist,
+            Request::Ttl { .. } => Command::Ttl,
+            Request::Cas { .. } => Command::Cas,
+            Request::Txn(_) => Command::Txn,
+            Request::Subscribe { .. } => Command::Subscribe,
+            Request::MGet { .. } => Command::MGet,
+            Request::MSet { .. } => Command::MSet,
+            Request::MDel { .. } => Command::MDel,
+            Request::Snapshot { .. } => Command::Snapshot,
+        }
+    }
+
+    /// `None` for TXN, whose ops carry their own buckets, and SNAPSHOT.
+    pub fn bucket(&self) -> Option<Bucket> {
+        match self {
+            Request::Get { bucket, .. }
+            | Request::Set { bucket, .. }
+            | Request::Del { bucket, .. }
+            | Request::GetAll { bucket, .. }
+            | Request::Scan { bucket, .. }
+            | Request::Expire { bucket, .. }
+            | Request::Persist { bucket, .. }
+            | Request::Ttl { bucket, .. }
+            | Request::Cas { bucket, .. }
+            | Request::Subscribe { bucket, .. }
+            | Request::MGet { bucket, .. }
+            | Request::MSet { bucket, .. }
+            | Request::MDel { bucket, .. } => Some(*bucket),
+            Request::Txn(_) | Request::Snapshot { .. } => None,
+        }
+    }
+
+    /// Fails with `KeyTooLong` for a key, or snapshot name, longer than
+    /// `MAX_KEY_LEN` and with `BadLength` for more items than a count
+    /// field holds. SET carries its TTL as `ttl: u32 BE` before the value,
+    /// 0 meaning none; CAS puts `expected: u64 BE` in front of that.
+    pub fn encode(&self) -> Result<Vec<u8>, Status> {
+        let Some(bucket) = self.bucket() else {
+            return match self {
+                Request::Snapshot { name, compress, overwrite } => {
+                    let mut flags = 0;
+                    if *compress {
+                        flags |= SNAPSHOT_COMPRESS;
+                    }
+                    if *overwrite {
+                        flags |= SNAPSHOT_OVERWRITE;
+                    }
+                    let mut out = vec![Command::Snapshot as u8, flags];
+                    put_key(&mut out, name.as_bytes())?;
+                    Ok(out)
+                },
+                _ => self.encode_txn(),
+            };
+        };
+        let mut out = vec![self.command() as u8, bucket as u8];
+        match self {
+            Request::Get { key, .. }
+            | Request::Del { key, .. }
+            | Request::Persist { key, .. }
+            | Request::Ttl { key, .. } => {
+                put_key(&mut out, key)?;
+            },
+            Request::Set { key, value, ttl, .. } => {
+                put_key(&mut out, key)?;
+                out.extend_from_slice(&ttl.unwrap_or(0).to_be_bytes());
+                out.extend_from_slice(value);
+            },
+            Request::Cas { key, expected, value, ttl, .. } => {
+                put_key(&mut out, key)?;
+                out.extend_from_slice(&expected.to_be_bytes());
+                out.extend_from_slice(&ttl.unwrap_or(0).to_be_bytes());
+                out.extend_from_slice(value);
+            },
+            Request::Expire { key, ttl, .. } => {
+                put_key(&mut out, key)?;
+                out.extend_from_slice(&ttl.to_be_bytes());
+            },
+            Request::Subscribe { key, exact, .. } => {
+                put_key(&mut out, key)?;
+                out.push(u8::from(*exact));
+            },
+            Request::GetAll { limit, .. } => {
+                // GET All has no key, the limit follows an empty key.
+                put_key(&mut out, &[])?;
+                out.push(*limit);
+            },
+            Request::Scan { scan, .. } => {
+                put_key(&mut out, &scan.start)?;
+                let mut flags = 0;
+                if scan.reverse {
+                    flags |= SCAN_REVERSE;
+                }
+                let end: &[u8] = match &scan.end {
+                    ScanEnd::Unbounded => &[],
+                    ScanEnd::Before(end) => end,
+                    ScanEnd::Prefix(prefix) => {
+                        flags |= SCAN_PREFIX;
+                        prefix
+                    },
+                };
+                out.push(flags);
+                put_key(&mut out, end)?;
+                out.extend_from_slice(&scan.limit.to_be_bytes());
+                put_key(&mut out, scan.cursor.as_deref().unwrap_or_default())?;
+            },
+            Request::MGet { keys, .. } | Request::MDel { keys, .. } => {
+                put_count(&mut out, keys.len())?;
+                for key in keys {
+                    put_key(&mut out, key)?;
+                }
+            },
+            Request::MSet { items, ttl, .. } => {
+                out.extend_from_slice(&ttl.unwrap_or(0).to_be_bytes());
+                put_count(&mut out, items.len())?;
+                for (key, value) in items {
+                    put_key(&mut out, key)?;
+                    // Values are bounded by the frame size, far below 4 GiB.
+                    out.extend_from_slice(&(value.len() as u32).to_be_bytes());
+                    out.extend_from_slice(value);
+                }
+            },
+            Request::Txn(_) | Request::Snapshot { .. } => {},
+        }
+        Ok(out)
+    }
+
+    fn encode_txn(&self) -> Result<Vec<u8>, Status> {
+        let mut out = vec![Command::Txn as u8];
+        if let Request::Txn(ops) = self {
+            put_count(&mut out, ops.len())?;
+            for op in ops {
+                op.encode(&mut out)?;
+            }
+        }
+        Ok(out)
+    }
+
+    fn decode_txn(buf: &[u8]) -> Result<Self, Status> {
+        let (count, mut rest) = take_count(buf, MAX_TXN_OPS)?;
+        let mut ops = Vec::with_capacity(count);
+        for _ in 0..count {
+            let (op, next) = TxnOp::decode(rest)?;
+            ops.push(op);
+            rest = next;
+        }
+        Ok(Request::Txn(ops))
+    }
+
+    fn decode_batch(command: Command, bucket: Bucket, buf: &[u8]) -> Result<Self, Status> {
+        let mut ttl = None;
+        let mut buf = buf;
+        if command == Command::MSet {
+            ttl = Some(read_u32(buf)?).filter(|ttl| *ttl > 0);
+            buf = &buf[4..];
+        }
+        let (count, mut rest) = take_count(buf, MAX_BATCH_KEYS)?;
+        let mut keys = Vec::with_capacity(count);
+        let mut items = Vec::new();
+        for _ in 0..count {
+            let (key, next) = take_key(rest)?;
+            rest = next;
+            if command == Command::MSet {
+                let len = read_u32(rest)? as usize;
+                let value = rest.get(4..4 + len).ok_or(Status::BadLength)?;
+                items.push((key, value.to_vec()));
+                rest = &rest[4 + len..];
+            } else {
+                keys.push(key);
+            }
+        }
+        Ok(match command {
+            Command::MGet => Request::MGet { bucket, keys },
+            Command::MSet => Request::MSet { bucket, items, ttl },
+            _ => Request::MDel { bucket, keys },
+        })
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self, Status> {
+        if buf.len() < 2 {
+            return Err(Status::BadLength);
+        }
+        let command = Command::try_from(buf[0])?;
+        if command == Command::Txn {
+            return Request::decode_txn(&buf[1..]);
+        }
+        if command == Command::Snapshot {
+            let (name, _) = take_key(&buf[2..])?;
+            let name = String::from_utf8(name).map_err(|_| Status::Malformed)?;
+            return Ok(Request::Snapshot {
+                name,
+                compress: buf[1] & SNAPSHOT_COMPRESS != 0,
+                overwrite: buf[1] & SNAPSHOT_OVERWRITE != 0,
+            });
+        }
+        let bucket = Bucket::try_from(buf[1])?;
+        if let Command::MGet | Command::MSet | Command::MDel = command {
+            return Request::decode_batch(command, bucket, &buf[2..]);
+        }
+        let (key, rest) = take_key(&buf[2..])?;
+        Ok(match command {
+            Command::Get => Request::Get { bucket, key },
+            Command::Set => {
+                let ttl = read_u32(rest)?;
+                Request::Set { bucket, key, value: rest[4..].to_vec(), ttl: (ttl > 0).then_some(ttl) }
+            },
+            Command::Del => Request::Del { bucket, key },
+            Command::GetAll => Request::GetAll {
+                bucket,
+                limit: rest.first().copied().unwrap_or(0),
+            },
+            Command::Scan => Request::Scan { bucket, scan: decode_scan(key, rest)? },
+            Command::Expire => Request::Expire { bucket, key, ttl: read_u32(rest)? },
+            Command::Persist => Request::Persist { bucket, key },
+            Command::Ttl => Request::Ttl { bucket, key },
+            Command::Cas => {
+                let expected = read_u64(rest)?;
+                let ttl = read_u32(&rest[8..])?;
+                Request::Cas { bucket, key, expected, value: rest[12..].to_vec(), ttl: (ttl > 0).then_some(ttl) }
+            },
+            Command::Txn
+            | Command::MGet
+            | Command::MSet
+            | Command::MDel
+            | Command::Snapshot => unreachable!("decoded above"),
+            Command::Subscribe => Request::Subscribe { bucket, key, exact: *rest.first().ok_or(Status::BadLength)? == 1 },
+        })
+    }
+}
+
+fn read_u64(buf: &[u8]) -> Result<u64, Status> {
+    let raw = buf.get(..8).ok_or(Status::BadLength)?;
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(raw);
+    Ok(u64::from_be_bytes(bytes))
+}
+
+fn read_u32(buf: &[u8]) -> Result<u32, Status> {
+    let raw = buf.get(..4).ok_or(Status::BadLength)?;
+    Ok(u32::from_be_bytes([raw[0], raw[1], raw[2], raw[3]]))
+}
+
+fn decode_scan(start: Vec<u8>, buf: &[u8]) -> Result<Scan, Status> {
+    let (&flags, buf) = buf.split_first().ok_or(Status::BadLength)?;
+    let (end, buf) = take_key(buf)?;
+    let limit = buf.get(..2).ok_or(Status::BadLength)?;
+    let limit = u16::from_be_bytes([limit[0], limit[1]]);
+    let (cursor, _) = take_key(&buf[2..])?;
+    let end = if flags & SCAN_PREFIX != 0 {
+        ScanEnd::Prefix(end)
+    } else if end.is_empty() {
+        ScanEnd::Unbounded
+    } else {
+        ScanEnd::Before(end)
+    };
+    Ok(Scan {
+        start,
+        end,
+        limit,
+        reverse: flags & SCAN_REVERSE != 0,
+        cursor: if cursor.is_empty() { None } else { Some(cursor) },
+    })
+}
+
+fn put_key(out: &mut Vec<u8>, key: &[u8]) -> Result<(), Status> {
+    let len = u16::try_from(key.len()).map_err(|_| Status::KeyTooLong)?;
+    out.extend_from_slice(&len.to_be_bytes());
+    out.extend_from_slice(key);
+    Ok(())
+}
+
+fn put_count(out: &mut Vec<u8>, count: usize) -> Result<(), Status> {
+    let count = u16::try_from(count).map_err(|_| Status::BadLength)?;
+    out.extend_from_slice(&count.to_be_bytes());
+    Ok(())
+}
+
+fn take_count(buf: &[u8], max: usize) -> Result<(usize, &[u8]), Status> {
+    let count = buf.get(..2).ok_or(Status::BadLength)?;
+    let count = usize::from(u16::from_be_bytes([count[0], count[1]]));
+    if count > max {
+        return Err(Status::BadLength);
+    }
+    Ok((count, &buf[2..]))
+}
+
+fn take_key(buf: &[u8]) -> Result<(Vec<u8>, &[u8]), Status> {
+    if buf.len() < 2 {
+        return Err(Status::BadLength);
+    }
+    let len = usize::from(u16::from_be_bytes([buf[0], buf[1]]));
+    let key = buf.get(2..2 + len).ok_or(Status::BadLength)?;
+    Ok((key.to_vec(), &buf[2 + len..]))
+}
+
+fn take_value(buf: &[u8]) -> Result<(Vec<u8>, &[u8]), Status> {
+    let len = read_u32(buf)? as usize;
+    let value = buf.get(4..4 + len).ok_or(Status::BadLength)?;
+    Ok((value.to_vec(), &buf[4 + len..]))
+}
+
+fn take_flag(buf: &[u8]) -> Result<(bool, &[u8]), Status> {
+    match buf.split_first() {
+        Some((0, rest)) => Ok((false, rest)),
+        Some((1, rest)) => Ok((true, rest)),
+        Some(_) => Err(Status::Malformed),
+        None => Err(Status::BadLength),
+    }
+}
+
+fn put_items(out: &mut Vec<u8>, items: &[KeyValMap]) -> Result<(), Status> {
+    put_count(out, items.len())?;
+    items.iter().try_for_each(|item| item.encode(out))
+}
+
+fn take_items(buf: &[u8], max: usize) -> Result<(Vec<KeyValMap>, &[u8]), Status> {
+    let (count, mut rest) = take_count(buf, max)?;
+    let mut items = Vec::with_capacity(count);
+    for _ in 0..count {
+        let (item, next) = KeyValMap::decode(rest)?;
+        items.push(item);
+        rest = next;
+    }
+    Ok((items, rest))
+}
+
+/// GET, SET and CAS answers start with the key's `version: u64 BE`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    Get { version: u64, value: Vec<u8> },
+    Set { version: u64 },
+    Del,
+    GetAll(Vec<KeyValMap>),
+    Scan(ScanPage),
+    Expire,
+    Persist,
+    /// Seconds left, or `None` for a key without TTL. Encoded as an empty
+    /// body or a `u32 BE`.
+    Ttl(Option<u32>),
+    Cas { version: u64 },
+    Txn(TxnOutcome),
+    Subscribed,
+    Event(ChangeEvent),
+    /// One entry per requested key, in order, `None` for missing keys.
+    MGet(Vec<Option<KeyValMap>>),
+    /// The new version of each key.
+    /// Layout: `[count: u16 BE, version: u64 BE per key]`.
+    MSet(Vec<u64>),
+    /// Whether each key existed.
+    /// Layout: `[count: u16 BE, existed per key]`.
+    MDel(Vec<bool>),
+    Snapshot(SnapshotInfo),
+    /// `cmd` is the raw command byte so unknown commands can be echoed back.
+    Error { cmd: u8, status: Status },
+}
+
+impl Response {
+    pub fn error(cmd: Command, status: Status) -> Self {
+        Response::Error { cmd: cmd as u8, status }
+    }
+
+    /// The command byte the answer starts with.
+    fn command(&self) -> u8 {
+        let cmd = match self {
+            Response::Get { .. } => Command::Get,
+            Response::Set { .. } => Command::Set,
+            Response::Del => Command::Del,
+            Response::GetAll(_) => Command::GetAll,
+            Response::Scan(_) => Command::Scan,
+            Response::Expire => Command::Expire,
+            Response::Persist => Command::Persist,
+            Response::Ttl(_) => Command::Ttl,
+            Response::Cas { .. } => Command::Cas,
+            Response::Txn(_) => Command::Txn,
+            Response::Subscribed | Response::Event(_) => Command::Subscribe,
+            Response::MGet(_) => Command::MGet,
+            Response::MSet(_) => Command::MSet,
+            Response::MDel(_) => Command::MDel,
+            Response::Snapshot(_) => Command::Snapshot,
+            Response::Error { cmd, .. } => return *cmd,
+        };
+        cmd as u8
+    }
+
+    /// A key or count too long for its length field turns the answer into
+    /// an error with the status `Request::encode` would fail with.
+    pub fn encode(&self) -> Vec<u8> {
+        self.try_encode().unwrap_or_else(|status| vec![self.command(), status as u8])
+    }
+
+    fn try_encode(&self) -> Result<Vec<u8>, Status> {
+        Ok(match self {
+            Response::Get { version, value } => {
+                let mut out = vec![Command::Get as u8, Status::Ok as u8];
+                out.extend_from_slice(&version.to_be_bytes());
+                out.extend_from_slice(value);
+                out
+            },
+            Response::Set { version } => {
+                let mut out = vec![Command::Set as u8, Status::Ok as u8];
+                out.extend_from_slice(&version.to_be_bytes());
+                out
+            },
+            Response::Del => vec![Command::Del as u8, Status::Ok as u8],
+            Response::GetAll(items) => {
+                let mut out = vec![Command::GetAll as u8, Status::Ok as u8];
+                put_items(&mut out, items)?;
+                out
+            },
+            Response::Scan(page) => {
+                let mut out = vec![Command::Scan as u8, Status::Ok as u8];
+                put_key(&mut out, page.cursor.as_deref().unwrap_or_default())?;
+                put_items(&mut out, &page.items)?;
+                out
+            },
+            Response::Expire => vec![Command::Expire as u8, Status::Ok as u8],
+            Response::Persist => vec![Command::Persist as u8, Status::Ok as u8],
+            Response::Ttl(ttl) => {
+                let mut out = vec![Command::Ttl as u8, Status::Ok as u8];
+                if let Some(ttl) = ttl {
+                    out.extend_from_slice(&ttl.to_be_bytes());
+                }
+                out
+            },
+            Response::Cas { version } => {
+                let mut out = vec![Command::Cas as u8, Status::Ok as u8];
+                out.extend_from_slice(&version.to_be_bytes());
+                out
+            },
+            Response::Txn(outcome) => {
+                let mut out = vec![Command::Txn as u8, Status::Ok as u8, u8::from(outcome.committed)];
+                put_count(&mut out, outcome.results.len())?;
+                for result in &outcome.results {
+                    result.encode(&mut out);
+                }
+                out
+            },
+            Response::Subscribed => vec![Command::Subscribe as u8, Status::Ok as u8],
+            Response::Event(event) => {
+                let mut out = vec![Command::Subscribe as u8, Status::Ok as u8];
+                event.encode(&mut out);
+                out
+            },
+            Response::MGet(items) => {
+                // `[count: u16 BE]`, then per key `[found]` and the item if found.
+                let mut out = vec![Command::MGet as u8, Status::Ok as u8];
+                put_count(&mut out, items.len())?;
+                for item in items {
+                    out.push(u8::from(item.is_some()));
+                    if let Some(item) = item {
+                        item.encode(&mut out)?;
+                    }
+                }
+                out
+            },
+            Response::MSet(versions) => {
+                let mut out = vec![Command::MSet as u8, Status::Ok as u8];
+                put_count(&mut out, versions.len())?;
+                for version in versions {
+                    out.extend_from_slice(&version.to_be_bytes());
+                }
+                out
+            },
+            Response::MDel(existed) => {
+                let mut out = vec![Command::MDel as u8, Status::Ok as u8];
+                put_count(&mut out, existed.len())?;
+                out.extend(existed.iter().map(|existed| u8::from(*existed)));
+                out
+            },
+            Response::Snapshot(info) => {
+                let mut out = vec![Command::Snapshot as u8, Status::Ok as u8];
+                out.extend_from_slice(&info.entries.to_be_bytes());
+                out.extend_from_slice(&info.bytes.to_be_bytes());
+                out.extend_from_slice(&info.checksum.to_be_bytes());
+                out
+            },
+            Response::Error { cmd, status } => vec![*cmd, *status as u8],
+        })
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self, Status> {
+        let (cmd, status, body) = split_response(buf)?;
+        if status != Status::Ok {
+            return Ok(Response::Error { cmd, status });
+        }
+        Ok(match Command::try_from(cmd)? {
+            Command::Get => Response::Get { version: read_u64(body)?, value: body[8..].to_vec() },
+            Command::Set => Response::Set { version: read_u64(body)? },
+            Command::Del => Response::Del,
+            Command::GetAll => Response::GetAll(take_items(body, usize::from(u8::MAX))?.0),
+            Command::Scan => {
+                let (cursor, rest) = take_key(body)?;
+                let (items, _) = take_items(rest, usize::from(MAX_SCAN_LIMIT))?;
+                Response::Scan(ScanPage { items, cursor: (!cursor.is_empty()).then_some(cursor) })
+            },
+            Command::Expire => Response::Expire,
+            Command::Persist => Response::Persist,
+            Command::Ttl if body.is_empty() => Response::Ttl(None),
+            Command::Ttl => Response::Ttl(Some(read_u32(body)?)),
+            Command::Cas => Response::Cas { version: read_u64(body)? },
+            Command::Txn => {
+                let (committed, rest) = take_flag(body)?;
+                let (count, mut rest) = take_count(rest, MAX_TXN_OPS)?;
+                let mut results = Vec::with_capacity(count);
+                for _ in 0..count {
+                    let (result, next) = TxnResult::decode(rest)?;
+                    results.push(result);
+                    rest = next;
+                }
+                Response::Txn(TxnOutcome { committed, results })
+            },
+            Command::Subscribe if body.is_empty() => Response::Subscribed,
+            Command::Subscribe => Response::Event(ChangeEvent::decode(body)?),
+            Command::MGet => {
+                let (count, mut rest) = take_count(body, MAX_BATCH_KEYS)?;
+                let mut items = Vec::with_capacity(count);
+                for _ in 0..count {
+                    let (found, next) = take_flag(rest)?;
+                    rest = next;
+                    if found {
+                        let (item, next) = KeyValMap::decode(rest)?;
+                        items.push(Some(item));
+                        rest = next;
+                    } else {
+                        items.push(None);
+                    }
+                }
+                Response::MGet(items)
+            },
+            Command::MSet => {
+                let (count, mut rest) = take_count(body, MAX_BATCH_KEYS)?;
+                let mut versions = Vec::with_capacity(count);
+                for _ in 0..count {
+                    versions.push(read_u64(rest)?);
+                    rest = &rest[8..];
+                }
+                Response::MSet(versions)
+            },
+            Command::MDel => {
+                let (count, mut rest) = take_count(body, MAX_BATCH_KEYS)?;
+                let mut existed = Vec::with_capacity(count);
+                for _ in 0..count {
+                    let (flag, next) = take_flag(rest)?;
+                    existed.push(flag);
+                    rest = next;
+                }
+                Response::MDel(existed)
+            },
+            Command::Snapshot => Response::Snapshot(SnapshotInfo {
+                entries: read_u64(body)?,
+                bytes: read_u64(&body[8..])?,
+                checksum: read_u32(body.get(16..).ok_or(Status::BadLength)?)?,
+            }),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn requests() -> Vec<Request> {
+        let bucket = Bucket::Kv;
+        let key = b"user:1".to_vec();
+        vec![
+            Request::Get { bucket, key: key.clone() },
+            Request::Set { bucket, key: key.clone(), value: b"v".to_vec(), ttl: None },
+            Request::Set { bucket, key: key.clone(), value: vec![], ttl: Some(30) },
+            Request::Del { bucket, key: key.clone() },
+            Request::GetAll { bucket, limit: 5 },
+            Request::Scan { bucket, scan: Scan::default() },
+            Request::Scan {
+                bucket,
+                scan: Scan {
+                    start: b"a".to_vec(),
+                    end: ScanEnd::Prefix(b"user:".to_vec()),
+                    limit: 10,
+                    reverse: true,
+                    cursor: Some(b"user:0".to_vec()),
+                },
+            },
+            Request::Scan {
+                bucket,
+                scan: Scan { end: ScanEnd::Before(b"z".to_vec()), ..Scan::default() },
+            },
+            Request::Expire { bucket, key: key.clone(), ttl: 60 },
+            Request::Persist { bucket, key: key.clone() },
+            Request::Ttl { bucket, key: key.clone() },
+            Request::Cas { bucket, key: key.clone(), expected: 3, value: b"new".to_vec(), ttl: Some(9) },
+            Request::Txn(vec![
+                TxnOp::Get { bucket, key: key.clone() },
+                TxnOp::Check { bucket: Bucket::User, key: key.clone(), version: 7 },
+                TxnOp::Set { bucket, key: key.clone(), value: b"v".to_vec(), ttl: Some(1) },
+                TxnOp::Del { bucket: Bucket::Session, key: key.clone() },
+            ]),
+            Request::Txn(vec![]),
+            Request::Subscribe { bucket, key: key.clone(), exact: true },
+            Request::Subscribe { bucket, key: vec![], exact: false },
+            Request::MGet { bucket, keys: vec![key.clone(), b"other".to_vec()] },
+            Request::MSet { bucket, items: vec![(key.clone(), b"1".to_vec()), (b"k".to_vec(), vec![])], ttl: Some(5) },
+            Request::MDel { bucket, keys: vec![key] },
+            Request::Snapshot { name: "star.snap".to_string(), compress: true, overwrite: false },
+            Request::Snapshot { name: "daily/star.snap".to_string(), compress: false, overwrite: true },
+        ]
+    }
+
+    fn item(key: &[u8], val: &[u8], version: u64) -> KeyValMap {
+        KeyValMap { key: key.to_vec(), val: val.to_vec(), version }
+    }
+
+    fn responses() -> Vec<Response> {
+        vec![
+            Response::Get { version: 4, value: b"value".to_vec() },
+            Response::Set { version: 5 },
+            Response::Del,
+            Response::GetAll(vec![item(b"a", b"1", 1), item(b"b", b"", 2)]),
+            Response::Scan(ScanPage { items: vec![item(b"a", &[0, 255], 1)], cursor: Some(b"a".to_vec()) }),
+            Response::Scan(ScanPage { items: vec![], cursor: None }),
+            Response::Expire,
+            Response::Persist,
+            Response::Ttl(None),
+            Response::Ttl(Some(42)),
+            Response::Cas { version: 6 },
+            Response::Txn(TxnOutcome {
+                committed: false,
+                results: vec![
+                    TxnResult::Get { version: 1, value: Some(b"x".to_vec()) },
+                    TxnResult::Get { version: 0, value: None },
+                    TxnResult::Check { version: 1, ok: false },
+                    TxnResult::Set { version: 2 },
+                    TxnResult::Del { existed: true },
+                ],
+            }),
+            Response::Subscribed,
+            Response::Event(ChangeEvent { key: b"k".to_vec(), op: ChangeOp::Set, version: 3 }),
+            Response::Event(ChangeEvent { key: b"k".to_vec(), op: ChangeOp::Del, version: 0 }),
+            Response::MGet(vec![Some(item(b"a", b"1", 1)), None]),
+            Response::MSet(vec![1, 2]),
+            Response::MDel(vec![true, false]),
+            Response::MDel(vec![]),
+            Response::Snapshot(SnapshotInfo { entries: 10, bytes: 2048, checksum: 0xdeadbeef }),
+            Response::error(Command::Get, Status::NotFound),
+            Response::Error { cmd: 99, status: Status::UnknownCommand },
+        ]
+    }
+
+    #[test]
+    fn requests_round_trip() {
+        for request in requests() {
+            assert_eq!(Request::decode(&request.encode().unwrap()), Ok(request.clone()), "{:?}", request);
+        }
+    }
+
+    #[test]
+    fn responses_round_trip() {
+        for response in responses() {
+            assert_eq!(Response::decode(&response.encode()), Ok(response.clone()), "{:?}", response);
+        }
+    }
+
+    /// A cut-off message must never panic nor pass for the original.
+    #[test]
+    fn truncated_requests() {
+        for request in requests() {
+            let raw = request.encode().unwrap();
+            for len in 0..raw.len() {
+                assert_ne!(Request::decode(&raw[..len]), Ok(request.clone()), "{:?} cut to {}", request, len);
+            }
+        }
+        for raw in [&[][..], &[Command::Get as u8], &[Command::Get as u8, 12, 0, 5, b'a']] {
+            assert_eq!(Request::decode(raw), Err(Status::BadLength));
+        }
+        assert_eq!(Request::decode(&[Command::Get as u8, 200, 0, 0]), Err(Status::InvalidBucket));
+        assert_eq!(Request::decode(&[0, 0]), Err(Status::UnknownCommand));
+    }
+
+    #[test]
+    fn truncated_responses() {
+        for response in responses() {
+            let raw = response.encode();
+            for len in 0..raw.len() {
+                assert_ne!(Response::decode(&raw[..len]), Ok(response.clone()), "{:?} cut to {}", response, len);
+            }
+        }
+        assert_eq!(Response::decode(&[Command::Get as u8, 0, 0, 0]), Err(Status::BadLength));
+        assert_eq!(Response::decode(&[Command::Get as u8, 7]), Err(Status::Malformed));
+    }
+
+    #[test]
+    fn oversized_fields_are_refused() {
+        let bucket = Bucket::Kv;
+        let long = vec![b'k'; MAX_KEY_LEN + 1];
+        assert_eq!(Request::Get { bucket, key: long.clone() }.encode(), Err(Status::KeyTooLong));
+        let txn = Request::Txn(vec![TxnOp::Del { bucket, key: long.clone() }]);
+        assert_eq!(txn.encode(), Err(Status::KeyTooLong));
+        let keys = vec![b"k".to_vec(); usize::from(u16::MAX) + 1];
+        assert_eq!(Request::MGet { bucket, keys }.encode(), Err(Status::BadLength));
+        let page = ScanPage { items: vec![], cursor: Some(long) };
+        assert_eq!(Response::Scan(page).encode(), [Command::Scan as u8, Status::KeyTooLong as u8]);
+    }
+
+    #[test]
+    fn flags_must_be_zero_or_one() {
+        assert_eq!(Response::decode(&[Command::MGet as u8, 0, 0, 1, 2]), Err(Status::Malformed));
+        assert_eq!(Response::decode(&[Command::Txn as u8, 0, 2, 0, 0]), Err(Status::Malformed));
+        assert_eq!(Response::decode(&[Command::MDel as u8, 0, 0, 1, 2]), Err(Status::Malformed));
+    }
+
+    #[test]
+    fn batch_results_are_fixed_width() {
+        let mset = Response::MSet(vec![1, 258]).encode();
+        assert_eq!(mset, [Command::MSet as u8, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 1, 2]);
+        let mdel = Response::MDel(vec![true, false]).encode();
+        assert_eq!(mdel, [Command::MDel as u8, 0, 0, 2, 1, 0]);
+        assert_eq!(Response::decode(&[Command::MSet as u8, 0, 0, 0]), Ok(Response::MSet(vec![])));
+        let info = SnapshotInfo { entries: 1, bytes: 2, checksum: 3 };
+        let snapshot = Response::Snapshot(info).encode();
+        assert_eq!(snapshot, [Command::Snapshot as u8, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 3]);
+    }
+
+    /// A page filled up to `MAX_PAGE_BYTES`, with the longest cursor the
+    /// layout allows, still fits in one frame.
+    #[test]
+    fn full_page_fits_a_frame() {
+        let big = item(b"a", &vec![0; MAX_PAGE_BYTES - item_len(1, 0)], 1);
+        assert!(item_fits(&big.key, &big.val));
+        assert!(!item_fits(&big.key, &[big.val.as_slice(), &[0]].concat()));
+        let page = ScanPage { items: vec![big], cursor: Some(vec![1; MAX_KEY_LEN]) };
+        let raw = Response::Scan(page.clone()).encode();
+        assert!(crate::frame::Frame::new(1, raw.clone()).encode().is_ok());
+        assert_eq!(Response::decode(&raw), Ok(Response::Scan(page)));
+    }
+}
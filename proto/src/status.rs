@@ -0,0 +1,118 @@
+use std::fmt;
+
+/// Second byte of every response. Anything other than `Ok` means the
+/// command was not applied.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Ok = 0,
+    UnknownCommand = 128,
+    InvalidBucket = 129,
+    StoreUnavailable = 130,
+    BucketUnavailable = 131,
+    NotFound = 132,
+    ReadFailed = 133,
+    WriteFailed = 134,
+    DeleteFailed = 135,
+    BadLength = 136,
+    Malformed = 137,
+    KeyTooLong = 138,
+    /// CAS found a different version than the caller expected.
+    Conflict = 139,
+    /// A TXN touched more buckets than star can lock together.
+    TooManyBuckets = 140,
+    /// The daemon is at its connection limit; sent once, unasked, before
+    /// it closes the connection.
+    Busy = 141,
+    /// SQLite rejected or failed to run a statement.
+    QueryFailed = 142,
+    /// DEFINE refused a statement by policy; the body says why.
+    Rejected = 143,
+    /// COMMIT, ROLLBACK without a BEGIN, or the transaction was already
+    /// rolled back.
+    NoTransaction = 144,
+    /// BEGIN while the connection already has a transaction open.
+    TransactionOpen = 145,
+    /// A value, or an answer, would not fit in a frame.
+    TooLarge = 146,
+    /// EXEC of a statement registered for a role the caller lacks, or
+    /// SNAPSHOT while star has snapshots turned off.
+    Forbidden = 147,
+    /// A snapshot name that is absolute or climbs out of `snapshot_dir`.
+    BadPath = 148,
+    /// SNAPSHOT onto an existing file without the overwrite flag.
+    Exists = 149,
+}
+
+impl TryFrom<u8> for Status {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Status::Ok),
+            128 => Ok(Status::UnknownCommand),
+            129 => Ok(Status::InvalidBucket),
+            130 => Ok(Status::StoreUnavailable),
+            131 => Ok(Status::BucketUnavailable),
+            132 => Ok(Status::NotFound),
+            133 => Ok(Status::ReadFailed),
+            134 => Ok(Status::WriteFailed),
+            135 => Ok(Status::DeleteFailed),
+            136 => Ok(Status::BadLength),
+            137 => Ok(Status::Malformed),
+            138 => Ok(Status::KeyTooLong),
+            139 => Ok(Status::Conflict),
+            140 => Ok(Status::TooManyBuckets),
+            141 => Ok(Status::Busy),
+            142 => Ok(Status::QueryFailed),
+            143 => Ok(Status::Rejected),
+            144 => Ok(Status::NoTransaction),
+            145 => Ok(Status::TransactionOpen),
+            146 => Ok(Status::TooLarge),
+            147 => Ok(Status::Forbidden),
+            148 => Ok(Status::BadPath),
+            149 => Ok(Status::Exists),
+            other => Err(other),
+        }
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = match self {
+            Status::Ok => "ok",
+            Status::UnknownCommand => "unknown command",
+            Status::InvalidBucket => "invalid bucket",
+            Status::StoreUnavailable => "store unavailable",
+            Status::BucketUnavailable => "bucket unavailable",
+            Status::NotFound => "not found",
+            Status::ReadFailed => "read failed",
+            Status::WriteFailed => "write failed",
+            Status::DeleteFailed => "delete failed",
+            Status::BadLength => "bad length",
+            Status::Malformed => "malformed payload",
+            Status::KeyTooLong => "key too long",
+            Status::Conflict => "version conflict",
+            Status::TooManyBuckets => "too many buckets",
+            Status::Busy => "too many connections",
+            Status::QueryFailed => "query failed",
+            Status::Rejected => "statement rejected",
+            Status::NoTransaction => "no transaction open",
+            Status::TransactionOpen => "transaction already open",
+            Status::TooLarge => "too large",
+            Status::Forbidden => "not allowed",
+            Status::BadPath => "bad path",
+            Status::Exists => "already exists",
+        };
+        write!(f, "{} ({})", text, *self as u8)
+    }
+}
+
+/// Splits a response payload into its command byte, status and body.
+pub fn split_response(buf: &[u8]) -> Result<(u8, Status, &[u8]), Status> {
+    if buf.len() < 2 {
+        return Err(Status::BadLength);
+    }
+    let status = Status::try_from(buf[1]).map_err(|_| Status::Malformed)?;
+    Ok((buf[0], status, &buf[2..]))
+}
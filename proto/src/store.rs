@@ -0,0 +1,677 @@
+// Store request layouts:
+//
+// DEFINE `[cmd, name_len u8, name, role_len u8, role, sql_len u32, sql,
+//         var_count u8, (var_len u8, var)*]`
+// LIST   `[cmd]`
+// DROP   `[cmd, id(16)]`
+// EXEC   `[cmd, id(16), role_count u8, (role_len u8, role)*, param_count u8,
+//         value*]`
+// BEGIN, COMMIT, ROLLBACK `[cmd]`
+//
+// EXEC carries the roles of the user it runs for, as authenticated by the
+// caller, and only runs statements registered for one of them. It takes
+// one value per declared variable, in declaration order. A value
+// is `[type u8]` followed by nothing (null), `i64 BE` (integer), `f64 BE`
+// (real) or `len u32, bytes` (text, blob). EXEC answers either
+// `[0, changes u64]` or `[1, column_count u16, (name_len u8, name)*,
+// row_count u32, value*]`, row by row.
+//
+// DEFINE checks the SQL against store's policy and answers
+// `Status::Rejected` with a JSON `Rejection` body when it does not pass.
+//
+// A transaction belongs to the connection that sent BEGIN: EXECs on that
+// connection run inside it until COMMIT or ROLLBACK. Store rolls it back
+// when the connection closes or stays idle too long.
+//
+// Statements are addressed by `statement_id(name)`, so a client that knows
+// a statement's name never needs to look its ID up.
+
+use std::fmt;
+use serde::{Deserialize, Serialize};
+use crate::{read_key, status::split_response, Key, Status, KEY_LEN};
+
+pub const MAX_NAME_LEN: usize = 64;
+pub const MAX_SQL_LEN: usize = 65536;
+pub const MAX_STATEMENT_VARS: usize = 64;
+pub const MAX_EXEC_ROLES: usize = 64;
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    Define = 1,
+    List = 2,
+    Drop = 3,
+    Exec = 4,
+    Begin = 5,
+    Commit = 6,
+    Rollback = 7,
+}
+
+impl TryFrom<u8> for Command {
+    type Error = Status;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(Command::Define),
+            2 => Ok(Command::List),
+            3 => Ok(Command::Drop),
+            4 => Ok(Command::Exec),
+            5 => Ok(Command::Begin),
+            6 => Ok(Command::Commit),
+            7 => Ok(Command::Rollback),
+            _ => Err(Status::UnknownCommand),
+        }
+    }
+}
+
+/// A named SQL statement and the variables it binds, as registered with
+/// DEFINE and returned by LIST.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Statement {
+    pub id: Key,
+    pub name: String,
+    /// The role whose tables the statement was checked against.
+    #[serde(default)]
+    pub role: String,
+    pub statement: String,
+    pub vars: Vec<String>,
+}
+
+/// Why DEFINE refused a statement.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "reason", rename_all = "snake_case")]
+pub enum Rejection {
+    /// The SQL does not parse as SQLite.
+    Syntax { message: String },
+    MultipleStatements { count: usize },
+    Attach,
+    Pragma,
+    /// Schema changes only run as migrations.
+    Ddl { statement: String },
+    /// Anything other than SELECT, INSERT, UPDATE and DELETE.
+    UnsupportedStatement { statement: String },
+    /// SQL the policy cannot check, so it does not allow it either.
+    Unsupported { construct: String },
+    UnknownRole { role: String },
+    TableNotAllowed { table: String, role: String },
+    /// Values must be passed as variables.
+    Literal { literal: String },
+    /// SQLite reads a double-quoted name that is no column as a string.
+    QuotedLiteral { name: String },
+    FunctionNotAllowed { function: String },
+    PositionalPlaceholder { placeholder: String },
+    UndeclaredVariable { name: String },
+    UnusedVariable { name: String },
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejection::Syntax { message } => write!(f, "syntax error: {}", message),
+            Rejection::MultipleStatements { count } => write!(f, "{} statements, expected one", count),
+            Rejection::Attach => f.write_str("ATTACH and DETACH are not allowed"),
+            Rejection::Pragma => f.write_str("PRAGMA is not allowed"),
+            Rejection::Ddl { statement } => write!(f, "{} is only allowed in migrations", statement),
+            Rejection::UnsupportedStatement { statement } => write!(f, "{} is not allowed", statement),
+            Rejection::Unsupported { construct } => write!(f, "unsupported SQL: {}", construct),
+            Rejection::UnknownRole { role } => write!(f, "no tables are listed for role {}", role),
+            Rejection::TableNotAllowed { table, role } => write!(f, "table {} is not allowed for role {}", table, role),
+            Rejection::Literal { literal } => write!(f, "literal {} must be a variable", literal),
+            Rejection::QuotedLiteral { name } => write!(f, "\"{}\" is no column and would be read as a string", name),
+            Rejection::FunctionNotAllowed { function } => write!(f, "function {} is not allowed", function),
+            Rejection::PositionalPlaceholder { placeholder } => write!(f, "placeholder {} must be named", placeholder),
+            Rejection::UndeclaredVariable { name } => write!(f, "variable {} is not declared", name),
+            Rejection::UnusedVariable { name } => write!(f, "variable {} is never used", name),
+        }
+    }
+}
+
+/// The ID of the statement named `name`: the 128-bit FNV-1a hash of the
+/// name, big endian. Stays the same across redefinitions and restarts.
+pub fn statement_id(name: &str) -> Key {
+    const OFFSET: u128 = 0x6c62272e07bb014262b821756295c58d;
+    const PRIME: u128 = 0x0000000001000000000000000000013b;
+    let hash = name.bytes().fold(OFFSET, |hash, byte| (hash ^ u128::from(byte)).wrapping_mul(PRIME));
+    hash.to_be_bytes()
+}
+
+/// Statement names are 1 to `MAX_NAME_LEN` ASCII letters, digits, `_`,
+/// `-` or `.`.
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name.bytes().all(|b| b.is_ascii_alphanumeric() || matches!(b, b'_' | b'-' | b'.'))
+}
+
+/// Variables are bound as SQLite named parameters (`:name`), so they follow
+/// identifier rules.
+pub fn is_valid_var(var: &str) -> bool {
+    let mut bytes = var.bytes();
+    matches!(bytes.next(), Some(b) if b.is_ascii_alphabetic() || b == b'_')
+        && bytes.all(|b| b.is_ascii_alphanumeric() || b == b'_')
+        && var.len() <= MAX_NAME_LEN
+}
+
+/// A SQLite value, bound as a parameter or read from a result row.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SqlValue {
+    Null,
+    Integer(i64),
+    Real(f64),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+impl SqlValue {
+    fn tag(&self) -> u8 {
+        match self {
+            SqlValue::Null => 0,
+            SqlValue::Integer(_) => 1,
+            SqlValue::Real(_) => 2,
+            SqlValue::Text(_) => 3,
+            SqlValue::Blob(_) => 4,
+        }
+    }
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.push(self.tag());
+        match self {
+            SqlValue::Null => {},
+            SqlValue::Integer(value) => out.extend_from_slice(&value.to_be_bytes()),
+            SqlValue::Real(value) => out.extend_from_slice(&value.to_be_bytes()),
+            SqlValue::Text(text) => put_long(out, text.as_bytes()),
+            SqlValue::Blob(blob) => put_long(out, blob),
+        }
+    }
+
+    fn decode(buf: &[u8]) -> Result<(SqlValue, &[u8]), Status> {
+        let (&tag, rest) = buf.split_first().ok_or(Status::BadLength)?;
+        Ok(match tag {
+            0 => (SqlValue::Null, rest),
+            1 => {
+                let (bytes, rest) = take_8(rest)?;
+                (SqlValue::Integer(i64::from_be_bytes(bytes)), rest)
+            },
+            2 => {
+                let (bytes, rest) = take_8(rest)?;
+                (SqlValue::Real(f64::from_be_bytes(bytes)), rest)
+            },
+            3 => {
+                let (bytes, rest) = take_long(rest)?;
+                (SqlValue::Text(String::from_utf8(bytes.to_vec()).map_err(|_| Status::Malformed)?), rest)
+            },
+            4 => {
+                let (bytes, rest) = take_long(rest)?;
+                (SqlValue::Blob(bytes.to_vec()), rest)
+            },
+            _ => return Err(Status::Malformed),
+        })
+    }
+}
+
+/// What EXEC produced: a change count for statements without result
+/// columns, the rows otherwise.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExecResult {
+    Changes(u64),
+    Rows { columns: Vec<String>, rows: Vec<Vec<SqlValue>> },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Request {
+    Define { name: String, role: String, statement: String, vars: Vec<String> },
+    List,
+    Drop { id: Key },
+    /// `roles` are those of the user the statement runs for.
+    Exec { id: Key, roles: Vec<String>, params: Vec<SqlValue> },
+    Begin,
+    Commit,
+    Rollback,
+}
+
+impl Request {
+    pub fn command(&self) -> Command {
+        match self {
+            Request::Define { .. } => Command::Define,
+            Request::List => Command::List,
+            Request::Drop { .. } => Command::Drop,
+            Request::Exec { .. } => Command::Exec,
+            Request::Begin => Command::Begin,
+            Request::Commit => Command::Commit,
+            Request::Rollback => Command::Rollback,
+        }
+    }
+
+    /// Fails with `BadLength` for a name, role or variable longer than a
+    /// length byte allows, or for more of them than a count byte does.
+    pub fn encode(&self) -> Result<Vec<u8>, Status> {
+        let mut out = vec![self.command() as u8];
+        match self {
+            Request::Define { name, role, statement, vars } => {
+                put_short(&mut out, name)?;
+                put_short(&mut out, role)?;
+                out.extend_from_slice(&(statement.len() as u32).to_be_bytes());
+                out.extend_from_slice(statement.as_bytes());
+                put_count(&mut out, vars.len())?;
+                for var in vars {
+                    put_short(&mut out, var)?;
+                }
+            },
+            Request::List | Request::Begin | Request::Commit | Request::Rollback => {},
+            Request::Drop { id } => out.extend_from_slice(id),
+            Request::Exec { id, roles, params } => {
+                out.extend_from_slice(id);
+                put_count(&mut out, roles.len())?;
+                for role in roles {
+                    put_short(&mut out, role)?;
+                }
+                put_count(&mut out, params.len())?;
+                for param in params {
+                    param.encode(&mut out);
+                }
+            },
+        }
+        Ok(out)
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self, Status> {
+        if buf.is_empty() {
+            return Err(Status::BadLength);
+        }
+        let command = Command::try_from(buf[0])?;
+        let body = &buf[1..];
+        Ok(match command {
+            Command::Define => {
+                let (name, rest) = take_short(body)?;
+                let (role, rest) = take_short(rest)?;
+                let (statement, mut rest) = take_long(rest)?;
+                if statement.len() > MAX_SQL_LEN {
+                    return Err(Status::BadLength);
+                }
+                let statement = String::from_utf8(statement.to_vec()).map_err(|_| Status::Malformed)?;
+                let count = usize::from(*rest.first().ok_or(Status::BadLength)?);
+                if count > MAX_STATEMENT_VARS {
+                    return Err(Status::BadLength);
+                }
+                rest = &rest[1..];
+                let mut vars = Vec::with_capacity(count);
+                for _ in 0..count {
+                    let (var, tail) = take_short(rest)?;
+                    vars.push(var);
+                    rest = tail;
+                }
+                if !rest.is_empty() {
+                    return Err(Status::BadLength);
+                }
+                Request::Define { name, role, statement, vars }
+            },
+            Command::List => without_body(body, Request::List)?,
+            Command::Begin => without_body(body, Request::Begin)?,
+            Command::Commit => without_body(body, Request::Commit)?,
+            Command::Rollback => without_body(body, Request::Rollback)?,
+            Command::Drop => {
+                if body.len() != KEY_LEN {
+                    return Err(Status::BadLength);
+                }
+                Request::Drop { id: read_key(body)? }
+            },
+            Command::Exec => {
+                let id = read_key(body)?;
+                let count = usize::from(*body.get(KEY_LEN).ok_or(Status::BadLength)?);
+                if count > MAX_EXEC_ROLES {
+                    return Err(Status::BadLength);
+                }
+                let mut rest = &body[KEY_LEN + 1..];
+                let mut roles = Vec::with_capacity(count);
+                for _ in 0..count {
+                    let (role, tail) = take_short(rest)?;
+                    roles.push(role);
+                    rest = tail;
+                }
+                let count = usize::from(*rest.first().ok_or(Status::BadLength)?);
+                if count > MAX_STATEMENT_VARS {
+                    return Err(Status::BadLength);
+                }
+                rest = &rest[1..];
+                let mut params = Vec::with_capacity(count);
+                for _ in 0..count {
+                    let (param, tail) = SqlValue::decode(rest)?;
+                    params.push(param);
+                    rest = tail;
+                }
+                if !rest.is_empty() {
+                    return Err(Status::BadLength);
+                }
+                Request::Exec { id, roles, params }
+            },
+        })
+    }
+}
+
+/// For commands that take no arguments.
+fn without_body(body: &[u8], request: Request) -> Result<Request, Status> {
+    if !body.is_empty() {
+        return Err(Status::BadLength);
+    }
+    Ok(request)
+}
+
+fn put_short(out: &mut Vec<u8>, text: &str) -> Result<(), Status> {
+    put_count(out, text.len())?;
+    out.extend_from_slice(text.as_bytes());
+    Ok(())
+}
+
+fn put_count(out: &mut Vec<u8>, count: usize) -> Result<(), Status> {
+    out.push(u8::try_from(count).map_err(|_| Status::BadLength)?);
+    Ok(())
+}
+
+fn put_long(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    out.extend_from_slice(bytes);
+}
+
+/// Reads a `[len u32][bytes]` field.
+fn take_long(buf: &[u8]) -> Result<(&[u8], &[u8]), Status> {
+    let (len, rest) = take_u32(buf)?;
+    let bytes = rest.get(..len as usize).ok_or(Status::BadLength)?;
+    Ok((bytes, &rest[len as usize..]))
+}
+
+fn take_u32(buf: &[u8]) -> Result<(u32, &[u8]), Status> {
+    let bytes = buf.get(..4).ok_or(Status::BadLength)?;
+    Ok((u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]), &buf[4..]))
+}
+
+fn take_8(buf: &[u8]) -> Result<([u8; 8], &[u8]), Status> {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(buf.get(..8).ok_or(Status::BadLength)?);
+    Ok((bytes, &buf[8..]))
+}
+
+/// Reads a `[len u8][utf-8]` string.
+fn take_short(buf: &[u8]) -> Result<(String, &[u8]), Status> {
+    let len = usize::from(*buf.first().ok_or(Status::BadLength)?);
+    let text = buf.get(1..1 + len).ok_or(Status::BadLength)?;
+    let text = String::from_utf8(text.to_vec()).map_err(|_| Status::Malformed)?;
+    Ok((text, &buf[1 + len..]))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Response {
+    /// The ID the statement is stored under.
+    Define { id: Key },
+    List(Vec<Statement>),
+    Drop,
+    Exec(ExecResult),
+    Begin,
+    Commit,
+    Rollback,
+    /// `Status::Rejected`, only DEFINE answers with it.
+    Rejected(Rejection),
+    Error { cmd: u8, status: Status },
+}
+
+impl Response {
+    pub fn error(cmd: Command, status: Status) -> Self {
+        Response::Error { cmd: cmd as u8, status }
+    }
+
+    /// The command byte the answer starts with.
+    fn command(&self) -> u8 {
+        let cmd = match self {
+            Response::Define { .. } | Response::Rejected(_) => Command::Define,
+            Response::List(_) => Command::List,
+            Response::Drop => Command::Drop,
+            Response::Exec(_) => Command::Exec,
+            Response::Begin => Command::Begin,
+            Response::Commit => Command::Commit,
+            Response::Rollback => Command::Rollback,
+            Response::Error { cmd, .. } => return *cmd,
+        };
+        cmd as u8
+    }
+
+    /// A column name longer than a length byte allows turns the answer
+    /// into a `BadLength` error.
+    pub fn encode(&self) -> Vec<u8> {
+        self.try_encode().unwrap_or_else(|status| vec![self.command(), status as u8])
+    }
+
+    fn try_encode(&self) -> Result<Vec<u8>, Status> {
+        Ok(match self {
+            Response::Define { id } => {
+                let mut out = vec![Command::Define as u8, Status::Ok as u8];
+                out.extend_from_slice(id);
+                out
+            },
+            Response::List(statements) => {
+                let mut out = vec![Command::List as u8, Status::Ok as u8];
+                // Plain strings and byte arrays cannot fail to serialize.
+                out.append(&mut serde_json::to_vec(statements).unwrap_or_default());
+                out
+            },
+            Response::Drop => vec![Command::Drop as u8, Status::Ok as u8],
+            Response::Begin => vec![Command::Begin as u8, Status::Ok as u8],
+            Response::Commit => vec![Command::Commit as u8, Status::Ok as u8],
+            Response::Rollback => vec![Command::Rollback as u8, Status::Ok as u8],
+            Response::Exec(ExecResult::Changes(changes)) => {
+                let mut out = vec![Command::Exec as u8, Status::Ok as u8, 0];
+                out.extend_from_slice(&changes.to_be_bytes());
+                out
+            },
+            Response::Exec(ExecResult::Rows { columns, rows }) => {
+                let mut out = vec![Command::Exec as u8, Status::Ok as u8, 1];
+                let count = u16::try_from(columns.len()).map_err(|_| Status::BadLength)?;
+                out.extend_from_slice(&count.to_be_bytes());
+                for column in columns {
+                    put_short(&mut out, column)?;
+                }
+                out.extend_from_slice(&(rows.len() as u32).to_be_bytes());
+                for value in rows.iter().flatten() {
+                    value.encode(&mut out);
+                }
+                out
+            },
+            Response::Rejected(rejection) => {
+                let mut out = vec![Command::Define as u8, Status::Rejected as u8];
+                // Plain strings and numbers cannot fail to serialize.
+                out.append(&mut serde_json::to_vec(rejection).unwrap_or_default());
+                out
+            },
+            Response::Error { cmd, status } => vec![*cmd, *status as u8],
+        })
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self, Status> {
+        let (cmd, status, body) = split_response(buf)?;
+        if status == Status::Rejected {
+            return Ok(Response::Rejected(serde_json::from_slice(body).map_err(|_| Status::Malformed)?));
+        }
+        if status != Status::Ok {
+            return Ok(Response::Error { cmd, status });
+        }
+        Ok(match Command::try_from(cmd)? {
+            Command::Define => Response::Define { id: read_key(body)? },
+            Command::List => Response::List(serde_json::from_slice(body).map_err(|_| Status::Malformed)?),
+            Command::Drop => Response::Drop,
+            Command::Exec => Response::Exec(decode_exec(body)?),
+            Command::Begin => Response::Begin,
+            Command::Commit => Response::Commit,
+            Command::Rollback => Response::Rollback,
+        })
+    }
+}
+
+fn decode_exec(body: &[u8]) -> Result<ExecResult, Status> {
+    let (&kind, rest) = body.split_first().ok_or(Status::BadLength)?;
+    if kind == 0 {
+        return Ok(ExecResult::Changes(u64::from_be_bytes(take_8(rest)?.0)));
+    }
+    let count = rest.get(..2).ok_or(Status::BadLength)?;
+    let count = usize::from(u16::from_be_bytes([count[0], count[1]]));
+    let mut rest = &rest[2..];
+    let mut columns = Vec::with_capacity(count);
+    for _ in 0..count {
+        let (column, tail) = take_short(rest)?;
+        columns.push(column);
+        rest = tail;
+    }
+    let (row_count, mut rest) = take_u32(rest)?;
+    // Every value takes at least its type byte, and a result without
+    // columns has no rows, so a short body cannot claim millions of them.
+    if count == 0 && row_count > 0 {
+        return Err(Status::Malformed);
+    }
+    if row_count as usize > rest.len() / count.max(1) {
+        return Err(Status::BadLength);
+    }
+    let mut rows = Vec::with_capacity(row_count as usize);
+    for _ in 0..row_count {
+        let mut row = Vec::with_capacity(count);
+        for _ in 0..count {
+            let (value, tail) = SqlValue::decode(rest)?;
+            row.push(value);
+            rest = tail;
+        }
+        rows.push(row);
+    }
+    Ok(ExecResult::Rows { columns, rows })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn requests() -> Vec<Request> {
+        let id = statement_id("orders.insert");
+        vec![
+            Request::Define {
+                name: "orders.insert".to_string(),
+                role: "app".to_string(),
+                statement: "INSERT INTO orders (id) VALUES (:id)".to_string(),
+                vars: vec!["id".to_string()],
+            },
+            Request::Define { name: "n".to_string(), role: String::new(), statement: "SELECT 1".to_string(), vars: vec![] },
+            Request::List,
+            Request::Drop { id },
+            Request::Exec {
+                id,
+                roles: vec!["app".to_string(), "reports".to_string()],
+                params: vec![
+                    SqlValue::Null,
+                    SqlValue::Integer(-7),
+                    SqlValue::Real(1.5),
+                    SqlValue::Text("text".to_string()),
+                    SqlValue::Blob(vec![0, 1, 255]),
+                ],
+            },
+            Request::Exec { id, roles: vec![], params: vec![] },
+            Request::Begin,
+            Request::Commit,
+            Request::Rollback,
+        ]
+    }
+
+    fn responses() -> Vec<Response> {
+        let id = statement_id("orders.insert");
+        vec![
+            Response::Define { id },
+            Response::List(vec![Statement {
+                id,
+                name: "orders.insert".to_string(),
+                role: "app".to_string(),
+                statement: "INSERT INTO orders (id) VALUES (:id)".to_string(),
+                vars: vec!["id".to_string()],
+            }]),
+            Response::List(vec![]),
+            Response::Drop,
+            Response::Exec(ExecResult::Changes(3)),
+            Response::Exec(ExecResult::Rows {
+                columns: vec!["id".to_string(), "name".to_string()],
+                rows: vec![
+                    vec![SqlValue::Integer(1), SqlValue::Text("ann".to_string())],
+                    vec![SqlValue::Real(2.5), SqlValue::Null],
+                ],
+            }),
+            Response::Exec(ExecResult::Rows { columns: vec!["id".to_string()], rows: vec![] }),
+            Response::Begin,
+            Response::Commit,
+            Response::Rollback,
+            Response::Rejected(Rejection::TableNotAllowed { table: "secrets".to_string(), role: "app".to_string() }),
+            Response::Rejected(Rejection::Attach),
+            Response::error(Command::Exec, Status::QueryFailed),
+        ]
+    }
+
+    #[test]
+    fn requests_round_trip() {
+        for request in requests() {
+            assert_eq!(Request::decode(&request.encode().unwrap()), Ok(request.clone()), "{:?}", request);
+        }
+    }
+
+    #[test]
+    fn responses_round_trip() {
+        for response in responses() {
+            assert_eq!(Response::decode(&response.encode()), Ok(response.clone()), "{:?}", response);
+        }
+    }
+
+    /// A cut-off message must never panic nor pass for the original.
+    #[test]
+    fn truncated_requests() {
+        for request in requests() {
+            let raw = request.encode().unwrap();
+            for len in 0..raw.len() {
+                assert!(Request::decode(&raw[..len]).is_err(), "{:?} cut to {}", request, len);
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_responses() {
+        for response in responses() {
+            let raw = response.encode();
+            for len in 0..raw.len() {
+                assert_ne!(Response::decode(&raw[..len]), Ok(response.clone()), "{:?} cut to {}", response, len);
+            }
+        }
+    }
+
+    #[test]
+    fn oversized_fields_are_refused() {
+        let id = statement_id("n");
+        let long = "r".repeat(256);
+        let exec = Request::Exec { id, roles: vec![long.clone()], params: vec![] };
+        assert_eq!(exec.encode(), Err(Status::BadLength));
+        let exec = Request::Exec { id, roles: vec![], params: vec![SqlValue::Null; 256] };
+        assert_eq!(exec.encode(), Err(Status::BadLength));
+        let define = Request::Define { name: long.clone(), role: "app".to_string(), statement: "SELECT 1".to_string(), vars: vec![] };
+        assert_eq!(define.encode(), Err(Status::BadLength));
+        let rows = Response::Exec(ExecResult::Rows { columns: vec![long], rows: vec![] });
+        assert_eq!(rows.encode(), [Command::Exec as u8, Status::BadLength as u8]);
+    }
+
+    #[test]
+    fn trailing_bytes_are_rejected() {
+        for request in requests() {
+            let mut raw = request.encode().unwrap();
+            raw.push(0);
+            assert!(Request::decode(&raw).is_err(), "{:?}", request);
+        }
+    }
+
+    #[test]
+    fn row_count_is_bounded_by_the_body() {
+        let mut raw = vec![Command::Exec as u8, Status::Ok as u8, 1, 0, 0];
+        raw.extend_from_slice(&u32::MAX.to_be_bytes());
+        assert_eq!(Response::decode(&raw), Err(Status::Malformed));
+
+        let mut raw = vec![Command::Exec as u8, Status::Ok as u8, 1, 0, 1, 2, b'i', b'd'];
+        raw.extend_from_slice(&1000u32.to_be_bytes());
+        raw.push(0);
+        assert_eq!(Response::decode(&raw), Err(Status::BadLength));
+    }
+}
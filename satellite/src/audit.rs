@@ -0,0 +1,44 @@
+use std::{net::IpAddr, time::SystemTime};
+use serde::Serialize;
+use sentinel_proto::sonar;
+
+use crate::AppState;
+
+/// One line in sonar's journal.
+#[derive(Debug, Serialize)]
+pub struct AuditEvent<'a> {
+    pub event: &'a str,
+    pub subject: &'a str,
+    pub ip: Option<IpAddr>,
+    pub success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<&'a str>,
+    pub ts: u64,
+}
+
+impl<'a> AuditEvent<'a> {
+    pub fn new(event: &'a str, subject: &'a str, ip: Option<IpAddr>, success: bool) -> Self {
+        let ts = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs();
+        AuditEvent { event, subject, ip, success, detail: None, ts }
+    }
+
+    pub fn with_detail(mut self, detail: &'a str) -> Self {
+        self.detail = Some(detail);
+        self
+    }
+}
+
+/// Writes an event to sonar. A failed write is logged but never fails the
+/// request that caused it.
+pub async fn record(state: &AppState, event: AuditEvent<'_>) {
+    let entry = match serde_json::to_vec(&event) {
+        Ok(entry) => entry,
+        Err(err) => {
+            tracing::error!("failed to encode audit event: {}", err);
+            return;
+        }
+    };
+    if let Err(err) = state.sonar.send(sonar::Request::Log(entry).encode()).await {
+        tracing::error!("failed to write audit event {:?}: {}", event, err);
+    }
+}
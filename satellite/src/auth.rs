@@ -0,0 +1,206 @@
+use std::{
+    fmt::Display,
+    net::SocketAddr,
+    time::{Duration, SystemTime},
+};
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, Extension, FromRequest, RequestParts, TypedHeader},
+    headers::{authorization::Bearer, Authorization},
+    http::{header::{RETRY_AFTER, WWW_AUTHENTICATE}, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use jsonwebtoken::jwk::JwkSet;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    audit::{self, AuditEvent},
+    authz::{self, Grants},
+    backend::BackendError,
+    keys::random_token,
+    session,
+    users,
+    AppState, SharedState,
+};
+
+/// Access tokens are short-lived; clients renew them through `/session/refresh`.
+pub const ACCESS_TTL: u64 = 15 * 60;
+
+impl Display for Claims {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Email: {}\nCompany: {}", self.sub, self.aud)
+    }
+}
+
+impl Claims {
+    pub fn for_user(username: &str, grants: Grants) -> Self {
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+        Claims {
+            sub: username.to_owned(),
+            aud: "orbit1".to_owned(),
+            iss: "satellite".to_owned(),
+            iat: now,
+            exp: now + ACCESS_TTL,
+            nbf: now - 60,
+            jti: random_token(16),
+            roles: grants.roles,
+            groups: grants.groups,
+            perms: grants.permissions,
+        }
+    }
+}
+
+impl AuthBody {
+    pub fn new(access_token: String, expires_in: u64) -> Self {
+        Self {
+            access_token,
+            token_type: "Bearer".to_string(),
+            expires_in,
+            refresh_token: None,
+        }
+    }
+
+    pub fn with_refresh(mut self, refresh_token: String) -> Self {
+        self.refresh_token = Some(refresh_token);
+        self
+    }
+}
+
+#[async_trait]
+impl<S> FromRequest<S> for Claims
+where
+    S: Send + Sync,
+{
+    type Rejection = AuthError;
+
+    async fn from_request(req: &mut RequestParts<S>) -> Result<Self, Self::Rejection> {
+        let TypedHeader(Authorization(bearer)) =
+            TypedHeader::<Authorization<Bearer>>::from_request(req)
+                .await
+                .map_err(|_| AuthError::InvalidToken)?;
+        let state = req.extensions().get::<SharedState>().cloned().ok_or(AuthError::InvalidToken)?;
+        let claims: Claims = state.keys.verify(bearer.token())
+            .await
+            .map_err(|_| AuthError::InvalidToken)?;
+
+        if session::is_revoked(&state, &claims.jti).await.map_err(AuthError::Backend)? {
+            return Err(AuthError::InvalidToken);
+        }
+
+        Ok(claims)
+    }
+}
+
+impl IntoResponse for AuthError {
+    fn into_response(self) -> Response {
+        match self {
+            AuthError::WrongCredentials | AuthError::InvalidToken => {
+                let mut headers = HeaderMap::new();
+                headers.insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer realm='orbit1'"));
+                (headers, StatusCode::UNAUTHORIZED).into_response()
+            },
+            AuthError::Forbidden => StatusCode::FORBIDDEN.into_response(),
+            AuthError::TokenCreation => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+            AuthError::RateLimited(wait) => {
+                let mut headers = HeaderMap::new();
+                headers.insert(RETRY_AFTER, HeaderValue::from(wait.as_secs().max(1)));
+                (headers, StatusCode::TOO_MANY_REQUESTS).into_response()
+            },
+            AuthError::Backend(err) => err.into_response(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Claims {
+    pub sub: String,
+    pub aud: String,
+    pub iss: String,
+    pub exp: u64,
+    pub nbf: u64,
+    pub iat: u64,
+    pub jti: String,
+    #[serde(default)]
+    pub roles: Vec<String>,
+    #[serde(default)]
+    pub groups: Vec<String>,
+    /// Permissions of all roles, resolved when the token was issued.
+    #[serde(default)]
+    pub perms: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AuthBody {
+    access_token: String,
+    token_type: String,
+    expires_in: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    refresh_token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Credentials {
+    username: String,
+    password: String,
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    WrongCredentials,
+    TokenCreation,
+    InvalidToken,
+    Forbidden,
+    RateLimited(Duration),
+    Backend(BackendError),
+}
+
+pub fn issue_token(state: &AppState, claims: &Claims) -> Result<String, AuthError> {
+    state.keys.sign(claims).map_err(|_| AuthError::TokenCreation)
+}
+
+pub async fn login(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(state): Extension<SharedState>,
+    Json(credentials): Json<Credentials>,
+) -> Result<Json<AuthBody>, AuthError> {
+    let ip = addr.ip();
+    if let Err(wait) = state.limiter.check(&credentials.username, ip) {
+        audit::record(&state, AuditEvent::new("login.limited", &credentials.username, Some(ip), false)).await;
+        return Err(AuthError::RateLimited(wait));
+    }
+    let user = match users::load(&state, &credentials.username).await {
+        Ok(user) => user,
+        Err(err) => {
+            state.limiter.release(&credentials.username, ip);
+            return Err(AuthError::Backend(err));
+        },
+    };
+    // Unknown users are checked against a dummy hash, so they take as long
+    // as a wrong password and the timing does not tell which names exist.
+    let verified = match &user {
+        Some(user) => users::verify_password(user.password_hash.clone(), credentials.password).await,
+        None => {
+            users::verify_password(users::DUMMY_HASH.to_owned(), credentials.password).await;
+            false
+        },
+    };
+    audit::record(&state, AuditEvent::new("login", &credentials.username, Some(ip), verified)).await;
+    if !verified {
+        // The attempt taken by `check` stays counted as the failure.
+        return Err(AuthError::WrongCredentials);
+    }
+    state.limiter.record_success(&credentials.username, ip);
+
+    // `verified` is only true when the user was found.
+    let user = user.expect("verified user exists");
+    let grants = authz::resolve(&state, &user).await.map_err(AuthError::Backend)?;
+    let claims = Claims::for_user(&credentials.username, grants);
+    let access_token = issue_token(&state, &claims)?;
+    let refresh_token = session::issue_refresh(&state, &credentials.username).await.map_err(AuthError::Backend)?;
+    Ok(Json(AuthBody::new(access_token, ACCESS_TTL).with_refresh(refresh_token)))
+}
+
+pub async fn jwks(Extension(state): Extension<SharedState>) -> Json<JwkSet> {
+    Json(state.keys.jwks())
+}
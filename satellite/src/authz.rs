@@ -0,0 +1,273 @@
+use std::{
+    collections::BTreeSet,
+    marker::PhantomData,
+    net::{IpAddr, SocketAddr},
+};
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, Extension, FromRequest, Path, RequestParts},
+    http::StatusCode,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use sentinel_proto::{star, Status};
+
+use crate::{
+    audit::{self, AuditEvent},
+    auth::{AuthError, Claims},
+    backend::BackendError,
+    keys::hash_key,
+    users::{self, UserRecord},
+    AppState, SharedState,
+};
+
+/// Grants every permission; it has no record in star.
+pub const ADMIN_ROLE: &str = "admin";
+
+/// A permission a route can require through `Require<P>`.
+pub trait Permission {
+    const NAME: &'static str;
+}
+
+macro_rules! permissions {
+    ($($ty:ident => $name:literal),* $(,)?) => {
+        $(
+            pub struct $ty;
+
+            impl Permission for $ty {
+                const NAME: &'static str = $name;
+            }
+        )*
+    };
+}
+
+permissions! {
+    KvRead => "kv:read",
+    KvWrite => "kv:write",
+    UsersWrite => "users:write",
+    AuthzWrite => "authz:write",
+}
+
+/// Stored in star's `config` bucket under `role:<name>`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoleRecord {
+    pub name: String,
+    pub permissions: Vec<String>,
+}
+
+/// Stored in star's `group` bucket under the group name. Members of a group
+/// get all of its roles.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GroupRecord {
+    pub name: String,
+    pub roles: Vec<String>,
+}
+
+/// What a user is allowed to do, resolved when a token is issued.
+#[derive(Debug, Default)]
+pub struct Grants {
+    pub roles: Vec<String>,
+    pub groups: Vec<String>,
+    pub permissions: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RoleUpdate {
+    permissions: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GroupUpdate {
+    roles: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UserGrantsUpdate {
+    #[serde(default)]
+    roles: Vec<String>,
+    #[serde(default)]
+    groups: Vec<String>,
+}
+
+fn role_key(name: &str) -> sentinel_proto::Key {
+    hash_key(format!("role:{}", name).as_bytes())
+}
+
+/// Loads JSON records of one bucket in a single round trip, one entry per
+/// key in order.
+async fn load_json_many<T: serde::de::DeserializeOwned>(
+    state: &AppState,
+    bucket: star::Bucket,
+    keys: Vec<sentinel_proto::Key>,
+) -> Result<Vec<Option<T>>, BackendError> {
+    let keys: Vec<Vec<u8>> = keys.iter().map(|key| key.to_vec()).collect();
+    state.star.star_mget(bucket, &keys).await?
+        .into_iter()
+        .map(|item| match item {
+            Some((_, raw)) => serde_json::from_slice(&raw)
+                .map(Some)
+                .map_err(|_| BackendError::Status("star", Status::Malformed)),
+            None => Ok(None),
+        })
+        .collect()
+}
+
+async fn save_json<T: Serialize>(
+    state: &AppState,
+    bucket: star::Bucket,
+    key: sentinel_proto::Key,
+    value: &T,
+) -> Result<(), BackendError> {
+    // Records here are plain strings and lists of strings, which always serialize.
+    let raw = serde_json::to_vec(value).unwrap_or_default();
+    state.star.star_set(bucket, &key, raw).await
+}
+
+/// Collects the user's own roles, the roles of their groups and the
+/// permissions of all those roles. Unknown groups and roles grant nothing.
+pub async fn resolve(state: &AppState, user: &UserRecord) -> Result<Grants, BackendError> {
+    let mut roles: BTreeSet<String> = user.roles.iter().cloned().collect();
+    let group_keys = user.groups.iter().map(|group| hash_key(group.as_bytes())).collect();
+    for record in load_json_many::<GroupRecord>(state, star::Bucket::Group, group_keys).await?.into_iter().flatten() {
+        roles.extend(record.roles);
+    }
+    let mut permissions = BTreeSet::new();
+    if roles.contains(ADMIN_ROLE) {
+        permissions.insert("*".to_owned());
+    }
+    let role_keys = roles.iter().filter(|role| *role != ADMIN_ROLE).map(|role| role_key(role)).collect();
+    for record in load_json_many::<RoleRecord>(state, star::Bucket::Config, role_keys).await?.into_iter().flatten() {
+        permissions.extend(record.permissions);
+    }
+    Ok(Grants {
+        roles: roles.into_iter().collect(),
+        groups: user.groups.clone(),
+        permissions: permissions.into_iter().collect(),
+    })
+}
+
+/// Matches `needed` against granted patterns, segment by segment on `:`.
+/// A `*` segment matches the rest, so `store:*` covers `store:query:orders`.
+pub fn allows(granted: &[String], needed: &str) -> bool {
+    granted.iter().any(|pattern| {
+        let mut wanted = needed.split(':');
+        for segment in pattern.split(':') {
+            if segment == "*" {
+                return true;
+            }
+            if wanted.next() != Some(segment) {
+                return false;
+            }
+        }
+        wanted.next().is_none()
+    })
+}
+
+/// Checks a permission that is only known at runtime, such as
+/// `store:query:<name>`. Denials are written to sonar.
+pub async fn authorize(state: &AppState, claims: &Claims, needed: &str, ip: Option<IpAddr>) -> Result<(), AuthError> {
+    if allows(&claims.perms, needed) {
+        return Ok(());
+    }
+    audit::record(state, AuditEvent::new("authz.denied", &claims.sub, ip, false).with_detail(needed)).await;
+    Err(AuthError::Forbidden)
+}
+
+/// Extractor for routes that need a fixed permission.
+pub struct Require<P>(pub Claims, PhantomData<fn() -> P>);
+
+#[async_trait]
+impl<S, P> FromRequest<S> for Require<P>
+where
+    S: Send + Sync,
+    P: Permission,
+{
+    type Rejection = AuthError;
+
+    async fn from_request(req: &mut RequestParts<S>) -> Result<Self, Self::Rejection> {
+        let claims = Claims::from_request(req).await?;
+        let state = req.extensions().get::<SharedState>().cloned().ok_or(AuthError::InvalidToken)?;
+        let ip = req.extensions().get::<ConnectInfo<SocketAddr>>().map(|ConnectInfo(addr)| addr.ip());
+        authorize(&state, &claims, P::NAME, ip).await?;
+        Ok(Require(claims, PhantomData))
+    }
+}
+
+pub async fn put_role(
+    Require(claims, _): Require<AuthzWrite>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(name): Path<String>,
+    Extension(state): Extension<SharedState>,
+    Json(update): Json<RoleUpdate>,
+) -> Result<StatusCode, BackendError> {
+    let record = RoleRecord { name, permissions: update.permissions };
+    save_json(&state, star::Bucket::Config, role_key(&record.name), &record).await?;
+    audit::record(&state, AuditEvent::new("authz.role", &claims.sub, Some(addr.ip()), true).with_detail(&record.name)).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn put_group(
+    Require(claims, _): Require<AuthzWrite>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(name): Path<String>,
+    Extension(state): Extension<SharedState>,
+    Json(update): Json<GroupUpdate>,
+) -> Result<StatusCode, BackendError> {
+    let record = GroupRecord { name, roles: update.roles };
+    save_json(&state, star::Bucket::Group, hash_key(record.name.as_bytes()), &record).await?;
+    audit::record(&state, AuditEvent::new("authz.group", &claims.sub, Some(addr.ip()), true).with_detail(&record.name)).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn put_user_grants(
+    Require(claims, _): Require<AuthzWrite>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(username): Path<String>,
+    Extension(state): Extension<SharedState>,
+    Json(update): Json<UserGrantsUpdate>,
+) -> Result<StatusCode, BackendError> {
+    let found = users::update(&state, &username, |user| {
+        user.roles = update.roles.clone();
+        user.groups = update.groups.clone();
+    }).await?;
+    if !found {
+        return Ok(StatusCode::NOT_FOUND);
+    }
+    audit::record(&state, AuditEvent::new("authz.user", &claims.sub, Some(addr.ip()), true).with_detail(&username)).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn granted(patterns: &[&str]) -> Vec<String> {
+        patterns.iter().map(|pattern| pattern.to_string()).collect()
+    }
+
+    #[test]
+    fn star_matches_the_rest() {
+        assert!(allows(&granted(&["*"]), "users:write"));
+        assert!(allows(&granted(&["store:*"]), "store:query:orders"));
+        assert!(allows(&granted(&["store:query:*"]), "store:query:orders"));
+        assert!(!allows(&granted(&["store:query:*"]), "kv:read"));
+        assert!(!allows(&granted(&["kv:*"]), "store:query:orders"));
+    }
+
+    #[test]
+    fn segments_match_exactly() {
+        assert!(allows(&granted(&["kv:read"]), "kv:read"));
+        assert!(!allows(&granted(&["kv:read"]), "kv:readwrite"));
+        assert!(!allows(&granted(&["kv:rea"]), "kv:read"));
+        assert!(!allows(&granted(&[]), "kv:read"));
+    }
+
+    #[test]
+    fn a_prefix_is_not_a_grant() {
+        assert!(!allows(&granted(&["store"]), "store:query:x"));
+        assert!(!allows(&granted(&["store:query"]), "store:query:x"));
+        assert!(!allows(&granted(&["store:query:x"]), "store"));
+        assert!(!allows(&granted(&["store:query:x:y"]), "store:query:x"));
+        assert!(allows(&granted(&["store", "store:query:x"]), "store:query:x"));
+    }
+}
@@ -0,0 +1,712 @@
+use std::{
+    fmt,
+    io,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use futures_util::future::BoxFuture;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::UnixStream,
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::{sleep, timeout},
+};
+use sentinel_proto::{
+    daemon,
+    frame::{Frame, FrameDecoder, FrameError},
+    star,
+    store::{self, ExecResult, SqlValue},
+    Key, Status,
+};
+
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    /// Connections in use at once, store transactions included.
+    pub size: usize,
+    /// Dedicated SUBSCRIBE connections, on top of `size`.
+    pub stream_size: usize,
+    pub call_timeout: Duration,
+    pub idle_timeout: Duration,
+    pub health_interval: Duration,
+    pub connect_retries: u32,
+    pub backoff_base: Duration,
+    pub backoff_max: Duration,
+    /// Bytes read from a backend socket at once.
+    pub buffer_size: usize,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            size: 8,
+            stream_size: 16,
+            call_timeout: Duration::from_secs(5),
+            idle_timeout: Duration::from_secs(20),
+            health_interval: Duration::from_secs(10),
+            connect_retries: 4,
+            backoff_base: Duration::from_millis(50),
+            backoff_max: Duration::from_secs(1),
+            buffer_size: 65536,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum BackendError {
+    /// No connection could be opened to the backend.
+    Unavailable(&'static str, io::Error),
+    /// The backend did not answer within `PoolConfig::call_timeout`.
+    Timeout(&'static str),
+    /// The connection broke mid-call.
+    Io(&'static str, io::Error),
+    /// The backend answered with something that is not a valid frame.
+    Protocol(&'static str, FrameError),
+    /// The backend answered with an error status.
+    Status(&'static str, Status),
+}
+
+impl fmt::Display for BackendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackendError::Unavailable(name, err) => write!(f, "{} is unavailable: {}", name, err),
+            BackendError::Timeout(name) => write!(f, "{} timed out", name),
+            BackendError::Io(name, err) => write!(f, "{} connection failed: {}", name, err),
+            BackendError::Protocol(name, err) => write!(f, "{} sent a bad frame: {}", name, err),
+            BackendError::Status(name, status) => write!(f, "{} answered {}", name, status),
+        }
+    }
+}
+
+impl IntoResponse for BackendError {
+    fn into_response(self) -> Response {
+        tracing::error!("{}", self);
+        let status = match self {
+            BackendError::Unavailable(..) | BackendError::Status(_, Status::Busy) => StatusCode::SERVICE_UNAVAILABLE,
+            BackendError::Status(_, Status::KeyTooLong | Status::BadLength) => StatusCode::BAD_REQUEST,
+            BackendError::Status(_, Status::NotFound) => StatusCode::NOT_FOUND,
+            BackendError::Status(_, Status::Forbidden) => StatusCode::FORBIDDEN,
+            BackendError::Status(_, Status::Conflict) => StatusCode::CONFLICT,
+            BackendError::Status(_, Status::TooLarge) => StatusCode::PAYLOAD_TOO_LARGE,
+            BackendError::Timeout(_)
+            | BackendError::Io(..)
+            | BackendError::Protocol(..)
+            | BackendError::Status(..) => StatusCode::BAD_GATEWAY,
+        };
+        (status, self.to_string()).into_response()
+    }
+}
+
+/// A live SUBSCRIBE stream; dropping it closes the connection, which ends
+/// the subscription on star's side, and frees its stream slot.
+pub struct Subscription {
+    backend: Arc<Backend>,
+    conn: Connection,
+    request_id: u32,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Subscription {
+    /// Waits for the next change. There is no timeout, events come whenever
+    /// the subscribed keys change.
+    pub async fn next(&mut self) -> Result<star::ChangeEvent, BackendError> {
+        let name = self.backend.name;
+        let rsp = self.backend.read_reply(&mut self.conn, self.request_id).await?;
+        match star::Response::decode(&rsp) {
+            Ok(star::Response::Event(event)) => Ok(event),
+            Ok(star::Response::Error { status, .. }) | Err(status) => Err(BackendError::Status(name, status)),
+            Ok(_) => Err(BackendError::Status(name, Status::Malformed)),
+        }
+    }
+}
+
+/// A store transaction with a connection of its own, see
+/// `Backend::store_transaction`.
+pub struct StoreTransaction<'a> {
+    backend: &'a Backend,
+    conn: Connection,
+    /// Whether `conn` came from the idle pool and has not been used yet.
+    pooled: bool,
+}
+
+impl StoreTransaction<'_> {
+    /// Runs a registered statement inside the transaction for a user
+    /// holding `roles`.
+    pub async fn exec(&mut self, id: Key, roles: Vec<String>, params: Vec<SqlValue>) -> Result<ExecResult, BackendError> {
+        match self.request(store::Request::Exec { id, roles, params }).await? {
+            store::Response::Exec(result) => Ok(result),
+            _ => Err(BackendError::Status(self.backend.name, Status::Malformed)),
+        }
+    }
+
+    async fn request(&mut self, req: store::Request) -> Result<store::Response, BackendError> {
+        let backend = self.backend;
+        let payload = req.encode().map_err(|status| BackendError::Status(backend.name, status))?;
+        let pooled = std::mem::take(&mut self.pooled);
+        let rsp = timeout(backend.config.call_timeout, backend.roundtrip(&mut self.conn, pooled, payload))
+            .await
+            .map_err(|_| BackendError::Timeout(backend.name))??;
+        match store::Response::decode(&rsp) {
+            Ok(store::Response::Error { status, .. }) | Err(status) => Err(BackendError::Status(backend.name, status)),
+            Ok(store::Response::Rejected(_)) => Err(BackendError::Status(backend.name, Status::Rejected)),
+            Ok(rsp) => Ok(rsp),
+        }
+    }
+
+    /// Hands the connection back to the pool once the transaction is over.
+    fn release(mut self) {
+        self.conn.last_used = Instant::now();
+        self.backend.idle.lock().unwrap().push(self.conn);
+    }
+}
+
+/// How one try at a request failed.
+enum Attempt {
+    /// The write failed, or the peer hung up before any byte of the
+    /// answer came back.
+    Closed(io::Error),
+    Failed(BackendError),
+}
+
+struct Connection {
+    stream: UnixStream,
+    decoder: FrameDecoder,
+    last_used: Instant,
+}
+
+impl Connection {
+    /// A pooled connection is only reused if the peer has not hung up and
+    /// has not left unread bytes behind.
+    fn is_reusable(&self, idle_timeout: Duration) -> bool {
+        if self.last_used.elapsed() > idle_timeout {
+            return false;
+        }
+        let mut probe = [0; 1];
+        matches!(self.stream.try_read(&mut probe), Err(err) if err.kind() == io::ErrorKind::WouldBlock)
+    }
+}
+
+/// Pool of framed connections to one socket daemon.
+pub struct Backend {
+    name: &'static str,
+    path: PathBuf,
+    config: PoolConfig,
+    idle: Mutex<Vec<Connection>>,
+    permits: Semaphore,
+    streams: Arc<Semaphore>,
+    /// `None` while the backend is reachable.
+    outage: Mutex<Option<Outage>>,
+    next_request_id: AtomicU32,
+}
+
+/// Since when and why a backend has been unreachable.
+#[derive(Debug, Clone)]
+pub struct Outage {
+    pub since: Instant,
+    pub error: String,
+}
+
+impl Backend {
+    pub fn new(name: &'static str, path: PathBuf, config: PoolConfig) -> Self {
+        Backend {
+            name,
+            path,
+            permits: Semaphore::new(config.size),
+            streams: Arc::new(Semaphore::new(config.stream_size)),
+            config,
+            idle: Mutex::new(Vec::new()),
+            outage: Mutex::new(None),
+            next_request_id: AtomicU32::new(0),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.outage.lock().unwrap().is_none()
+    }
+
+    pub fn outage(&self) -> Option<Outage> {
+        self.outage.lock().unwrap().clone()
+    }
+
+    fn mark_up(&self) {
+        if let Some(outage) = self.outage.lock().unwrap().take() {
+            tracing::info!("{}: reachable again after {:?}", self.name, outage.since.elapsed());
+        }
+    }
+
+    /// Logs only when an outage starts, later failures just update the
+    /// reported error.
+    fn mark_down(&self, error: &dyn fmt::Display) {
+        let mut outage = self.outage.lock().unwrap();
+        match outage.as_mut() {
+            Some(outage) => outage.error = error.to_string(),
+            None => {
+                tracing::error!("{}: unreachable: {}", self.name, error);
+                *outage = Some(Outage { since: Instant::now(), error: error.to_string() });
+            },
+        }
+    }
+
+    /// Sends one request and waits for the response carrying the same request ID.
+    pub async fn call(&self, payload: Vec<u8>) -> Result<Vec<u8>, BackendError> {
+        timeout(self.config.call_timeout, self.exchange(payload, true))
+            .await
+            .map_err(|_| BackendError::Timeout(self.name))?
+            .map(Option::unwrap_or_default)
+    }
+
+    /// Sends one request without waiting for an answer, for sonar.
+    pub async fn send(&self, payload: Vec<u8>) -> Result<(), BackendError> {
+        timeout(self.config.call_timeout, self.exchange(payload, false))
+            .await
+            .map_err(|_| BackendError::Timeout(self.name))?
+            .map(|_| ())
+    }
+
+    /// Sends a star request and decodes the answer, turning error statuses
+    /// into `BackendError::Status` except for `NotFound`, which callers
+    /// usually want to treat as a regular outcome.
+    pub async fn star(&self, req: star::Request) -> Result<star::Response, BackendError> {
+        let payload = req.encode().map_err(|status| BackendError::Status(self.name, status))?;
+        let rsp = self.call(payload).await?;
+        match star::Response::decode(&rsp) {
+            Ok(star::Response::Error { status, .. }) if status != Status::NotFound => Err(BackendError::Status(self.name, status)),
+            Ok(rsp) => Ok(rsp),
+            Err(status) => Err(BackendError::Status(self.name, status)),
+        }
+    }
+
+    pub async fn star_get(&self, bucket: star::Bucket, key: &[u8]) -> Result<Option<Vec<u8>>, BackendError> {
+        Ok(self.star_get_versioned(bucket, key).await?.map(|(_, value)| value))
+    }
+
+    /// Like `star_get`, also returning the version to pass to `star_cas`.
+    pub async fn star_get_versioned(&self, bucket: star::Bucket, key: &[u8]) -> Result<Option<(u64, Vec<u8>)>, BackendError> {
+        match self.star(star::Request::Get { bucket, key: key.to_vec() }).await? {
+            star::Response::Get { version, value } => Ok(Some((version, value))),
+            _ => Ok(None),
+        }
+    }
+
+    pub async fn star_set(&self, bucket: star::Bucket, key: &[u8], value: Vec<u8>) -> Result<(), BackendError> {
+        self.star(star::Request::Set { bucket, key: key.to_vec(), value, ttl: None }).await.map(|_| ())
+    }
+
+    /// Like `star_set`, but star drops the key after `ttl` seconds.
+    pub async fn star_set_ex(&self, bucket: star::Bucket, key: &[u8], value: Vec<u8>, ttl: u32) -> Result<(), BackendError> {
+        self.star(star::Request::Set { bucket, key: key.to_vec(), value, ttl: Some(ttl) }).await.map(|_| ())
+    }
+
+    pub async fn star_del(&self, bucket: star::Bucket, key: &[u8]) -> Result<(), BackendError> {
+        self.star(star::Request::Del { bucket, key: key.to_vec() }).await.map(|_| ())
+    }
+
+    /// Writes only if the key is still at `expected` (0 for a new key) and
+    /// returns the new version. A mismatch is `Status::Conflict`.
+    pub async fn star_cas(&self, bucket: star::Bucket, key: &[u8], expected: u64, value: Vec<u8>) -> Result<u64, BackendError> {
+        match self.star(star::Request::Cas { bucket, key: key.to_vec(), expected, value, ttl: None }).await? {
+            star::Response::Cas { version } => Ok(version),
+            _ => Err(BackendError::Status(self.name, Status::Malformed)),
+        }
+    }
+
+    /// Runs the ops atomically. A failed CHECK is not an error; look at
+    /// `committed` and the results.
+    pub async fn star_txn(&self, ops: Vec<star::TxnOp>) -> Result<star::TxnOutcome, BackendError> {
+        match self.star(star::Request::Txn(ops)).await? {
+            star::Response::Txn(outcome) => Ok(outcome),
+            _ => Err(BackendError::Status(self.name, Status::Malformed)),
+        }
+    }
+
+    /// Fetches many keys of one bucket, one entry per key in order. Batches
+    /// larger than star accepts, or whose values do not fit in one answer,
+    /// are split, so only each chunk is consistent.
+    pub async fn star_mget(&self, bucket: star::Bucket, keys: &[Vec<u8>]) -> Result<Vec<Option<(u64, Vec<u8>)>>, BackendError> {
+        let mut values = Vec::with_capacity(keys.len());
+        let mut chunks: Vec<&[Vec<u8>]> = keys.chunks(star::MAX_BATCH_KEYS).rev().collect();
+        while let Some(chunk) = chunks.pop() {
+            match self.star(star::Request::MGet { bucket, keys: chunk.to_vec() }).await {
+                Ok(star::Response::MGet(items)) => {
+                    values.extend(items.into_iter().map(|item| item.map(|item| (item.version, item.val))));
+                },
+                Ok(_) => return Err(BackendError::Status(self.name, Status::Malformed)),
+                // Star caps single values so one key always fits.
+                Err(BackendError::Status(_, Status::TooLarge)) if chunk.len() > 1 => {
+                    let (head, tail) = chunk.split_at(chunk.len() / 2);
+                    chunks.push(tail);
+                    chunks.push(head);
+                },
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(values)
+    }
+
+    /// Fetches one SCAN page; pass the returned cursor back in `scan` for the next one.
+    pub async fn star_scan(&self, bucket: star::Bucket, scan: star::Scan) -> Result<star::ScanPage, BackendError> {
+        match self.star(star::Request::Scan { bucket, scan }).await? {
+            star::Response::Scan(page) => Ok(page),
+            _ => Err(BackendError::Status(self.name, Status::Malformed)),
+        }
+    }
+
+    /// Opens a dedicated connection and subscribes to changes of `key`, or
+    /// of every key starting with it unless `exact`. An empty prefix covers
+    /// the whole bucket. The connection is never returned to the pool, and
+    /// takes one of `PoolConfig::stream_size` slots while it lives; with
+    /// none left this fails right away with `Status::Busy`.
+    pub async fn subscribe(self: &Arc<Self>, bucket: star::Bucket, key: &[u8], exact: bool) -> Result<Subscription, BackendError> {
+        let permit = Arc::clone(&self.streams)
+            .try_acquire_owned()
+            .map_err(|_| BackendError::Status(self.name, Status::Busy))?;
+        let mut conn = self.connect().await?;
+        let request_id = self.next_request_id.fetch_add(1, Ordering::Relaxed);
+        let req = star::Request::Subscribe { bucket, key: key.to_vec(), exact };
+        let payload = req.encode().map_err(|status| BackendError::Status(self.name, status))?;
+        let frame = self.encode(request_id, payload)?;
+        conn.stream.write_all(&frame).await.map_err(|err| BackendError::Io(self.name, err))?;
+        let rsp = timeout(self.config.call_timeout, self.read_reply(&mut conn, request_id))
+            .await
+            .map_err(|_| BackendError::Timeout(self.name))??;
+        match star::Response::decode(&rsp) {
+            Ok(star::Response::Subscribed) => Ok(Subscription { backend: Arc::clone(self), conn, request_id, _permit: permit }),
+            Ok(star::Response::Error { status, .. }) | Err(status) => Err(BackendError::Status(self.name, status)),
+            Ok(_) => Err(BackendError::Status(self.name, Status::Malformed)),
+        }
+    }
+
+    /// Runs `body` in a store transaction, on a connection that stays
+    /// pinned to it, and holds a pool permit, until the end. The
+    /// transaction commits when `body` returns `Ok` and rolls back when it
+    /// returns `Err`; if the future is dropped halfway, the connection
+    /// closes and store rolls back itself.
+    pub async fn store_transaction<T, E, F>(&self, body: F) -> Result<T, E>
+    where
+        F: for<'t> FnOnce(&'t mut StoreTransaction<'_>) -> BoxFuture<'t, Result<T, E>>,
+        E: From<BackendError>,
+    {
+        // The semaphore is never closed, so acquiring can only fail if it were.
+        let _permit = self.permits.acquire().await.expect("pool semaphore closed");
+        let (conn, pooled) = self.checkout().await?;
+        let mut tx = StoreTransaction { backend: self, conn, pooled };
+        tx.request(store::Request::Begin).await?;
+        match body(&mut tx).await {
+            Ok(value) => {
+                tx.request(store::Request::Commit).await?;
+                tx.release();
+                Ok(value)
+            },
+            Err(err) => {
+                match tx.request(store::Request::Rollback).await {
+                    Ok(_) => tx.release(),
+                    // Closing the connection rolls back all the same.
+                    Err(rollback) => tracing::warn!("{}: rollback failed: {}", self.name, rollback),
+                }
+                Err(err)
+            },
+        }
+    }
+
+    /// Sends one request on a connection the caller holds on to.
+    async fn roundtrip(&self, conn: &mut Connection, pooled: bool, payload: Vec<u8>) -> Result<Vec<u8>, BackendError> {
+        let request_id = self.next_request_id.fetch_add(1, Ordering::Relaxed);
+        let frame = self.encode(request_id, payload)?;
+        let rsp = self.deliver(conn, pooled, &frame, request_id, true).await?;
+        Ok(rsp.unwrap_or_default())
+    }
+
+    /// A request too large for a frame fails here as if the backend had
+    /// refused it, before it takes a connection.
+    fn encode(&self, request_id: u32, payload: Vec<u8>) -> Result<Vec<u8>, BackendError> {
+        Frame::new(request_id, payload)
+            .encode()
+            .map_err(|_| BackendError::Status(self.name, Status::TooLarge))
+    }
+
+    /// An idle connection if there is one, a new one otherwise; the flag
+    /// tells which.
+    async fn checkout(&self) -> Result<(Connection, bool), BackendError> {
+        match self.take_idle() {
+            Some(conn) => Ok((conn, true)),
+            None => Ok((self.connect().await?, false)),
+        }
+    }
+
+    /// Writes `frame` and, if `expect_reply`, reads the answer to
+    /// `request_id`. A pooled connection can be closed by the daemon, idle
+    /// past its read timeout say, between the liveness probe and the
+    /// write. That shows as a failed write or as EOF before any byte of
+    /// the answer, and is retried once on a fresh connection. Only a fresh
+    /// connection failing marks the backend down.
+    async fn deliver(
+        &self,
+        conn: &mut Connection,
+        pooled: bool,
+        frame: &[u8],
+        request_id: u32,
+        expect_reply: bool,
+    ) -> Result<Option<Vec<u8>>, BackendError> {
+        if pooled {
+            match self.attempt(conn, frame, request_id, expect_reply).await {
+                Err(Attempt::Closed(err)) => {
+                    tracing::debug!("{}: stale pooled connection ({}), reconnecting", self.name, err);
+                    *conn = self.connect().await?;
+                },
+                Err(Attempt::Failed(err)) => return Err(err),
+                Ok(rsp) => return Ok(rsp),
+            }
+        }
+        match self.attempt(conn, frame, request_id, expect_reply).await {
+            Ok(rsp) => Ok(rsp),
+            Err(Attempt::Closed(err)) => {
+                self.mark_down(&err);
+                Err(BackendError::Io(self.name, err))
+            },
+            Err(Attempt::Failed(err)) => Err(err),
+        }
+    }
+
+    async fn attempt(
+        &self,
+        conn: &mut Connection,
+        frame: &[u8],
+        request_id: u32,
+        expect_reply: bool,
+    ) -> Result<Option<Vec<u8>>, Attempt> {
+        conn.stream.write_all(frame).await.map_err(Attempt::Closed)?;
+        if !expect_reply {
+            return Ok(None);
+        }
+        self.read_frame(conn, request_id).await.map(Some)
+    }
+
+    async fn exchange(&self, payload: Vec<u8>, expect_reply: bool) -> Result<Option<Vec<u8>>, BackendError> {
+        let request_id = self.next_request_id.fetch_add(1, Ordering::Relaxed);
+        let frame = self.encode(request_id, payload)?;
+        // The semaphore is never closed, so acquiring can only fail if it were.
+        let _permit = self.permits.acquire().await.expect("pool semaphore closed");
+        let (mut conn, pooled) = self.checkout().await?;
+        let rsp = self.deliver(&mut conn, pooled, &frame, request_id, expect_reply).await?;
+        conn.last_used = Instant::now();
+        self.idle.lock().unwrap().push(conn);
+        Ok(rsp)
+    }
+
+    /// Reads on a connection the backend was known to be up on, so EOF
+    /// marks it down.
+    async fn read_reply(&self, conn: &mut Connection, request_id: u32) -> Result<Vec<u8>, BackendError> {
+        match self.read_frame(conn, request_id).await {
+            Ok(payload) => Ok(payload),
+            Err(Attempt::Closed(err)) => {
+                self.mark_down(&err);
+                Err(BackendError::Io(self.name, err))
+            },
+            Err(Attempt::Failed(err)) => Err(err),
+        }
+    }
+
+    async fn read_frame(&self, conn: &mut Connection, request_id: u32) -> Result<Vec<u8>, Attempt> {
+        let mut buf = vec![0; self.config.buffer_size];
+        let mut received = false;
+        loop {
+            match conn.decoder.next_frame() {
+                Ok(Some(frame)) if frame.request_id == request_id => return Ok(frame.payload),
+                // The daemon is at its connection limit and closes this one.
+                Ok(Some(frame)) if daemon::is_refusal(&frame.payload) => {
+                    return Err(Attempt::Failed(BackendError::Status(self.name, Status::Busy)));
+                },
+                Ok(Some(frame)) => {
+                    tracing::warn!("{}: dropping stale response {}", self.name, frame.request_id);
+                    continue;
+                },
+                Ok(None) => {},
+                Err(err) => return Err(Attempt::Failed(BackendError::Protocol(self.name, err))),
+            }
+            let count = conn.stream
+                .read(&mut buf)
+                .await
+                .map_err(|err| Attempt::Failed(BackendError::Io(self.name, err)))?;
+            if count == 0 {
+                let err = io::Error::from(io::ErrorKind::UnexpectedEof);
+                if received {
+                    return Err(Attempt::Failed(BackendError::Io(self.name, err)));
+                }
+                return Err(Attempt::Closed(err));
+            }
+            received = true;
+            conn.decoder.extend(&buf[..count]);
+        }
+    }
+
+    fn take_idle(&self) -> Option<Connection> {
+        let mut idle = self.idle.lock().unwrap();
+        while let Some(conn) = idle.pop() {
+            if conn.is_reusable(self.config.idle_timeout) {
+                return Some(conn);
+            }
+        }
+        None
+    }
+
+    /// Opens a new connection, retrying with exponential backoff.
+    async fn connect(&self) -> Result<Connection, BackendError> {
+        let mut delay = self.config.backoff_base;
+        let mut attempt = 0;
+        loop {
+            match UnixStream::connect(&self.path).await {
+                Ok(stream) => {
+                    self.mark_up();
+                    return Ok(Connection {
+                        stream,
+                        decoder: FrameDecoder::default(),
+                        last_used: Instant::now(),
+                    });
+                },
+                Err(err) if attempt >= self.config.connect_retries => {
+                    self.mark_down(&err);
+                    return Err(BackendError::Unavailable(self.name, err));
+                },
+                Err(err) => {
+                    tracing::warn!("{}: connect failed ({}), retrying in {:?}", self.name, err, delay);
+                    sleep(delay).await;
+                    delay = (delay * 2).min(self.config.backoff_max);
+                    attempt += 1;
+                },
+            }
+        }
+    }
+
+    /// Drops dead idle connections and keeps one warm connection around so
+    /// `is_healthy` reflects whether the backend is reachable right now.
+    pub async fn health_check(&self) {
+        let has_live = {
+            let mut idle = self.idle.lock().unwrap();
+            idle.retain(|conn| conn.is_reusable(self.config.idle_timeout));
+            !idle.is_empty()
+        };
+        if has_live {
+            self.mark_up();
+            return;
+        }
+        match UnixStream::connect(&self.path).await {
+            Ok(stream) => {
+                self.mark_up();
+                self.idle.lock().unwrap().push(Connection {
+                    stream,
+                    decoder: FrameDecoder::default(),
+                    last_used: Instant::now(),
+                });
+            },
+            Err(err) => self.mark_down(&err),
+        }
+    }
+
+    pub fn spawn_health_checks(self: &Arc<Self>) {
+        let backend = Arc::clone(self);
+        tokio::spawn(async move {
+            loop {
+                backend.health_check().await;
+                sleep(backend.config.health_interval).await;
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        process,
+        time::{SystemTime, UNIX_EPOCH},
+    };
+    use tokio::net::UnixListener;
+
+    fn listen(name: &str) -> (UnixListener, Backend) {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos();
+        let path = std::env::temp_dir().join(format!("satellite-{}-{}-{}.sock", name, process::id(), nanos));
+        let listener = UnixListener::bind(&path).unwrap();
+        let config = PoolConfig { connect_retries: 0, ..PoolConfig::default() };
+        (listener, Backend::new("test", path, config))
+    }
+
+    async fn read_request(stream: &mut UnixStream) -> Frame {
+        let mut decoder = FrameDecoder::default();
+        let mut buf = [0; 1024];
+        loop {
+            if let Some(frame) = decoder.next_frame().unwrap() {
+                return frame;
+            }
+            let count = stream.read(&mut buf).await.unwrap();
+            assert!(count > 0, "client hung up");
+            decoder.extend(&buf[..count]);
+        }
+    }
+
+    /// Answers with the request's own payload.
+    async fn echo(stream: &mut UnixStream) {
+        let frame = read_request(stream).await;
+        stream.write_all(&Frame::encode_response(frame.request_id, frame.payload)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn stale_pooled_connection_is_retried() {
+        let (listener, backend) = listen("stale");
+        let daemon = tokio::spawn(async move {
+            let (mut first, _) = listener.accept().await.unwrap();
+            echo(&mut first).await;
+            // Hangs up without answering, as a daemon whose read timeout
+            // fires just as the next request comes in.
+            read_request(&mut first).await;
+            drop(first);
+            let (mut second, _) = listener.accept().await.unwrap();
+            echo(&mut second).await;
+        });
+        assert_eq!(backend.call(vec![1]).await.unwrap(), [1]);
+        assert_eq!(backend.call(vec![2]).await.unwrap(), [2]);
+        assert!(backend.is_healthy());
+        daemon.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn fresh_connection_closing_marks_down() {
+        let (listener, backend) = listen("fresh");
+        let daemon = tokio::spawn(async move {
+            let (mut conn, _) = listener.accept().await.unwrap();
+            read_request(&mut conn).await;
+        });
+        assert!(matches!(backend.call(vec![1]).await, Err(BackendError::Io(..))));
+        assert!(!backend.is_healthy());
+        daemon.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn closing_mid_reply_is_not_retried() {
+        let (listener, backend) = listen("partial");
+        let daemon = tokio::spawn(async move {
+            let (mut conn, _) = listener.accept().await.unwrap();
+            echo(&mut conn).await;
+            let frame = read_request(&mut conn).await;
+            let reply = Frame::encode_response(frame.request_id, vec![0; 16]);
+            conn.write_all(&reply[..reply.len() / 2]).await.unwrap();
+            drop(conn);
+            listener
+        });
+        backend.call(vec![1]).await.unwrap();
+        assert!(matches!(backend.call(vec![2]).await, Err(BackendError::Io(..))));
+        let listener = daemon.await.unwrap();
+        assert!(timeout(Duration::from_millis(100), listener.accept()).await.is_err(), "request was resent");
+        assert!(backend.is_healthy());
+    }
+}
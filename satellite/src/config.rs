@@ -0,0 +1,118 @@
+// Satellite's `[satellite]` table of the sentinel config, see
+// `sentinel_proto::config` for where it is read from. The daemon sockets
+// come from their own tables, so one file describes the whole stack.
+
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
+use serde::{Deserialize, Serialize};
+use sentinel_proto::config::{require_positive, ConfigError, Settings};
+
+use crate::backend::PoolConfig;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+struct Section {
+    listen: String,
+    request_timeout_secs: u64,
+    concurrency_limit: usize,
+    pool_size: usize,
+    stream_pool_size: usize,
+    max_watchers: usize,
+    call_timeout_ms: u64,
+    idle_timeout_secs: u64,
+    health_interval_secs: u64,
+    connect_retries: u32,
+    backoff_base_ms: u64,
+    backoff_max_ms: u64,
+    buffer_size: usize,
+    shutdown_timeout_secs: u64,
+    key_file: String,
+    key_sync_secs: u64,
+}
+
+impl Default for Section {
+    fn default() -> Self {
+        let pool = PoolConfig::default();
+        Section {
+            listen: "0.0.0.0:3000".to_owned(),
+            request_timeout_secs: 10,
+            concurrency_limit: 1024,
+            pool_size: pool.size,
+            stream_pool_size: pool.stream_size,
+            max_watchers: 1024,
+            call_timeout_ms: pool.call_timeout.as_millis() as u64,
+            idle_timeout_secs: pool.idle_timeout.as_secs(),
+            health_interval_secs: pool.health_interval.as_secs(),
+            connect_retries: pool.connect_retries,
+            backoff_base_ms: pool.backoff_base.as_millis() as u64,
+            backoff_max_ms: pool.backoff_max.as_millis() as u64,
+            buffer_size: pool.buffer_size,
+            shutdown_timeout_secs: 10,
+            key_file: "satellite.key".to_owned(),
+            key_sync_secs: 30,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SatelliteConfig {
+    pub listen: SocketAddr,
+    pub request_timeout: Duration,
+    pub concurrency_limit: usize,
+    /// `/kvwatch` clients served at once, over at most
+    /// `PoolConfig::stream_size` star subscriptions.
+    pub max_watchers: usize,
+    /// How long in-flight requests get to finish on shutdown.
+    pub shutdown_timeout: Duration,
+    pub pool: PoolConfig,
+    pub star_socket: PathBuf,
+    pub sonar_socket: PathBuf,
+    pub store_socket: PathBuf,
+    /// Seals the private signing keys kept in star; instances sharing a
+    /// key ring need the same file.
+    pub key_file: PathBuf,
+    pub key_sync: Duration,
+}
+
+impl SatelliteConfig {
+    pub fn load() -> Result<SatelliteConfig, ConfigError> {
+        let settings = Settings::load()?;
+        let section: Section = settings.section("satellite")?;
+        let listen = section.listen.parse()
+            .map_err(|_| ConfigError::new(format!("[satellite] listen is not an address: {}", section.listen)))?;
+        require_positive("satellite", "request_timeout_secs", section.request_timeout_secs)?;
+        require_positive("satellite", "concurrency_limit", section.concurrency_limit as u64)?;
+        require_positive("satellite", "pool_size", section.pool_size as u64)?;
+        require_positive("satellite", "stream_pool_size", section.stream_pool_size as u64)?;
+        require_positive("satellite", "max_watchers", section.max_watchers as u64)?;
+        require_positive("satellite", "call_timeout_ms", section.call_timeout_ms)?;
+        require_positive("satellite", "health_interval_secs", section.health_interval_secs)?;
+        require_positive("satellite", "buffer_size", section.buffer_size as u64)?;
+        require_positive("satellite", "key_sync_secs", section.key_sync_secs)?;
+        if section.backoff_base_ms > section.backoff_max_ms {
+            return Err(ConfigError::new("[satellite] backoff_base_ms must not exceed backoff_max_ms"));
+        }
+        Ok(SatelliteConfig {
+            listen,
+            request_timeout: Duration::from_secs(section.request_timeout_secs),
+            concurrency_limit: section.concurrency_limit,
+            max_watchers: section.max_watchers,
+            shutdown_timeout: Duration::from_secs(section.shutdown_timeout_secs),
+            pool: PoolConfig {
+                size: section.pool_size,
+                stream_size: section.stream_pool_size,
+                call_timeout: Duration::from_millis(section.call_timeout_ms),
+                idle_timeout: Duration::from_secs(section.idle_timeout_secs),
+                health_interval: Duration::from_secs(section.health_interval_secs),
+                connect_retries: section.connect_retries,
+                backoff_base: Duration::from_millis(section.backoff_base_ms),
+                backoff_max: Duration::from_millis(section.backoff_max_ms),
+                buffer_size: section.buffer_size,
+            },
+            star_socket: settings.socket("star")?,
+            sonar_socket: settings.socket("sonar")?,
+            store_socket: settings.socket("store")?,
+            key_file: settings.path(&section.key_file),
+            key_sync: Duration::from_secs(section.key_sync_secs),
+        })
+    }
+}
@@ -0,0 +1,527 @@
+use std::{
+    fmt, fs,
+    io::{self, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, Instant, SystemTime},
+};
+use argon2::password_hash::rand_core::OsRng;
+use base64::{engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD}, Engine};
+use jsonwebtoken::{
+    decode, decode_header, encode,
+    errors::{Error as JwtError, ErrorKind},
+    jwk::{
+        AlgorithmParameters, CommonParameters, EllipticCurve, Jwk, JwkSet, OctetKeyPairParameters,
+        OctetKeyPairType, PublicKeyUse, RSAKeyParameters, RSAKeyType,
+    },
+    Algorithm, DecodingKey, EncodingKey, Header, Validation,
+};
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN},
+    rand::{SecureRandom, SystemRandom},
+    signature::{Ed25519KeyPair, KeyPair},
+};
+use rsa::{
+    pkcs1::{DecodeRsaPrivateKey, EncodeRsaPrivateKey},
+    traits::PublicKeyParts,
+    RsaPrivateKey,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sentinel_proto::{star, Status};
+
+use crate::{
+    auth::ACCESS_TTL,
+    backend::{Backend, BackendError},
+    keys::{hash_key, random_token},
+};
+
+/// The least time between two reloads for tokens signed by a key this
+/// instance does not know, so made-up key ids cannot flood star.
+const RELOAD_COOLDOWN: Duration = Duration::from_secs(5);
+
+/// How often `sync` retries when another instance wrote the ring first.
+const SYNC_ATTEMPTS: usize = 3;
+
+#[derive(Debug)]
+pub enum KeyRingError {
+    KeyFile(PathBuf, io::Error),
+    Backend(BackendError),
+    /// The ring in star is not one this version wrote.
+    Malformed(serde_json::Error),
+    Key(JwtError),
+}
+
+impl fmt::Display for KeyRingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyRingError::KeyFile(path, err) => write!(f, "key file {}: {}", path.display(), err),
+            KeyRingError::Backend(err) => write!(f, "{}", err),
+            KeyRingError::Malformed(err) => write!(f, "stored key ring is malformed: {}", err),
+            KeyRingError::Key(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<BackendError> for KeyRingError {
+    fn from(err: BackendError) -> Self {
+        KeyRingError::Backend(err)
+    }
+}
+
+impl From<JwtError> for KeyRingError {
+    fn from(err: JwtError) -> Self {
+        KeyRingError::Key(err)
+    }
+}
+
+/// A signing key as star keeps it. `private` is base64 of the nonce
+/// followed by the sealed private key DER, with the key id as associated
+/// data so a sealed key cannot be passed off under another id.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StoredKey {
+    kid: String,
+    alg: Algorithm,
+    created_at: u64,
+    private: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct StoredRing {
+    keys: Vec<StoredKey>,
+}
+
+fn ring_key() -> sentinel_proto::Key {
+    hash_key(b"jwt:keyring")
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// Seals private keys before they leave the process, with a 256-bit key
+/// read from the key file. Every instance sharing a ring needs the same
+/// file.
+struct Sealer {
+    key: LessSafeKey,
+    rng: SystemRandom,
+}
+
+impl Sealer {
+    /// Reads the hex-encoded key from `path`, creating the file readable
+    /// only by its owner if it does not exist yet.
+    fn load_or_create(path: &Path) -> Result<Self, KeyRingError> {
+        let rng = SystemRandom::new();
+        let fail = |err| KeyRingError::KeyFile(path.to_path_buf(), err);
+        let hex = match fs::read_to_string(path) {
+            Ok(hex) => hex,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                let hex = random_token(CHACHA20_POLY1305.key_len());
+                let created = fs::OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .mode(0o600)
+                    .open(path)
+                    .and_then(|mut file| file.write_all(hex.as_bytes()));
+                match created {
+                    Ok(()) => {
+                        tracing::info!("created key file {}", path.display());
+                        hex
+                    },
+                    // Another instance created it first.
+                    Err(err) if err.kind() == io::ErrorKind::AlreadyExists => fs::read_to_string(path).map_err(fail)?,
+                    Err(err) => return Err(fail(err)),
+                }
+            },
+            Err(err) => return Err(fail(err)),
+        };
+        let invalid = || fail(io::Error::new(io::ErrorKind::InvalidData, "expected 64 hex digits"));
+        let hex = hex.trim();
+        if hex.len() != 2 * CHACHA20_POLY1305.key_len() {
+            return Err(invalid());
+        }
+        let raw = (0..hex.len())
+            .step_by(2)
+            .map(|at| u8::from_str_radix(hex.get(at..at + 2)?, 16).ok())
+            .collect::<Option<Vec<u8>>>()
+            .ok_or_else(invalid)?;
+        let key = UnboundKey::new(&CHACHA20_POLY1305, &raw).map_err(|_| invalid())?;
+        Ok(Sealer { key: LessSafeKey::new(key), rng })
+    }
+
+    fn seal(&self, kid: &str, der: &[u8]) -> Result<String, KeyRingError> {
+        let sealing_failed = || KeyRingError::Key(ErrorKind::InvalidKeyFormat.into());
+        let mut nonce = [0; NONCE_LEN];
+        self.rng.fill(&mut nonce).map_err(|_| sealing_failed())?;
+        let mut sealed = der.to_vec();
+        self.key
+            .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(kid.as_bytes()), &mut sealed)
+            .map_err(|_| sealing_failed())?;
+        Ok(STANDARD.encode([&nonce[..], &sealed].concat()))
+    }
+
+    /// `None` if the key was sealed with another key file or tampered with.
+    fn open(&self, kid: &str, private: &str) -> Option<Vec<u8>> {
+        let raw = STANDARD.decode(private).ok()?;
+        if raw.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, sealed) = raw.split_at(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce).ok()?;
+        let mut sealed = sealed.to_vec();
+        let der = self.key.open_in_place(nonce, Aad::from(kid.as_bytes()), &mut sealed).ok()?;
+        Some(der.to_vec())
+    }
+}
+
+struct SigningKey {
+    kid: String,
+    alg: Algorithm,
+    created_at: u64,
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+    jwk: Jwk,
+    /// What star keeps of this key.
+    stored: StoredKey,
+}
+
+/// A fresh private key in DER: PKCS#8 for Ed25519, PKCS#1 for RSA.
+fn generate_der(alg: Algorithm) -> Result<Vec<u8>, JwtError> {
+    match alg {
+        Algorithm::EdDSA => Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+            .map(|pkcs8| pkcs8.as_ref().to_vec())
+            .map_err(|_| ErrorKind::InvalidEcdsaKey.into()),
+        Algorithm::RS256 => {
+            let private = RsaPrivateKey::new(&mut OsRng, 2048)
+                .map_err(|_| JwtError::from(ErrorKind::InvalidRsaKey("key generation failed")))?;
+            private.to_pkcs1_der()
+                .map(|der| der.as_bytes().to_vec())
+                .map_err(|_| ErrorKind::InvalidRsaKey("PKCS#1 encoding failed").into())
+        },
+        _ => Err(ErrorKind::InvalidAlgorithm.into()),
+    }
+}
+
+impl SigningKey {
+    fn from_der(stored: StoredKey, der: &[u8]) -> Result<Self, JwtError> {
+        let StoredKey { ref kid, alg, created_at, .. } = stored;
+        let common = CommonParameters {
+            public_key_use: Some(PublicKeyUse::Signature),
+            algorithm: Some(alg),
+            key_id: Some(kid.clone()),
+            ..Default::default()
+        };
+        let (encoding, decoding, algorithm) = match alg {
+            Algorithm::EdDSA => {
+                let pair = Ed25519KeyPair::from_pkcs8(der)
+                    .map_err(|_| JwtError::from(ErrorKind::InvalidEcdsaKey))?;
+                let public = pair.public_key().as_ref();
+                (
+                    EncodingKey::from_ed_der(der),
+                    DecodingKey::from_ed_der(public),
+                    AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+                        key_type: OctetKeyPairType::OctetKeyPair,
+                        curve: EllipticCurve::Ed25519,
+                        x: URL_SAFE_NO_PAD.encode(public),
+                    }),
+                )
+            },
+            Algorithm::RS256 => {
+                let private = RsaPrivateKey::from_pkcs1_der(der)
+                    .map_err(|_| JwtError::from(ErrorKind::InvalidRsaKey("PKCS#1 decoding failed")))?;
+                let n = URL_SAFE_NO_PAD.encode(private.n().to_bytes_be());
+                let e = URL_SAFE_NO_PAD.encode(private.e().to_bytes_be());
+                (
+                    EncodingKey::from_rsa_der(der),
+                    DecodingKey::from_rsa_components(&n, &e)?,
+                    AlgorithmParameters::RSA(RSAKeyParameters { key_type: RSAKeyType::RSA, n, e }),
+                )
+            },
+            _ => return Err(ErrorKind::InvalidAlgorithm.into()),
+        };
+        Ok(SigningKey {
+            kid: kid.clone(),
+            alg,
+            created_at,
+            encoding,
+            decoding,
+            jwk: Jwk { common, algorithm },
+            stored,
+        })
+    }
+}
+
+/// Asymmetric signing keys for access tokens. The newest key signs; older
+/// keys keep verifying until every token they signed has expired, and all of
+/// them are published through `/.well-known/jwks.json`.
+///
+/// The ring is kept in star's config bucket with the private keys sealed
+/// under the key file, so it survives restarts and every instance signs
+/// with and verifies the same keys. Instances merge their ring with star's
+/// every `sync_every`, which is also when a due rotation happens, and
+/// reload early when a token names a key they have not seen yet.
+pub struct KeyRing {
+    alg: Algorithm,
+    rotate_every: Duration,
+    sync_every: Duration,
+    sealer: Sealer,
+    star: Arc<Backend>,
+    /// Oldest first, the last key signs.
+    keys: RwLock<Vec<Arc<SigningKey>>>,
+    /// Serializes syncs and remembers when the last one ran.
+    synced: tokio::sync::Mutex<Option<Instant>>,
+}
+
+impl KeyRing {
+    /// Reads `JWT_ALG` (`EdDSA` or `RS256`, default `EdDSA`) and
+    /// `JWT_ROTATE_SECS` (default one day) and opens the key file. The ring
+    /// stays empty until `load`.
+    pub fn new(key_file: &Path, sync_every: Duration, star: Arc<Backend>) -> Result<Self, KeyRingError> {
+        let alg = match std::env::var("JWT_ALG").as_deref() {
+            Ok("RS256") => Algorithm::RS256,
+            Ok("EdDSA") | Err(_) => Algorithm::EdDSA,
+            Ok(_) => return Err(JwtError::from(ErrorKind::InvalidAlgorithm).into()),
+        };
+        let rotate_every = std::env::var("JWT_ROTATE_SECS")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(Duration::from_secs(24 * 3600));
+        Ok(KeyRing {
+            alg,
+            rotate_every,
+            sync_every,
+            sealer: Sealer::load_or_create(key_file)?,
+            star,
+            keys: RwLock::new(Vec::new()),
+            synced: tokio::sync::Mutex::new(None),
+        })
+    }
+
+    /// Loads the ring from star, or starts one there. Without star the
+    /// instance signs with a key of its own, which is written to star once
+    /// it is back.
+    pub async fn load(&self) -> Result<(), KeyRingError> {
+        if let Err(err) = self.sync().await {
+            tracing::warn!("failed to load the key ring, signing with a local key: {}", err);
+            if self.keys.read().unwrap().is_empty() {
+                let key = self.generate().await?;
+                self.keys.write().unwrap().push(Arc::new(key));
+            }
+        }
+        Ok(())
+    }
+
+    async fn generate(&self) -> Result<SigningKey, KeyRingError> {
+        let alg = self.alg;
+        let der = match tokio::task::spawn_blocking(move || generate_der(alg)).await {
+            Ok(der) => der?,
+            Err(err) => {
+                tracing::error!("signing key generation panicked: {}", err);
+                return Err(JwtError::from(ErrorKind::InvalidKeyFormat).into());
+            },
+        };
+        let kid = random_token(8);
+        let private = self.sealer.seal(&kid, &der)?;
+        Ok(SigningKey::from_der(StoredKey { kid, alg, created_at: now(), private }, &der)?)
+    }
+
+    async fn sync(&self) -> Result<(), KeyRingError> {
+        let mut synced = self.synced.lock().await;
+        let result = self.sync_locked().await;
+        *synced = Some(Instant::now());
+        result
+    }
+
+    /// Merges star's ring into this one, drops keys whose tokens can no
+    /// longer be valid, rotates if the signing key is due and writes the
+    /// result back unless star already has it.
+    async fn sync_locked(&self) -> Result<(), KeyRingError> {
+        let key = ring_key();
+        for _ in 0..SYNC_ATTEMPTS {
+            let (version, stored) = match self.star.star_get_versioned(star::Bucket::Config, &key).await? {
+                Some((version, raw)) => (version, serde_json::from_slice::<StoredRing>(&raw).map_err(KeyRingError::Malformed)?),
+                None => (0, StoredRing::default()),
+            };
+            let mut keys = self.keys.read().unwrap().clone();
+            for stored_key in stored.keys.iter() {
+                if keys.iter().any(|key| key.kid == stored_key.kid) {
+                    continue;
+                }
+                let Some(der) = self.sealer.open(&stored_key.kid, &stored_key.private) else {
+                    tracing::error!("cannot open signing key {}, is the key file the same on every instance?", stored_key.kid);
+                    continue;
+                };
+                keys.push(Arc::new(SigningKey::from_der(stored_key.clone(), &der)?));
+            }
+            keys.sort_by(|a, b| (a.created_at, &a.kid).cmp(&(b.created_at, &b.kid)));
+            // A key is retired once a newer one exists, and its tokens are
+            // valid for at most ACCESS_TTL after that.
+            let now = now();
+            let expired = keys
+                .windows(2)
+                .take_while(|pair| pair[1].created_at + ACCESS_TTL + 60 <= now)
+                .count();
+            keys.drain(..expired);
+            if keys.last().is_none_or(|key| key.created_at + self.rotate_every.as_secs() <= now) {
+                keys.push(Arc::new(self.generate().await?));
+            }
+            if keys.iter().map(|key| &key.kid).eq(stored.keys.iter().map(|key| &key.kid)) {
+                *self.keys.write().unwrap() = keys;
+                return Ok(());
+            }
+            let ring = StoredRing { keys: keys.iter().map(|key| key.stored.clone()).collect() };
+            // Keys are strings and numbers, which always serialize.
+            let raw = serde_json::to_vec(&ring).unwrap_or_default();
+            match self.star.star_cas(star::Bucket::Config, &key, version, raw).await {
+                // A key generated here only signs once star has it, so when
+                // two instances rotate at once the loser takes the winner's.
+                Err(BackendError::Status(_, Status::Conflict)) => continue,
+                result => {
+                    if let (Ok(_), Some(key)) = (&result, keys.last()) {
+                        if !stored.keys.iter().any(|stored_key| stored_key.kid == key.kid) {
+                            tracing::info!("rotated signing key to {}", key.kid);
+                        }
+                    }
+                    *self.keys.write().unwrap() = keys;
+                    result?;
+                    return Ok(());
+                },
+            }
+        }
+        Err(BackendError::Status(self.star.name(), Status::Conflict).into())
+    }
+
+    fn active(&self) -> Option<Arc<SigningKey>> {
+        self.keys.read().unwrap().last().cloned()
+    }
+
+    fn find(&self, kid: &str) -> Option<Arc<SigningKey>> {
+        self.keys.read().unwrap().iter().find(|key| key.kid == kid).cloned()
+    }
+
+    pub fn sign<T: Serialize>(&self, claims: &T) -> Result<String, JwtError> {
+        // `load` leaves at least one key in the ring before serving.
+        let key = self.active().ok_or(ErrorKind::InvalidKeyFormat)?;
+        let mut header = Header::new(key.alg);
+        header.typ = Some("JWT".to_string());
+        header.kid = Some(key.kid.clone());
+        encode(&header, claims, &key.encoding)
+    }
+
+    /// A token naming an unknown key may come from an instance that rotated
+    /// since the last sync, so the ring is reloaded once before rejecting.
+    pub async fn verify<T: DeserializeOwned>(&self, token: &str) -> Result<T, JwtError> {
+        let header = decode_header(token)?;
+        let kid = header.kid.ok_or(ErrorKind::InvalidToken)?;
+        let key = match self.find(&kid) {
+            Some(key) => key,
+            None => {
+                self.reload_for(&kid).await;
+                self.find(&kid).ok_or(ErrorKind::InvalidToken)?
+            },
+        };
+        decode::<T>(token, &key.decoding, &Validation::new(key.alg)).map(|data| data.claims)
+    }
+
+    async fn reload_for(&self, kid: &str) {
+        let mut synced = self.synced.lock().await;
+        // A sync that ran while this one waited may have brought the key.
+        if self.find(kid).is_some() || synced.is_some_and(|at| at.elapsed() < RELOAD_COOLDOWN) {
+            return;
+        }
+        if let Err(err) = self.sync_locked().await {
+            tracing::warn!("failed to reload the key ring: {}", err);
+        }
+        *synced = Some(Instant::now());
+    }
+
+    pub fn jwks(&self) -> JwkSet {
+        JwkSet { keys: self.keys.read().unwrap().iter().map(|key| key.jwk.clone()).collect() }
+    }
+
+    pub fn spawn_sync(self: &Arc<Self>) {
+        let ring = Arc::clone(self);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(ring.sync_every).await;
+                if let Err(err) = ring.sync().await {
+                    tracing::error!("failed to sync the key ring: {}", err);
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+    use crate::backend::PoolConfig;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Claims {
+        sub: String,
+        exp: u64,
+    }
+
+    fn claims() -> Claims {
+        Claims { sub: "root".to_string(), exp: now() + ACCESS_TTL }
+    }
+
+    /// A ring whose star is unreachable, so it signs with local keys.
+    async fn local_ring(name: &str) -> KeyRing {
+        let dir = std::env::temp_dir().join(format!("satellite-keyring-{}-{}-{}", name, process::id(), now()));
+        fs::create_dir_all(&dir).unwrap();
+        let config = PoolConfig { connect_retries: 0, ..PoolConfig::default() };
+        let star = Arc::new(Backend::new("star", dir.join("star.sock"), config));
+        let ring = KeyRing::new(&dir.join("satellite.key"), Duration::from_secs(30), star).unwrap();
+        ring.load().await.unwrap();
+        ring
+    }
+
+    #[tokio::test]
+    async fn rotated_keys_keep_verifying() {
+        let ring = local_ring("rotate").await;
+        let old = ring.sign(&claims()).unwrap();
+        let rotated = ring.generate().await.unwrap();
+        let kid = rotated.kid.clone();
+        ring.keys.write().unwrap().push(Arc::new(rotated));
+
+        let new = ring.sign(&claims()).unwrap();
+        assert_eq!(decode_header(&new).unwrap().kid, Some(kid));
+        assert_ne!(decode_header(&old).unwrap().kid, decode_header(&new).unwrap().kid);
+        assert_eq!(ring.verify::<Claims>(&old).await.unwrap().sub, "root");
+        assert_eq!(ring.verify::<Claims>(&new).await.unwrap().sub, "root");
+        assert_eq!(ring.jwks().keys.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn unknown_kid_is_rejected() {
+        let ring = local_ring("known").await;
+        let other = local_ring("unknown").await;
+        let token = other.sign(&claims()).unwrap();
+        let err = ring.verify::<Claims>(&token).await.unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::InvalidToken);
+
+        // Same kid, different key: the signature does not check out.
+        let stolen = ring.active().unwrap().kid.clone();
+        let mut header = Header::new(Algorithm::EdDSA);
+        header.kid = Some(stolen);
+        let forged = encode(&header, &claims(), &other.active().unwrap().encoding).unwrap();
+        let err = ring.verify::<Claims>(&forged).await.unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::InvalidSignature);
+    }
+
+    #[test]
+    fn sealed_keys_only_open_with_their_kid() {
+        let dir = std::env::temp_dir().join(format!("satellite-sealer-{}-{}", process::id(), now()));
+        fs::create_dir_all(&dir).unwrap();
+        let sealer = Sealer::load_or_create(&dir.join("satellite.key")).unwrap();
+        let sealed = sealer.seal("a", b"private").unwrap();
+        assert_eq!(sealer.open("a", &sealed).as_deref(), Some(&b"private"[..]));
+        assert_eq!(sealer.open("b", &sealed), None);
+        let reopened = Sealer::load_or_create(&dir.join("satellite.key")).unwrap();
+        assert_eq!(reopened.open("a", &sealed).as_deref(), Some(&b"private"[..]));
+    }
+}
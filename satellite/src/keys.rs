@@ -0,0 +1,19 @@
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha256};
+use sentinel_proto::{Key, KEY_LEN};
+
+/// Star keys are 16 bytes, so free-form identifiers are stored under a
+/// truncated SHA-256.
+pub fn hash_key(data: &[u8]) -> Key {
+    let digest = Sha256::digest(data);
+    let mut key = [0; KEY_LEN];
+    key.copy_from_slice(&digest[..KEY_LEN]);
+    key
+}
+
+/// Hex-encoded random token with `bytes` bytes of entropy.
+pub fn random_token(bytes: usize) -> String {
+    let mut raw = vec![0; bytes];
+    OsRng.fill_bytes(&mut raw);
+    raw.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,134 @@
+use std::convert::Infallible;
+use axum::{
+    body::Bytes,
+    extract::{Extension, Path, Query},
+    http::StatusCode,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    Json,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use futures_util::stream::{self, Stream};
+use serde::Deserialize;
+use serde_json::json;
+use sentinel_proto::star;
+
+use crate::{
+    authz::{KvRead, KvWrite, Require},
+    backend::BackendError,
+    SharedState,
+};
+
+#[derive(Debug, Deserialize)]
+pub struct ListParams {
+    prefix: Option<String>,
+    /// `cursor` of the previous page.
+    cursor: Option<String>,
+    limit: Option<u16>,
+}
+
+/// Lists one page of kv keys, at most `limit` of them, as
+/// `{"keys": [...], "cursor": ...}`. Pass a non-null `cursor` back to get
+/// the next page.
+pub async fn list_keys(
+    _: Require<KvRead>,
+    Query(params): Query<ListParams>,
+    Extension(state): Extension<SharedState>,
+) -> Result<Response, BackendError> {
+    let end = match params.prefix {
+        Some(prefix) => star::ScanEnd::Prefix(prefix.into_bytes()),
+        None => star::ScanEnd::Unbounded,
+    };
+    let cursor = match params.cursor.map(|cursor| URL_SAFE_NO_PAD.decode(cursor)) {
+        Some(Ok(cursor)) => Some(cursor),
+        Some(Err(_)) => return Ok((StatusCode::BAD_REQUEST, "Invalid cursor").into_response()),
+        None => None,
+    };
+    let limit = params.limit.unwrap_or(star::MAX_SCAN_LIMIT);
+    let scan = star::Scan { end, cursor, limit, ..star::Scan::default() };
+    let page = state.star.star_scan(star::Bucket::Kv, scan).await?;
+    let keys: Vec<String> = page.items.iter().map(|item| String::from_utf8_lossy(&item.key).into_owned()).collect();
+    let cursor = page.cursor.map(|cursor| URL_SAFE_NO_PAD.encode(cursor));
+    Ok(Json(json!({ "keys": keys, "cursor": cursor })).into_response())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WatchParams {
+    /// Watch a single key.
+    key: Option<String>,
+    /// Watch every key with this prefix; neither `key` nor `prefix` watches
+    /// the whole bucket.
+    prefix: Option<String>,
+}
+
+/// Pushes changes to kv keys as server-sent events, one JSON object per
+/// change: `{"key": ..., "op": "set" | "del", "version": ...}`.
+pub async fn watch(
+    _: Require<KvRead>,
+    Query(params): Query<WatchParams>,
+    Extension(state): Extension<SharedState>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, BackendError> {
+    let (key, exact) = match (params.key, params.prefix) {
+        (Some(key), _) => (key, true),
+        (None, prefix) => (prefix.unwrap_or_default(), false),
+    };
+    let watcher = state.kv_watch.watch(key.as_bytes(), exact).await?;
+
+    let events = stream::unfold(watcher, |mut watcher| async move {
+        let change = watcher.next().await?;
+        let data = json!({
+            "key": String::from_utf8_lossy(&change.key),
+            "op": change.op,
+            "version": change.version,
+        });
+        Some((Ok(Event::default().data(data.to_string())), watcher))
+    });
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+pub async fn set(
+    _: Require<KvWrite>,
+    Path(key): Path<String>,
+    Extension(state): Extension<SharedState>,
+    bytes: Bytes,
+) -> Result<impl IntoResponse, BackendError> {
+    state.star.star_set(star::Bucket::Kv, key.as_bytes(), bytes.to_vec()).await?;
+
+    Ok((
+        StatusCode::NO_CONTENT,
+        "Ok",
+    ))
+}
+
+pub async fn delete(
+    _: Require<KvWrite>,
+    Path(key): Path<String>,
+    Extension(state): Extension<SharedState>,
+) -> Result<impl IntoResponse, BackendError> {
+    state.star.star_del(star::Bucket::Kv, key.as_bytes()).await?;
+
+    Ok((
+        StatusCode::NO_CONTENT,
+        "Ok",
+    ))
+}
+
+pub async fn get(
+    _: Require<KvRead>,
+    Path(key): Path<String>,
+    Extension(state): Extension<SharedState>,
+) -> Result<impl IntoResponse, BackendError> {
+    if let Some(value) = state.star.star_get(star::Bucket::Kv, key.as_bytes()).await? {
+        let content = String::from_utf8_lossy(&value).into_owned();
+        return Ok((
+            StatusCode::OK,
+            Json(content),
+        ));
+    }
+    Ok((
+        StatusCode::NOT_FOUND,
+        Json("Nothing to see here.".to_owned()),
+    ))
+}
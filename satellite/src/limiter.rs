@@ -0,0 +1,133 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+struct Window {
+    failures: u32,
+    started: Instant,
+}
+
+/// Counts failed logins per user and per IP in fixed windows.
+pub struct LoginLimiter {
+    windows: Mutex<HashMap<String, Window>>,
+    max_per_user: u32,
+    max_per_ip: u32,
+    window: Duration,
+}
+
+impl Default for LoginLimiter {
+    fn default() -> Self {
+        LoginLimiter {
+            windows: Mutex::new(HashMap::new()),
+            max_per_user: 5,
+            max_per_ip: 20,
+            window: Duration::from_secs(15 * 60),
+        }
+    }
+}
+
+impl LoginLimiter {
+    /// Takes an attempt from both the user's and the IP's allowance, or
+    /// returns how long the caller has to wait if either is used up for
+    /// the current window. The attempt counts as a failure until `release`
+    /// or `record_success` gives it back, so concurrent guesses cannot all
+    /// pass before the first one fails.
+    pub fn check(&self, username: &str, ip: IpAddr) -> Result<(), Duration> {
+        let mut windows = self.windows.lock().unwrap();
+        let window = self.window;
+        windows.retain(|_, w| w.started.elapsed() < window);
+        let limits = [
+            (format!("user:{}", username), self.max_per_user),
+            (format!("ip:{}", ip), self.max_per_ip),
+        ];
+        for (key, max) in &limits {
+            if let Some(w) = windows.get(key) {
+                if w.failures >= *max {
+                    return Err(window.saturating_sub(w.started.elapsed()));
+                }
+            }
+        }
+        for (key, _) in limits {
+            let w = windows.entry(key).or_insert_with(|| Window { failures: 0, started: Instant::now() });
+            w.failures += 1;
+        }
+        Ok(())
+    }
+
+    /// Gives back an attempt that could not be decided, such as one that
+    /// failed on a backend error.
+    pub fn release(&self, username: &str, ip: IpAddr) {
+        let mut windows = self.windows.lock().unwrap();
+        for key in [format!("user:{}", username), format!("ip:{}", ip)] {
+            if let Some(w) = windows.get_mut(&key) {
+                w.failures = w.failures.saturating_sub(1);
+            }
+        }
+    }
+
+    /// Gives back the IP's attempt and clears the user's failures.
+    pub fn record_success(&self, username: &str, ip: IpAddr) {
+        self.release(username, ip);
+        self.windows.lock().unwrap().remove(&format!("user:{}", username));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{sync::Arc, thread};
+
+    const IP: IpAddr = IpAddr::V4(std::net::Ipv4Addr::LOCALHOST);
+
+    #[test]
+    fn concurrent_attempts_are_reserved() {
+        let limiter = Arc::new(LoginLimiter::default());
+        let threads: Vec<_> = (0..16)
+            .map(|_| {
+                let limiter = Arc::clone(&limiter);
+                thread::spawn(move || limiter.check("root", IP).is_ok())
+            })
+            .collect();
+        let passed = threads.into_iter().map(|thread| thread.join().unwrap()).filter(|passed| *passed).count();
+        assert_eq!(passed, 5);
+        assert!(limiter.check("root", IP).is_err());
+        assert!(limiter.check("other", IP).is_ok());
+    }
+
+    #[test]
+    fn released_attempts_are_given_back() {
+        let limiter = LoginLimiter::default();
+        for _ in 0..5 {
+            limiter.check("root", IP).unwrap();
+        }
+        limiter.release("root", IP);
+        limiter.check("root", IP).unwrap();
+        assert!(limiter.check("root", IP).is_err());
+    }
+
+    #[test]
+    fn success_clears_the_user() {
+        let limiter = LoginLimiter::default();
+        for _ in 0..5 {
+            limiter.check("root", IP).unwrap();
+        }
+        limiter.record_success("root", IP);
+        for _ in 0..5 {
+            limiter.check("root", IP).unwrap();
+        }
+        assert!(limiter.check("root", IP).is_err());
+    }
+
+    #[test]
+    fn ip_limit_spans_users() {
+        let limiter = LoginLimiter::default();
+        for user in 0..20 {
+            limiter.check(&format!("user{}", user), IP).unwrap();
+        }
+        let wait = limiter.check("fresh", IP).unwrap_err();
+        assert!(wait > Duration::ZERO && wait <= Duration::from_secs(15 * 60));
+    }
+}
@@ -1,189 +1,99 @@
 use std::{
     borrow::Cow,
     collections::HashMap,
-    sync::{Arc, RwLock},
-    time::{Duration, Instant},
-    str::from_utf8,
-    fmt::Display,
-    time::SystemTime,
-    os::unix::net::UnixStream, io::{Write, Read},
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
 };
 use axum::{
-    async_trait,
-    body::Bytes,
     error_handling::HandleErrorLayer,
-    extract::{RequestParts, Path, Extension, FromRequest, TypedHeader},
-    headers::{authorization::{Bearer, Basic}, Authorization},
+    extract::Extension,
     handler::Handler,
-    http::{HeaderValue, Method, StatusCode, header::WWW_AUTHENTICATE, HeaderMap},
-    response::{IntoResponse, Response},
-    routing::get,
+    http::{HeaderValue, Method, StatusCode},
+    response::IntoResponse,
+    routing::{get, post, put},
     Router, Json,
 };
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::Notify,
+    time::sleep,
+};
 use tower_http::{
     cors::CorsLayer,
     trace::TraceLayer,
     compression::CompressionLayer,
 };
-use once_cell::sync::Lazy;
-use serde::{Deserialize, Serialize};
 // use serde_json::json;
+use serde::Serialize;
 use tower::{BoxError, ServiceBuilder};
-use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Algorithm, Validation};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
-
-struct SocketConnector {
-    path: String,
-    last_used: Instant,
-    last_stream: UnixStream,
-}
-
-impl SocketConnector {
-    fn new(path: String) -> Self {
-        let stream = UnixStream::connect(&path).unwrap();
-        SocketConnector {
-            path,
-            last_used: Instant::now(),
-            last_stream: stream
-        }
-    }
-    fn write_n_read(&mut self, data: Vec<u8>) -> Result<Vec<u8>, u8> {
-        if self.last_used.elapsed() > Duration::from_secs(20) {
-            let stream = UnixStream::connect(self.path.clone()).unwrap();
-            self.last_stream = stream;
-        }
-        self.last_stream.write_all(data.as_slice()).unwrap();
-        self.last_used = Instant::now();
-        self.last_stream.flush().unwrap();
-        let mut buf = [0; 1048594];
-        let count = self.last_stream.read(&mut buf).unwrap();
-        return Ok(buf[..count].to_vec());
-    }
-}
+use sentinel_proto::star;
+use backend::Backend;
+use config::SatelliteConfig;
+use keyring::KeyRing;
+use limiter::LoginLimiter;
+use watch::WatchHub;
+
+mod audit;
+mod auth;
+mod authz;
+mod backend;
+mod config;
+mod keyring;
+mod keys;
+mod kv;
+mod limiter;
+mod session;
+mod store;
+mod users;
+mod watch;
 
 struct AppState {
-    star: RwLock<SocketConnector>,
-    sonar: RwLock<SocketConnector>,
-    store: RwLock<SocketConnector>,
-    session: RwLock<HashMap<String, Bytes>>
+    star: Arc<Backend>,
+    sonar: Arc<Backend>,
+    store: Arc<Backend>,
+    limiter: LoginLimiter,
+    keys: Arc<KeyRing>,
+    kv_watch: WatchHub,
 }
 
 impl AppState {
-    fn new() -> AppState {
+    fn new(config: &SatelliteConfig) -> AppState {
+        let pool = &config.pool;
+        let star = Arc::new(Backend::new("star", config.star_socket.clone(), pool.clone()));
+        let keys = KeyRing::new(&config.key_file, config.key_sync, Arc::clone(&star))
+            .unwrap_or_else(|err| panic!("failed to open the JWT key ring: {}", err));
         AppState {
-            star: RwLock::new(SocketConnector::new("/tmp/sentinel/star.sock".to_owned())),
-            sonar: RwLock::new(SocketConnector::new("/tmp/sentinel/sonar.sock".to_owned())), 
-            store: RwLock::new(SocketConnector::new("/tmp/sentinel/store.sock".to_owned())),
-            session: RwLock::new(HashMap::<String, Bytes>::new())
-        }
-    }
-}
-
-type SharedState = Arc<AppState>;
-
-static KEYS: Lazy<Keys> = Lazy::new(|| {
-    let secret = std::env::var("JWT_SECRET").expect("JWT_SECRET must be set");
-    Keys::new(secret.as_bytes())
-});
-
-impl Display for Claims {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Email: {}\nCompany: {}", self.sub, self.aud)
-    }
-}
-
-impl AuthBody {
-    fn new(access_token: String) -> Self {
-        Self {
-            access_token,
-            token_type: "Bearer".to_string(),
+            kv_watch: WatchHub::new(Arc::clone(&star), star::Bucket::Kv, config.max_watchers),
+            star,
+            sonar: Arc::new(Backend::new("sonar", config.sonar_socket.clone(), pool.clone())),
+            store: Arc::new(Backend::new("store", config.store_socket.clone(), pool.clone())),
+            limiter: LoginLimiter::default(),
+            keys: Arc::new(keys),
         }
     }
-}
-
-#[async_trait]
-impl<S> FromRequest<S> for Claims
-where
-    S: Send + Sync,
-{
-    type Rejection = AuthError;
 
-    async fn from_request(req: &mut RequestParts<S>) -> Result<Self, Self::Rejection> {
-        let bearer_wrapper =
-            TypedHeader::<Authorization<Bearer>>::from_request(req)
-                .await
-                .map_err(|_| AuthError::InvalidToken);
-        if let Ok(TypedHeader(Authorization(bearer))) = bearer_wrapper {
-            let token_data = decode::<Claims>(bearer.token(), &KEYS.decoding, &Validation::default())
-                .map_err(|_| AuthError::InvalidToken)?;
-
-            Ok(token_data.claims)
-        } else {
-            let TypedHeader(Authorization(basic)) =
-                TypedHeader::<Authorization<Basic>>::from_request(req)
-                    .await
-                    .map_err(|_| AuthError::InvalidToken)?;
-            if basic.username() != "vimarrow" && basic.password() != "toor21" {
-                return Err(AuthError::WrongCredentials);
+    /// Checks every backend once before serving. Satellite starts without
+    /// the ones that are down and keeps retrying them in the background.
+    async fn check_backends(&self) {
+        for backend in [&self.star, &self.sonar, &self.store] {
+            backend.health_check().await;
+            if !backend.is_healthy() {
+                tracing::warn!("starting without {}, /health reports it until it is back", backend.name());
             }
-            let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
-            let claims = Claims {
-                sub: basic.username().to_owned(),
-                aud: "orbit1".to_owned(),
-                iss: "satellite".to_owned(),
-                iat: now,
-                exp: now + 7200,
-                nbf: now - 60,
-            };
-            Ok(claims)
         }
     }
-}
-
-impl IntoResponse for AuthError {
-    fn into_response(self) -> Response {
-        let mut headers = HeaderMap::new();
-        headers.insert(WWW_AUTHENTICATE, HeaderValue::from_str("Basic realm='orbit1'").unwrap());
-        (headers, StatusCode::UNAUTHORIZED).into_response()
-    }
-}
 
-struct Keys {
-    encoding: EncodingKey,
-    decoding: DecodingKey,
-}
-
-impl Keys {
-    fn new(secret: &[u8]) -> Self {
-        Self {
-            encoding: EncodingKey::from_secret(secret),
-            decoding: DecodingKey::from_secret(secret),
-        }
+    fn spawn_background_tasks(&self) {
+        self.keys.spawn_sync();
+        self.star.spawn_health_checks();
+        self.sonar.spawn_health_checks();
+        self.store.spawn_health_checks();
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct Claims {
-    sub: String,
-    aud: String,
-    iss: String,
-    exp: u64,
-    nbf: u64,
-    iat: u64,
-}
-
-#[derive(Debug, Serialize)]
-struct AuthBody {
-    access_token: String,
-    token_type: String,
-}
-
-#[derive(Debug)]
-enum AuthError {
-    WrongCredentials,
-    TokenCreation,
-    InvalidToken,
-}
+type SharedState = Arc<AppState>;
 
 #[tokio::main]
 async fn main() {
@@ -191,124 +101,116 @@ async fn main() {
     tracing_subscriber::registry()
         .with(
             tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "key_value_store=debug,tower_http=debug".into()),
+                .unwrap_or_else(|_| "satellite=debug,tower_http=debug".into()),
         )
         .with(tracing_subscriber::fmt::layer())
         .init();
 
+    let config = SatelliteConfig::load().unwrap_or_else(|err| panic!("invalid config: {}", err));
+
     let cors_layer = CorsLayer::new()
         .allow_origin("http://localhost:1234".parse::<HeaderValue>().unwrap())
-        .allow_methods([Method::GET, Method::POST, Method::DELETE]);
+        .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE]);
+
+    let state = Arc::new(AppState::new(&config));
+    state.check_backends().await;
+    state.keys.load().await.unwrap_or_else(|err| panic!("failed to create JWT signing keys: {}", err));
+    state.spawn_background_tasks();
+    users::bootstrap_admin(&state).await;
 
     let app = Router::new()
-        .route("/meow", get(meow))
-        .route("/session", get(session))
-        .route("/kvlist", get(kv_list_keys))
+        .route("/health", get(health))
+        .route("/.well-known/jwks.json", get(auth::jwks))
+        .route("/login", post(auth::login))
+        .route("/session/refresh", post(session::refresh))
+        .route("/session/logout", post(session::logout))
+        .route("/users", post(users::create_user))
+        .route("/users/:name/grants", put(authz::put_user_grants))
+        .route("/groups/:name", put(authz::put_group))
+        .route("/roles/:name", put(authz::put_role))
+        .route("/store/transaction", post(store::transaction))
+        .route("/kvlist", get(kv::list_keys))
+        .route("/kvwatch", get(kv::watch))
         .route(
             "/kv/:key",
-            get(kv_get.layer(CompressionLayer::new()))
-            .post(kv_set)
-            .delete(kv_delete),
+            get(kv::get.layer(CompressionLayer::new()))
+            .post(kv::set)
+            .delete(kv::delete),
         )
         .layer(cors_layer)
         .layer(
             ServiceBuilder::new()
                 .layer(HandleErrorLayer::new(handle_error))
                 .load_shed()
-                .concurrency_limit(1024)
-                .timeout(Duration::from_secs(10))
+                .concurrency_limit(config.concurrency_limit)
+                .timeout(config.request_timeout)
                 .layer(TraceLayer::new_for_http())
-                .layer(Extension(Arc::new(AppState::new())))
+                .layer(Extension(state))
                 .into_inner(),
             )
         .fallback(handler_404.into_service());
 
-    serve(app).await;
+    serve(app, config.listen, config.shutdown_timeout).await;
 
 }
 
-async fn meow(Extension(state): Extension<SharedState>) -> impl IntoResponse {
-    let mut socket = state.star.write().unwrap();
-
-    let rsp = socket.write_n_read(vec![1,1,1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,16,16,16]).unwrap();
-    println!("{:?}", rsp);
-
-    return (
-        StatusCode::OK,
-        "Ok",
-    );
-}
-
-async fn serve(app: Router) {
-    axum::Server::bind(&"0.0.0.0:3000".parse().unwrap())
-        .serve(app.into_make_service())
-        .await
-        .unwrap();
+#[derive(Serialize)]
+struct BackendHealth {
+    up: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    down_secs: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
 }
 
-async fn kv_list_keys(Extension(state): Extension<SharedState>) -> Json<String> {
-    let db = &state.session.read().unwrap();
-
-    let result = db.keys()
-        .map(|key| key.to_string())
-        .collect::<Vec<String>>();
-
-    Json(serde_json::to_string::<Vec<String>>(&result).unwrap())
-}
-
-async fn kv_set(Path(key): Path<String>, Extension(state): Extension<SharedState>, bytes: Bytes) -> impl IntoResponse {
-    state.session.write().unwrap().insert(key, bytes);
-
-    return (
-        StatusCode::NO_CONTENT,
-        "Ok",
-    );
-}
-
-async fn kv_delete(Path(key): Path<String>, Extension(state): Extension<SharedState>) -> impl IntoResponse {
-    state.session.write().unwrap().remove(&key);
-
-    return (
-        StatusCode::NO_CONTENT,
-        "Ok",
-    );
-}
-
-async fn kv_get(
-    Path(key): Path<String>,
-    Extension(state): Extension<SharedState>,
-) -> impl IntoResponse {
-    let db = &state.session.read().unwrap();
-
-    if let Some(value) = db.get(&key) {
-        let content = from_utf8(&value.clone()).unwrap().to_owned();
-        return (
-            StatusCode::OK,
-            Json(content),
-        );
+async fn health(Extension(state): Extension<SharedState>) -> impl IntoResponse {
+    let backends = [&state.star, &state.sonar, &state.store]
+        .iter()
+        .map(|b| {
+            let outage = b.outage();
+            let health = BackendHealth {
+                up: outage.is_none(),
+                down_secs: outage.as_ref().map(|outage| outage.since.elapsed().as_secs()),
+                error: outage.map(|outage| outage.error),
+            };
+            (b.name(), health)
+        })
+        .collect::<HashMap<&str, BackendHealth>>();
+    let status = if backends.values().all(|health| health.up) {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(backends))
+}
+
+/// Serves until SIGTERM or Ctrl+C, then stops accepting connections and
+/// gives running requests, open event streams included, `deadline` to end.
+async fn serve(app: Router, listen: SocketAddr, deadline: Duration) {
+    let stopping = Arc::new(Notify::new());
+    let signalled = Arc::clone(&stopping);
+    let server = axum::Server::bind(&listen)
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(async move {
+            shutdown_signal().await;
+            signalled.notify_one();
+        });
+    tokio::select! {
+        result = server => result.unwrap(),
+        _ = async {
+            stopping.notified().await;
+            sleep(deadline).await;
+        } => tracing::warn!("requests still running after {:?}, closing anyway", deadline),
     }
-    return (
-        StatusCode::NOT_FOUND,
-        Json("Nothing to see here.".to_owned()),
-    );
 }
 
-async fn session(claims: Claims) -> Result<Json<AuthBody>, AuthError> {
-    let jwt_head = jsonwebtoken::Header {
-        typ: Some("JWT".to_string()),
-        alg: Algorithm::HS256,
-        cty: None,
-        jku: None,
-        jwk: None,
-        kid: None,
-        x5u: None,
-        x5c: None,
-        x5t: None,
-        x5t_s256: None,
-    };
-    let token = encode(&jwt_head, &claims, &KEYS.encoding)
-        .map_err(|_| AuthError::TokenCreation)?;
-    Ok(Json(AuthBody::new(token)))
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("failed to install SIGTERM handler");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {},
+        _ = terminate.recv() => {},
+    }
+    tracing::info!("shutting down");
 }
 
 async fn handle_error(error: BoxError) -> impl IntoResponse {
@@ -0,0 +1,133 @@
+use std::{net::SocketAddr, time::SystemTime};
+use axum::{
+    extract::{ConnectInfo, Extension},
+    http::StatusCode,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use sentinel_proto::{star, Status};
+
+use crate::{
+    audit::{self, AuditEvent},
+    authz,
+    auth::{issue_token, AuthBody, AuthError, Claims, ACCESS_TTL},
+    backend::BackendError,
+    keys::{hash_key, random_token},
+    users,
+    AppState, SharedState,
+};
+
+pub const REFRESH_TTL: u32 = 14 * 24 * 3600;
+
+/// Stored in star's `session` bucket under the hash of the refresh token,
+/// so a leaked star dump does not leak usable tokens. Star expires the
+/// entry together with the token.
+#[derive(Debug, Serialize, Deserialize)]
+struct RefreshRecord {
+    username: String,
+    expires_at: u64,
+}
+
+/// Stored in star's `revoked` bucket under the hash of the access token's
+/// `jti`, until the token would have expired anyway.
+#[derive(Debug, Serialize, Deserialize)]
+struct Revocation {
+    exp: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RefreshRequest {
+    refresh_token: String,
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+pub async fn issue_refresh(state: &AppState, username: &str) -> Result<String, BackendError> {
+    let token = random_token(32);
+    let record = RefreshRecord { username: username.to_owned(), expires_at: now() + u64::from(REFRESH_TTL) };
+    // A struct of a string and an integer always serializes.
+    let value = serde_json::to_vec(&record).unwrap_or_default();
+    state.star.star_set_ex(star::Bucket::Session, &hash_key(token.as_bytes()), value, REFRESH_TTL).await?;
+    Ok(token)
+}
+
+/// Looks a refresh token up and deletes it in one TXN, so every token works
+/// once even when two refreshes race.
+async fn take_refresh(state: &AppState, token: &str) -> Result<Option<RefreshRecord>, BackendError> {
+    let key = hash_key(token.as_bytes()).to_vec();
+    let ops = vec![
+        star::TxnOp::Get { bucket: star::Bucket::Session, key: key.clone() },
+        star::TxnOp::Del { bucket: star::Bucket::Session, key },
+    ];
+    let outcome = state.star.star_txn(ops).await?;
+    let Some(star::TxnResult::Get { value: Some(raw), .. }) = outcome.results.into_iter().next() else {
+        return Ok(None);
+    };
+    let record: RefreshRecord = serde_json::from_slice(&raw)
+        .map_err(|_| BackendError::Status("star", Status::Malformed))?;
+    if record.expires_at <= now() {
+        return Ok(None);
+    }
+    Ok(Some(record))
+}
+
+/// Revokes the access token and, if given, deletes the refresh token in the
+/// same TXN, so a logout never ends half done.
+pub async fn revoke(state: &AppState, claims: &Claims, refresh_token: Option<&str>) -> Result<(), BackendError> {
+    let value = serde_json::to_vec(&Revocation { exp: claims.exp }).unwrap_or_default();
+    // Keep the entry a little past `exp` to cover clock skew between instances.
+    let ttl = claims.exp.saturating_sub(now()) + 60;
+    let ttl = u32::try_from(ttl).unwrap_or(u32::MAX);
+    let mut ops = vec![star::TxnOp::Set {
+        bucket: star::Bucket::Revoked,
+        key: hash_key(claims.jti.as_bytes()).to_vec(),
+        value,
+        ttl: Some(ttl),
+    }];
+    if let Some(token) = refresh_token {
+        ops.push(star::TxnOp::Del { bucket: star::Bucket::Session, key: hash_key(token.as_bytes()).to_vec() });
+    }
+    state.star.star_txn(ops).await.map(|_| ())
+}
+
+pub async fn is_revoked(state: &AppState, jti: &str) -> Result<bool, BackendError> {
+    Ok(state.star.star_get(star::Bucket::Revoked, &hash_key(jti.as_bytes())).await?.is_some())
+}
+
+pub async fn refresh(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(state): Extension<SharedState>,
+    Json(req): Json<RefreshRequest>,
+) -> Result<Json<AuthBody>, AuthError> {
+    let record = take_refresh(&state, &req.refresh_token).await.map_err(AuthError::Backend)?;
+    let Some(record) = record else {
+        audit::record(&state, AuditEvent::new("session.refresh", "", Some(addr.ip()), false)).await;
+        return Err(AuthError::InvalidToken);
+    };
+    // The account may have been removed since the refresh token was issued.
+    let Some(user) = users::load(&state, &record.username).await.map_err(AuthError::Backend)? else {
+        return Err(AuthError::InvalidToken);
+    };
+    audit::record(&state, AuditEvent::new("session.refresh", &record.username, Some(addr.ip()), true)).await;
+
+    // Grants are resolved again so role and group changes apply on refresh.
+    let grants = authz::resolve(&state, &user).await.map_err(AuthError::Backend)?;
+    let claims = Claims::for_user(&record.username, grants);
+    let access_token = issue_token(&state, &claims)?;
+    let refresh_token = issue_refresh(&state, &record.username).await.map_err(AuthError::Backend)?;
+    Ok(Json(AuthBody::new(access_token, ACCESS_TTL).with_refresh(refresh_token)))
+}
+
+pub async fn logout(
+    claims: Claims,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(state): Extension<SharedState>,
+    req: Option<Json<RefreshRequest>>,
+) -> Result<StatusCode, AuthError> {
+    let refresh_token = req.as_ref().map(|Json(req)| req.refresh_token.as_str());
+    revoke(&state, &claims, refresh_token).await.map_err(AuthError::Backend)?;
+    audit::record(&state, AuditEvent::new("session.logout", &claims.sub, Some(addr.ip()), true)).await;
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,158 @@
+use std::net::SocketAddr;
+use axum::{
+    extract::{ConnectInfo, Extension},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sentinel_proto::store::{is_valid_name, statement_id, ExecResult, SqlValue};
+
+use crate::{
+    auth::{AuthError, Claims},
+    authz::authorize,
+    backend::BackendError,
+    SharedState,
+};
+
+/// One registered statement to run, with its parameters in the order the
+/// statement declares its variables.
+#[derive(Debug, Deserialize)]
+pub struct Call {
+    name: String,
+    #[serde(default)]
+    params: Vec<Value>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TransactionBody {
+    statements: Vec<Call>,
+}
+
+#[derive(Debug)]
+pub enum StoreError {
+    Invalid(&'static str),
+    Auth(AuthError),
+    Backend(BackendError),
+}
+
+impl IntoResponse for StoreError {
+    fn into_response(self) -> Response {
+        match self {
+            StoreError::Invalid(reason) => (StatusCode::BAD_REQUEST, reason).into_response(),
+            StoreError::Auth(err) => err.into_response(),
+            StoreError::Backend(err) => err.into_response(),
+        }
+    }
+}
+
+impl From<BackendError> for StoreError {
+    fn from(err: BackendError) -> Self {
+        StoreError::Backend(err)
+    }
+}
+
+/// Booleans are bound as 0 and 1, like SQLite stores them.
+fn to_sql(value: Value) -> Option<SqlValue> {
+    match value {
+        Value::Null => Some(SqlValue::Null),
+        Value::Bool(flag) => Some(SqlValue::Integer(i64::from(flag))),
+        Value::Number(number) => match number.as_i64() {
+            Some(integer) => Some(SqlValue::Integer(integer)),
+            None => number.as_f64().map(SqlValue::Real),
+        },
+        Value::String(text) => Some(SqlValue::Text(text)),
+        Value::Array(_) | Value::Object(_) => None,
+    }
+}
+
+/// Blobs come out base64 encoded.
+fn from_sql(value: SqlValue) -> Value {
+    match value {
+        SqlValue::Null => Value::Null,
+        SqlValue::Integer(integer) => json!(integer),
+        SqlValue::Real(real) => json!(real),
+        SqlValue::Text(text) => Value::String(text),
+        SqlValue::Blob(blob) => Value::String(STANDARD.encode(blob)),
+    }
+}
+
+fn result_json(result: ExecResult) -> Value {
+    match result {
+        ExecResult::Changes(changes) => json!({ "changes": changes }),
+        ExecResult::Rows { columns, rows } => {
+            let rows: Vec<Vec<Value>> = rows
+                .into_iter()
+                .map(|row| row.into_iter().map(from_sql).collect())
+                .collect();
+            json!({ "columns": columns, "rows": rows })
+        },
+    }
+}
+
+/// Runs the statements in one store transaction and answers one result
+/// each, in order. Every statement needs `store:query:<name>`, and store
+/// runs it only if the caller holds the role it was registered for; if any
+/// of them fails, none of their changes are kept.
+pub async fn transaction(
+    claims: Claims,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(state): Extension<SharedState>,
+    Json(body): Json<TransactionBody>,
+) -> Result<Json<Vec<Value>>, StoreError> {
+    if body.statements.is_empty() {
+        return Err(StoreError::Invalid("No statements to run"));
+    }
+    let mut calls = Vec::with_capacity(body.statements.len());
+    for call in body.statements {
+        if !is_valid_name(&call.name) {
+            return Err(StoreError::Invalid("Invalid statement name"));
+        }
+        let needed = format!("store:query:{}", call.name);
+        authorize(&state, &claims, &needed, Some(addr.ip())).await.map_err(StoreError::Auth)?;
+        let params = call.params
+            .into_iter()
+            .map(to_sql)
+            .collect::<Option<Vec<_>>>()
+            .ok_or(StoreError::Invalid("Parameters must be null, booleans, numbers or strings"))?;
+        calls.push((statement_id(&call.name), params));
+    }
+    let roles = claims.roles;
+    let results = state.store.store_transaction(|tx| Box::pin(async move {
+        let mut results = Vec::with_capacity(calls.len());
+        for (id, params) in calls {
+            results.push(result_json(tx.exec(id, roles.clone(), params).await?));
+        }
+        Ok::<_, StoreError>(results)
+    })).await?;
+    Ok(Json(results))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_to_sql() {
+        assert_eq!(to_sql(Value::Null), Some(SqlValue::Null));
+        assert_eq!(to_sql(json!(true)), Some(SqlValue::Integer(1)));
+        assert_eq!(to_sql(json!(false)), Some(SqlValue::Integer(0)));
+        assert_eq!(to_sql(json!(-7)), Some(SqlValue::Integer(-7)));
+        assert_eq!(to_sql(json!(1.5)), Some(SqlValue::Real(1.5)));
+        assert_eq!(to_sql(json!(u64::MAX)), Some(SqlValue::Real(u64::MAX as f64)));
+        assert_eq!(to_sql(json!("text")), Some(SqlValue::Text("text".to_string())));
+        assert_eq!(to_sql(json!([1])), None);
+        assert_eq!(to_sql(json!({ "a": 1 })), None);
+    }
+
+    #[test]
+    fn sql_to_json() {
+        assert_eq!(from_sql(SqlValue::Null), Value::Null);
+        assert_eq!(from_sql(SqlValue::Integer(3)), json!(3));
+        assert_eq!(from_sql(SqlValue::Real(0.25)), json!(0.25));
+        assert_eq!(from_sql(SqlValue::Text("a".to_string())), json!("a"));
+        assert_eq!(from_sql(SqlValue::Blob(vec![0, 1, 255])), json!("AAH/"));
+    }
+}
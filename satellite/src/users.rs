@@ -0,0 +1,202 @@
+use std::{net::SocketAddr, time::SystemTime};
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use axum::{
+    extract::{ConnectInfo, Extension},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use sentinel_proto::{star, Key, Status};
+
+use crate::{
+    auth::AuthError,
+    authz::{authorize, AuthzWrite, Permission, Require, UsersWrite, ADMIN_ROLE},
+    backend::BackendError,
+    keys::hash_key,
+    AppState, SharedState,
+};
+
+/// A login account, stored as JSON in star's `user` bucket.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserRecord {
+    pub username: String,
+    /// Argon2id hash in PHC string format.
+    pub password_hash: String,
+    #[serde(default)]
+    pub roles: Vec<String>,
+    #[serde(default)]
+    pub groups: Vec<String>,
+    /// Only read from records written before roles existed.
+    #[serde(default, skip_serializing)]
+    admin: bool,
+    pub created_at: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewUser {
+    username: String,
+    password: String,
+    #[serde(default)]
+    roles: Vec<String>,
+    #[serde(default)]
+    groups: Vec<String>,
+}
+
+#[derive(Debug)]
+pub enum UserError {
+    Invalid(&'static str),
+    Exists,
+    Hash,
+    Auth(AuthError),
+    Backend(BackendError),
+}
+
+impl IntoResponse for UserError {
+    fn into_response(self) -> Response {
+        match self {
+            UserError::Invalid(reason) => (StatusCode::BAD_REQUEST, reason).into_response(),
+            UserError::Exists => (StatusCode::CONFLICT, "User already exists").into_response(),
+            UserError::Hash => (StatusCode::INTERNAL_SERVER_ERROR, "Failed to hash password").into_response(),
+            UserError::Auth(err) => err.into_response(),
+            UserError::Backend(err) => err.into_response(),
+        }
+    }
+}
+
+const UPDATE_RETRIES: u32 = 3;
+
+/// Argon2id hash of a password nobody uses, with the same parameters as
+/// `hash_password`. Logins for unknown users verify against it.
+pub const DUMMY_HASH: &str = "$argon2id$v=19$m=19456,t=2,p=1$0HI1iJdfUsPi7XGIg5RyAQ$Xt5zbVv22IKWs7iq5qHen7WiqotjKbE2BD1Z8aDSPNE";
+
+pub fn user_key(username: &str) -> Key {
+    hash_key(username.as_bytes())
+}
+
+/// Argon2 is deliberately slow, so hashing runs off the async workers.
+pub async fn hash_password(password: String) -> Result<String, UserError> {
+    tokio::task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+    })
+    .await
+    .map_err(|_| UserError::Hash)?
+    .map_err(|_| UserError::Hash)
+}
+
+pub async fn verify_password(password_hash: String, password: String) -> bool {
+    tokio::task::spawn_blocking(move || {
+        PasswordHash::new(&password_hash)
+            .map(|parsed| Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok())
+            .unwrap_or(false)
+    })
+    .await
+    .unwrap_or(false)
+}
+
+pub async fn load(state: &AppState, username: &str) -> Result<Option<UserRecord>, BackendError> {
+    Ok(load_versioned(state, username).await?.map(|(_, user)| user))
+}
+
+async fn load_versioned(state: &AppState, username: &str) -> Result<Option<(u64, UserRecord)>, BackendError> {
+    match state.star.star_get_versioned(star::Bucket::User, &user_key(username)).await? {
+        Some((version, raw)) => {
+            let mut user: UserRecord = serde_json::from_slice(&raw)
+                .map_err(|_| BackendError::Status("star", Status::Malformed))?;
+            if user.admin && !user.roles.iter().any(|role| role == ADMIN_ROLE) {
+                user.roles.push(ADMIN_ROLE.to_owned());
+            }
+            Ok(Some((version, user)))
+        },
+        None => Ok(None),
+    }
+}
+
+/// Writes the user only if its record is still at `expected`, 0 for a new user.
+async fn save(state: &AppState, user: &UserRecord, expected: u64) -> Result<(), BackendError> {
+    // A struct of strings, string lists and an integer always serializes.
+    let value = serde_json::to_vec(user).unwrap_or_default();
+    state.star.star_cas(star::Bucket::User, &user_key(&user.username), expected, value).await.map(|_| ())
+}
+
+/// Read-modify-write of a user record, retried when another writer got in
+/// between. Returns `false` if the user does not exist.
+pub async fn update<F>(state: &AppState, username: &str, apply: F) -> Result<bool, BackendError>
+where
+    F: Fn(&mut UserRecord),
+{
+    let mut attempts = 0;
+    loop {
+        let Some((version, mut user)) = load_versioned(state, username).await? else {
+            return Ok(false);
+        };
+        apply(&mut user);
+        match save(state, &user, version).await {
+            Err(BackendError::Status(_, Status::Conflict)) if attempts < UPDATE_RETRIES => attempts += 1,
+            result => return result.map(|_| true),
+        }
+    }
+}
+
+async fn insert(state: &AppState, new_user: NewUser) -> Result<(), UserError> {
+    if new_user.username.is_empty() || new_user.username.len() > 64 {
+        return Err(UserError::Invalid("Username must be 1 to 64 bytes"));
+    }
+    if new_user.password.len() < 8 {
+        return Err(UserError::Invalid("Password must be at least 8 bytes"));
+    }
+    if load(state, &new_user.username).await.map_err(UserError::Backend)?.is_some() {
+        return Err(UserError::Exists);
+    }
+    let user = UserRecord {
+        username: new_user.username,
+        password_hash: hash_password(new_user.password).await?,
+        roles: new_user.roles,
+        groups: new_user.groups,
+        admin: false,
+        created_at: SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs(),
+    };
+    // Two concurrent creates both pass the check above; only one CAS wins.
+    match save(state, &user, 0).await {
+        Err(BackendError::Status(_, Status::Conflict)) => Err(UserError::Exists),
+        result => result.map_err(UserError::Backend),
+    }
+}
+
+/// Creating a user needs `users:write`; giving it roles or groups is a
+/// grant like `PUT /authz/users/:username` and needs `authz:write` as well.
+pub async fn create_user(
+    require: Require<UsersWrite>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(state): Extension<SharedState>,
+    Json(new_user): Json<NewUser>,
+) -> Result<StatusCode, UserError> {
+    if !new_user.roles.is_empty() || !new_user.groups.is_empty() {
+        authorize(&state, &require.0, AuthzWrite::NAME, Some(addr.ip())).await.map_err(UserError::Auth)?;
+    }
+    insert(&state, new_user).await?;
+    Ok(StatusCode::CREATED)
+}
+
+/// Creates the first admin from `SENTINEL_ADMIN_USER` and
+/// `SENTINEL_ADMIN_PASSWORD` when both are set and the user does not exist yet.
+pub async fn bootstrap_admin(state: &AppState) {
+    let (Ok(username), Ok(password)) = (
+        std::env::var("SENTINEL_ADMIN_USER"),
+        std::env::var("SENTINEL_ADMIN_PASSWORD"),
+    ) else {
+        return;
+    };
+    let admin = NewUser { username, password, roles: vec![ADMIN_ROLE.to_owned()], groups: vec![] };
+    match insert(state, admin).await {
+        Ok(()) => tracing::info!("created bootstrap admin"),
+        Err(UserError::Exists) => {},
+        Err(err) => tracing::error!("failed to create bootstrap admin: {:?}", err),
+    }
+}
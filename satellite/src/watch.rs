@@ -0,0 +1,134 @@
+// Fans star subscriptions out to `/kvwatch` clients. Every watched key or
+// prefix has a single star subscription, shared by all clients watching
+// it and closed once the last of them has left, so star connections grow
+// with the number of distinct watches rather than with open browser tabs.
+
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tokio::sync::{
+    broadcast::{self, error::RecvError},
+    Mutex, OwnedSemaphorePermit, Semaphore,
+};
+use sentinel_proto::{star, Status};
+
+use crate::backend::{Backend, BackendError, Subscription};
+
+/// Events a client may fall behind by before it is dropped.
+const BACKLOG: usize = 256;
+
+/// How often a subscription without events checks whether anyone still
+/// watches it.
+const IDLE_CHECK: Duration = Duration::from_secs(5);
+
+/// A watched key, or prefix unless exact.
+type Topic = (Vec<u8>, bool);
+
+type Topics = Arc<Mutex<HashMap<Topic, broadcast::Sender<star::ChangeEvent>>>>;
+
+pub struct WatchHub {
+    backend: Arc<Backend>,
+    bucket: star::Bucket,
+    watchers: Arc<Semaphore>,
+    topics: Topics,
+}
+
+/// One client's view of a shared subscription. Dropping it frees its
+/// watcher slot.
+pub struct Watcher {
+    events: broadcast::Receiver<star::ChangeEvent>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Watcher {
+    /// The next change, or `None` once the star subscription ended or this
+    /// client fell more than `BACKLOG` events behind. Either way it has
+    /// missed changes and should reconnect.
+    pub async fn next(&mut self) -> Option<star::ChangeEvent> {
+        match self.events.recv().await {
+            Ok(event) => Some(event),
+            Err(RecvError::Lagged(missed)) => {
+                tracing::warn!("kv watcher fell {} events behind, closing it", missed);
+                None
+            },
+            Err(RecvError::Closed) => None,
+        }
+    }
+}
+
+impl WatchHub {
+    /// Serves at most `max_watchers` clients over `bucket` at once.
+    pub fn new(backend: Arc<Backend>, bucket: star::Bucket, max_watchers: usize) -> Self {
+        WatchHub {
+            backend,
+            bucket,
+            watchers: Arc::new(Semaphore::new(max_watchers)),
+            topics: Arc::default(),
+        }
+    }
+
+    /// Joins the subscription to `key`, or to every key starting with it
+    /// unless `exact`, opening it first if nobody watches it yet. With every
+    /// watcher slot taken this fails right away with `Status::Busy`.
+    pub async fn watch(&self, key: &[u8], exact: bool) -> Result<Watcher, BackendError> {
+        let permit = Arc::clone(&self.watchers)
+            .try_acquire_owned()
+            .map_err(|_| BackendError::Status(self.backend.name(), Status::Busy))?;
+        let topic = (key.to_vec(), exact);
+        // Held while a new subscription opens, so two clients arriving at
+        // once do not open two.
+        let mut topics = self.topics.lock().await;
+        if let Some(sender) = topics.get(&topic) {
+            return Ok(Watcher { events: sender.subscribe(), _permit: permit });
+        }
+        let subscription = self.backend.subscribe(self.bucket, key, exact).await?;
+        let (sender, events) = broadcast::channel(BACKLOG);
+        topics.insert(topic.clone(), sender.clone());
+        tokio::spawn(forward(subscription, sender, topic, Arc::clone(&self.topics)));
+        Ok(Watcher { events, _permit: permit })
+    }
+}
+
+/// Passes the subscription's events on until it fails or nobody listens.
+/// Dropping `sender` ends every watcher still on it.
+async fn forward(
+    mut subscription: Subscription,
+    sender: broadcast::Sender<star::ChangeEvent>,
+    topic: Topic,
+    topics: Topics,
+) {
+    loop {
+        tokio::select! {
+            event = subscription.next() => match event {
+                // Fails only without receivers, which is checked below.
+                Ok(event) => {
+                    let _ = sender.send(event);
+                },
+                Err(err) => {
+                    tracing::error!("kv watch ended: {}", err);
+                    break;
+                },
+            },
+            _ = tokio::time::sleep(IDLE_CHECK) => {},
+        }
+        if sender.receiver_count() == 0 {
+            // Watchers join under the lock, so checking again under it
+            // leaves none stranded on a closing subscription.
+            let mut topics = topics.lock().await;
+            if sender.receiver_count() == 0 {
+                unregister(&mut topics, &topic, &sender);
+                return;
+            }
+        }
+    }
+    unregister(&mut *topics.lock().await, &topic, &sender);
+}
+
+/// Removes `topic` unless a newer subscription has taken its place.
+fn unregister(
+    topics: &mut HashMap<Topic, broadcast::Sender<star::ChangeEvent>>,
+    topic: &Topic,
+    sender: &broadcast::Sender<star::ChangeEvent>,
+) {
+    if topics.get(topic).is_some_and(|current| current.same_channel(sender)) {
+        topics.remove(topic);
+    }
+}
@@ -0,0 +1,72 @@
+// Sonar's `[sonar]` table of the sentinel config, see
+// `sentinel_proto::config` for where it is read from.
+
+use std::{path::PathBuf, time::Duration};
+use serde::{Deserialize, Serialize};
+use sentinel_proto::config::{ensure_parent, require_positive, ConfigError, Settings};
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+struct Section {
+    read_timeout_secs: u64,
+    buffer_size: usize,
+    shutdown_timeout_secs: u64,
+    max_connections: usize,
+    max_queued: usize,
+    metrics_secs: u64,
+}
+
+impl Default for Section {
+    fn default() -> Self {
+        Section {
+            read_timeout_secs: 30,
+            buffer_size: 65536,
+            shutdown_timeout_secs: 10,
+            max_connections: 64,
+            max_queued: 64,
+            metrics_secs: 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SonarConfig {
+    pub socket: PathBuf,
+    /// Idle clients are dropped after this long.
+    pub read_timeout: Duration,
+    /// Bytes read from a client socket at once.
+    pub buffer_size: usize,
+    /// How long connected clients get to finish on shutdown.
+    pub shutdown_timeout: Duration,
+    /// Connections served at once, one worker thread each.
+    pub max_connections: usize,
+    /// Connections waiting for a worker before new ones are refused.
+    pub max_queued: usize,
+    /// How often connection metrics are printed.
+    pub metrics_interval: Duration,
+}
+
+impl SonarConfig {
+    pub fn load() -> Result<SonarConfig, ConfigError> {
+        let settings = Settings::load()?;
+        let section: Section = settings.section("sonar")?;
+        require_positive("sonar", "read_timeout_secs", section.read_timeout_secs)?;
+        require_positive("sonar", "buffer_size", section.buffer_size as u64)?;
+        require_positive("sonar", "max_connections", section.max_connections as u64)?;
+        require_positive("sonar", "metrics_secs", section.metrics_secs)?;
+        Ok(SonarConfig {
+            socket: settings.socket("sonar")?,
+            read_timeout: Duration::from_secs(section.read_timeout_secs),
+            buffer_size: section.buffer_size,
+            shutdown_timeout: Duration::from_secs(section.shutdown_timeout_secs),
+            max_connections: section.max_connections,
+            max_queued: section.max_queued,
+            metrics_interval: Duration::from_secs(section.metrics_secs),
+        })
+    }
+
+    /// Creates the directory the socket lives in.
+    pub fn create_dirs(&self) -> Result<(), ConfigError> {
+        ensure_parent(&self.socket)
+    }
+}
@@ -1,45 +1,90 @@
 use std::io::Read;
-use std::thread;
 use std::{
     fs,
-    path::{Path},
-    os::unix::net::{UnixStream, UnixListener}
+    net,
+    process,
+    os::unix::net::UnixStream,
 };
+use sentinel_proto::{
+    daemon::{self, ClientError, Shutdown, WorkerPool},
+    frame::FrameDecoder,
+    sonar::Request,
+};
+use config::SonarConfig;
+
+mod config;
 
-fn handle_client(stream: UnixStream) {
+fn handle_client(mut stream: UnixStream, buffer_size: usize) {
     println!("Incomming");
-    //let payload = Vec::new();
-    for byte in stream.bytes() {
-        if let Ok(b) = byte {
-            print!("{}", b);
-        }
+    if let Err(err) = serve(&mut stream, buffer_size) {
+        println!("Closing: {}", err);
     }
+    let _ = stream.shutdown(net::Shutdown::Both);
     println!("Done");
 }
 
-fn main() {
-    let socket = Path::new("/tmp/sentinel/sonar.sock");
+/// Prints log entries until the client hangs up.
+fn serve(stream: &mut UnixStream, buffer_size: usize) -> Result<(), ClientError> {
+    let mut decoder = FrameDecoder::default();
+    let mut buf = vec![0; buffer_size];
+    loop {
+        let count = stream.read(&mut buf).map_err(ClientError::Read)?;
+        if count == 0 { // 0 means EOF package
+            return Ok(());
+        }
+        decoder.extend(&buf[..count]);
 
-    if socket.exists() {
-        fs::remove_file(&socket).unwrap();
+        while let Some(frame) = decoder.next_frame()? {
+            match Request::decode(&frame.payload) {
+                Ok(Request::Log(entry)) => println!("{}", String::from_utf8_lossy(&entry)),
+                Err(status) => println!("Bad request: {}", status),
+            }
+        }
     }
+}
 
-    let listener = match UnixListener::bind(&socket) {
-        Err(_) => panic!("failed to bind socket"),
+fn main() {
+    let config = match SonarConfig::load() {
+        Ok(config) => config,
+        Err(err) => {
+            println!("Invalid config: {}", err);
+            process::exit(2);
+        }
+    };
+    if let Err(err) = config.create_dirs() {
+        println!("{}", err);
+        process::exit(1);
+    }
+    let socket = config.socket.as_path();
+
+    let listener = match daemon::bind(socket) {
+        Err(err) => panic!("failed to bind socket: {}", err),
         Ok(listener) => listener,
     };
+    let shutdown = match Shutdown::on_signal(socket.to_path_buf()) {
+        Err(err) => panic!("failed to install signal handlers: {}", err),
+        Ok(shutdown) => shutdown,
+    };
+
+    let buffer_size = config.buffer_size;
+    let pool = match WorkerPool::new("sonar", config.max_connections, config.max_queued, move |stream| {
+        handle_client(stream, buffer_size);
+    }) {
+        Err(err) => panic!("failed to start workers: {}", err),
+        Ok(pool) => pool,
+    };
+    pool.report_every(config.metrics_interval);
 
     println!("Sonar started, waiting for clients");
 
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                thread::spawn(|| handle_client(stream));
-            }
-            Err(err) => {
-                println!("Error before spawn");
-                println!("{:?}", err);
-            }
-        }
+    pool.accept(&listener, &shutdown, config.read_timeout);
+
+    let busy = shutdown.drain(config.shutdown_timeout);
+    if busy > 0 {
+        println!("{} clients still busy after {:?}, closing anyway", busy, config.shutdown_timeout);
+    }
+    if let Err(err) = fs::remove_file(socket) {
+        println!("Failed to remove {}: {}", socket.display(), err);
     }
+    println!("Sonar stopped");
 }
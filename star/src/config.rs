@@ -0,0 +1,111 @@
+// Star's `[star]` table of the sentinel config, see
+// `sentinel_proto::config` for where it is read from.
+
+use std::{path::PathBuf, time::Duration};
+use serde::{Deserialize, Serialize};
+use sentinel_proto::{
+    config::{ensure_parent, require_positive, ConfigError, Settings},
+    star::MAX_KEY_LEN,
+};
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+struct Section {
+    /// sled database directory.
+    data: PathBuf,
+    read_timeout_secs: u64,
+    buffer_size: usize,
+    shutdown_timeout_secs: u64,
+    max_connections: usize,
+    max_queued: usize,
+    max_subscriptions: usize,
+    metrics_secs: u64,
+    max_key_len: usize,
+    sweep_secs: u64,
+    snapshot_dir: PathBuf,
+    allow_snapshots: bool,
+}
+
+impl Default for Section {
+    fn default() -> Self {
+        Section {
+            data: PathBuf::from("start.bin"),
+            read_timeout_secs: 30,
+            buffer_size: 65536,
+            shutdown_timeout_secs: 10,
+            max_connections: 64,
+            max_queued: 64,
+            max_subscriptions: 256,
+            metrics_secs: 60,
+            max_key_len: 1024,
+            sweep_secs: 60,
+            snapshot_dir: PathBuf::from("snapshots"),
+            allow_snapshots: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct StarConfig {
+    pub socket: PathBuf,
+    pub data: PathBuf,
+    /// Idle clients are dropped after this long.
+    pub read_timeout: Duration,
+    /// Bytes read from a client socket at once.
+    pub buffer_size: usize,
+    /// How long connected clients get to finish on shutdown.
+    pub shutdown_timeout: Duration,
+    /// Connections served at once, one worker thread each.
+    pub max_connections: usize,
+    /// Connections waiting for a worker before new ones are refused.
+    pub max_queued: usize,
+    /// SUBSCRIBE streams served at once, one thread each, outside the
+    /// connection workers.
+    pub max_subscriptions: usize,
+    /// How often connection metrics are printed.
+    pub metrics_interval: Duration,
+    pub max_key_len: usize,
+    pub sweep_interval: Duration,
+    /// Where SNAPSHOT writes; names in requests are relative to it.
+    pub snapshot_dir: PathBuf,
+    /// SNAPSHOT answers `Forbidden` unless this is set.
+    pub allow_snapshots: bool,
+}
+
+impl StarConfig {
+    pub fn load() -> Result<StarConfig, ConfigError> {
+        let settings = Settings::load()?;
+        let section: Section = settings.section("star")?;
+        require_positive("star", "read_timeout_secs", section.read_timeout_secs)?;
+        require_positive("star", "buffer_size", section.buffer_size as u64)?;
+        require_positive("star", "max_connections", section.max_connections as u64)?;
+        require_positive("star", "max_subscriptions", section.max_subscriptions as u64)?;
+        require_positive("star", "metrics_secs", section.metrics_secs)?;
+        require_positive("star", "max_key_len", section.max_key_len as u64)?;
+        require_positive("star", "sweep_secs", section.sweep_secs)?;
+        if section.max_key_len > MAX_KEY_LEN {
+            return Err(ConfigError::new(format!("[star] max_key_len must be at most {}", MAX_KEY_LEN)));
+        }
+        Ok(StarConfig {
+            socket: settings.socket("star")?,
+            data: settings.path(&section.data),
+            read_timeout: Duration::from_secs(section.read_timeout_secs),
+            buffer_size: section.buffer_size,
+            shutdown_timeout: Duration::from_secs(section.shutdown_timeout_secs),
+            max_connections: section.max_connections,
+            max_queued: section.max_queued,
+            max_subscriptions: section.max_subscriptions,
+            metrics_interval: Duration::from_secs(section.metrics_secs),
+            max_key_len: section.max_key_len,
+            sweep_interval: Duration::from_secs(section.sweep_secs),
+            snapshot_dir: settings.path(&section.snapshot_dir),
+            allow_snapshots: section.allow_snapshots,
+        })
+    }
+
+    /// Creates the directories the socket and the database live in.
+    pub fn create_dirs(&self) -> Result<(), ConfigError> {
+        ensure_parent(&self.socket)?;
+        ensure_parent(&self.data)
+    }
+}
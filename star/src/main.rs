@@ -1,202 +1,674 @@
 use kv::{Config, Store, Bucket, Value, Key};
 use std::{
     io::{Read, Write},
-    sync::{Arc, RwLock},
-    os::unix::net::{UnixStream, UnixListener, SocketAddr},
+    net,
+    sync::{Arc, PoisonError, RwLock},
+    os::unix::net::{UnixStream, SocketAddr},
     path::Path,
-    thread, usize, fs, time::Duration,
+    thread, fs, process, time::Duration,
+};
+use sentinel_proto::{
+    daemon::{self, ClientError, Shutdown, WorkerPool},
+    frame::{Frame, FrameDecoder},
+    star::{
+        item_fits, Bucket as StarBucket, Command, KeyValMap, Request, Response, Scan, ScanEnd, ScanPage,
+        MAX_PAGE_BYTES, MAX_SCAN_LIMIT,
+    },
+    Status,
 };
-use serde::{Deserialize, Serialize};
-
-static BUCKETS: [&'static str; 10] = ["config", "group", "user", "table", "flow", "step", "commit", "param", "query", "migration"];
-
-#[derive(Debug, Serialize, Deserialize)]
-struct KeyValMap {
-    key: Vec<u8>,
-    val: Vec<u8>,
-}
 
-fn get_bucket<'a, K: Key<'a>, V: Value>(buffer: &[u8], store: &Arc<RwLock<Store>>) -> Result<Bucket<'a, K, V>, u8> {
-    let bucket_raw = usize::from(buffer[1]);
-    if bucket_raw >= BUCKETS.len() {
-        println!("Invalid bucket requested!");
-        return Err(129);
-    }
+fn get_bucket<'a, K: Key<'a>, V: Value>(bucket: StarBucket, store: &Arc<RwLock<Store>>) -> Result<Bucket<'a, K, V>, Status> {
     let readable = match store.read() {
         Ok(r) => r,
         Err(err) => {
             println!("{:?}", err);
             println!("Failed to get readable!");
-            return Err(130);
+            return Err(Status::StoreUnavailable);
         }
     };
-    match readable.bucket::<K, V>(Some(BUCKETS[bucket_raw])) {
+    match readable.bucket::<K, V>(Some(bucket.name())) {
         Ok(b) => Ok(b),
         Err(err) => {
             println!("{:?}", err);
             println!("Failed to get bucket!");
-            return Err(131);
+            Err(Status::BucketUnavailable)
         }
     }
 }
 
-fn handle_client(mut stream: UnixStream, store: Arc<RwLock<Store>>) {
-    let addr = match stream.peer_addr() {
-        Ok(addr) => addr,
-        Err(_) => SocketAddr::from_pathname("/unkwonw").unwrap()
+mod config;
+mod migrate;
+mod record;
+mod snapshot;
+mod txn;
+mod watch;
+
+use config::StarConfig;
+use record::Record;
+use watch::Watchers;
+
+fn check_key(key: &[u8], max_key_len: usize) -> Result<(), Status> {
+    if key.is_empty() {
+        return Err(Status::BadLength);
+    }
+    if key.len() > max_key_len {
+        return Err(Status::KeyTooLong);
+    }
+    Ok(())
+}
+
+/// Smallest key after every prefixed key, or `None` if the prefix is all 0xFF.
+fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+/// Runs one SCAN page over `[lo, hi)`. The cursor is the walk direction
+/// followed by the last key returned, and only narrows the range of the
+/// next page, so keys written meanwhile past the cursor still show up.
+/// A page ends at `limit` items or `MAX_PAGE_BYTES`, whichever comes first.
+fn scan(bucket: &Bucket<Vec<u8>, Vec<u8>>, scan: Scan, max_key_len: usize) -> Result<ScanPage, Status> {
+    // No stored key is longer than `max_key_len`, so this sorts after all of them.
+    let unbounded = vec![u8::MAX; max_key_len + 1];
+    let mut lo = scan.start;
+    let mut hi = match scan.end {
+        ScanEnd::Unbounded => unbounded,
+        ScanEnd::Before(end) => end,
+        ScanEnd::Prefix(prefix) => {
+            lo = lo.max(prefix.clone());
+            prefix_end(&prefix).unwrap_or(unbounded)
+        },
     };
-    println!("Incomming: {:?}", addr);
-    loop {
+    if let Some(cursor) = scan.cursor {
+        let (&reverse, last) = cursor.split_first().ok_or(Status::Malformed)?;
+        if (reverse == 1) != scan.reverse {
+            return Err(Status::Malformed);
+        }
+        if scan.reverse {
+            hi = hi.min(last.to_vec());
+        } else {
+            // The smallest key after `last`.
+            let mut next = last.to_vec();
+            next.push(0);
+            lo = lo.max(next);
+        }
+    }
+    if lo >= hi {
+        return Ok(ScanPage { items: vec![], cursor: None });
+    }
 
-        let mut buf: [u8; 1048594] = [0; 1048594];
-        let count = match stream.read(&mut buf) {
-            Ok(size) => size,
+    let limit = usize::from(scan.limit.clamp(1, MAX_SCAN_LIMIT));
+    let iter = bucket.iter_range(&lo, &hi);
+    let iter: Box<dyn Iterator<Item = _>> = if scan.reverse { Box::new(iter.rev()) } else { Box::new(iter) };
+    let now = record::now();
+    let mut items: Vec<KeyValMap> = Vec::with_capacity(limit);
+    let mut bytes = 0;
+    let mut more = false;
+    for curs in iter {
+        let (key, raw) = match curs.and_then(|curs| Ok((curs.key()?, curs.value()?))) {
+            Ok(item) => item,
             Err(err) => {
-                println!("Error on read!");
-                println!("{:?}", err.to_string());
-                break;
+                println!("{:?}", err);
+                return Err(Status::ReadFailed);
             }
         };
-        if count == 0 { // 0 means EOF package
+        let Some(record) = Record::live(raw, now)? else {
+            continue;
+        };
+        let item = KeyValMap { key, val: record.value, version: record.version };
+        if items.len() == limit || bytes + item.encoded_len() > MAX_PAGE_BYTES {
+            if items.is_empty() {
+                // Stored before values were capped; no page can carry it.
+                return Err(Status::TooLarge);
+            }
+            more = true;
             break;
         }
+        bytes += item.encoded_len();
+        items.push(item);
+    }
+    let cursor = match items.last() {
+        Some(last) if more => {
+            let mut cursor = vec![u8::from(scan.reverse)];
+            cursor.extend_from_slice(&last.key);
+            Some(cursor)
+        },
+        _ => None,
+    };
+    Ok(ScanPage { items, cursor })
+}
 
-        let cmd = buf[0];
-        println!("Got: {:?}", cmd);
-        let bucket = match get_bucket::<Vec<u8>, Vec<u8>>(&buf, &store) {
-            Ok(b) => b,
-            Err(flag) => {
-                stream.write_all(&[cmd, flag]).unwrap();
-                continue;
-            }
+fn flush(bucket: &Bucket<Vec<u8>, Vec<u8>>) {
+    let flush_op = bucket.flush();
+    if flush_op.is_err() {
+        println!("Failed to flush");
+    }
+}
+
+fn read_live(bucket: &Bucket<Vec<u8>, Vec<u8>>, key: &Vec<u8>) -> Result<Option<Record>, Status> {
+    match bucket.get(key) {
+        Ok(Some(raw)) => Record::live(raw, record::now()),
+        Ok(None) => Ok(None),
+        Err(err) => {
+            println!("{:?}", err);
+            Err(Status::ReadFailed)
+        }
+    }
+}
+
+/// Writes `value` under a fresh version, provided the key is currently at
+/// `expected` (0 for missing or expired). `None` writes unconditionally.
+/// Returns the new version.
+fn write_versioned(
+    store: &Arc<RwLock<Store>>,
+    bucket: &Bucket<Vec<u8>, Vec<u8>>,
+    key: &Vec<u8>,
+    value: Vec<u8>,
+    ttl: Option<u32>,
+    expected: Option<u64>,
+) -> Result<u64, Status> {
+    // sled can't hand out IDs from inside a transaction, so the fresh ID is
+    // drawn first and only used if it is above the key's current version.
+    let fresh = match store.read().map(|readable| record::next_version(&readable)) {
+        Ok(Ok(version)) => version,
+        Ok(Err(err)) => {
+            println!("{:?}", err);
+            return Err(Status::WriteFailed);
+        }
+        Err(err) => {
+            println!("{:?}", err);
+            return Err(Status::StoreUnavailable);
+        }
+    };
+    // The version is read and replaced in one transaction, so two writers
+    // can't both pass the check or move a key's version backwards.
+    let written = bucket.transaction::<_, kv::Error, _>(|txn| {
+        let current = match txn.get(key)?.map(|raw| Record::live(raw, record::now())) {
+            Some(Ok(Some(record))) => record.version,
+            _ => 0,
         };
-        if count < 18 {
-            stream.write_all(&[cmd, 136]).unwrap();
-            println!("Bad length!");
-            continue;
+        if matches!(expected, Some(expected) if expected != current) {
+            return Ok(None);
         }
-        let key = &buf[2..18].to_vec();
-
-        match cmd {
-            1 => { // GET Key
-                let value = bucket.get(key);
-                match value {
-                    Ok(res) => {
-                        match res {
-                            Some(val) => {
-                                let mut resp: Vec<u8> = vec![cmd, 0];
-                                resp.append(&mut val.clone());
-                                stream.write_all(&resp).unwrap();
-                            },
-                            None => {
-                                stream.write_all(&[cmd, 132]).unwrap();
-                            }
-                        }
-                    },
-                    Err(err) => {
-                        stream.write_all(&[cmd, 133]).unwrap();
-                        println!("{:?}", err);
-                    }
-                }
+        let version = fresh.max(current + 1);
+        txn.set(key, &Record::new(value.clone(), ttl, version).encode())?;
+        Ok(Some(version))
+    });
+    match written {
+        Ok(Some(version)) => {
+            flush(bucket);
+            Ok(version)
+        },
+        Ok(None) => Err(Status::Conflict),
+        Err(err) => {
+            println!("{:?}", err);
+            Err(Status::WriteFailed)
+        }
+    }
+}
+
+/// Replaces the TTL of a live key. Runs in a transaction so a concurrent
+/// SET is not overwritten with the old value.
+fn set_ttl(bucket: &Bucket<Vec<u8>, Vec<u8>>, key: &Vec<u8>, ttl: Option<u32>) -> Result<(), Status> {
+    let updated = bucket.transaction::<_, kv::Error, _>(|txn| {
+        let Some(raw) = txn.get(key)? else {
+            return Ok(false);
+        };
+        match Record::live(raw, record::now()) {
+            Ok(Some(mut record)) => {
+                record.expire(ttl);
+                txn.set(key, &record.encode())?;
+                Ok(true)
             },
-            2 => { // SET Key
-                let mut payload = vec![];
-                for i in 18..count {
-                    payload.insert(i-18, buf[i]);
-                }
-                let value = bucket.set(key, &payload);
-                match value {
-                    Ok(_) => {
-                        stream.write_all(&[cmd, 0]).unwrap();
-                    },
-                    Err(err) => {
-                        stream.write_all(&[cmd, 134]).unwrap();
-                        println!("{:?}", err);
-                    }
+            _ => Ok(false),
+        }
+    });
+    match updated {
+        Ok(true) => {
+            flush(bucket);
+            Ok(())
+        },
+        Ok(false) => Err(Status::NotFound),
+        Err(err) => {
+            println!("{:?}", err);
+            Err(Status::WriteFailed)
+        }
+    }
+}
+
+fn handle_command(buf: &[u8], store: &Arc<RwLock<Store>>, max_key_len: usize) -> Response {
+    let request = match Request::decode(buf) {
+        Ok(request) => request,
+        Err(status) => {
+            println!("Bad request: {}", status);
+            return Response::Error { cmd: buf.first().copied().unwrap_or(0), status };
+        }
+    };
+    let cmd = request.command();
+    println!("Got: {:?}", cmd);
+    if let Request::Get { key, .. }
+    | Request::Set { key, .. }
+    | Request::Del { key, .. }
+    | Request::Expire { key, .. }
+    | Request::Persist { key, .. }
+    | Request::Ttl { key, .. }
+    | Request::Cas { key, .. } = &request {
+        if let Err(status) = check_key(key, max_key_len) {
+            return Response::error(cmd, status);
+        }
+    }
+    if let Request::Set { key, value, .. } | Request::Cas { key, value, .. } = &request {
+        if !item_fits(key, value) {
+            return Response::error(cmd, Status::TooLarge);
+        }
+    }
+    let (request, bucket) = match request {
+        Request::Txn(ops) => return txn::run(ops, store, max_key_len),
+        request @ (Request::MGet { .. } | Request::MSet { .. } | Request::MDel { .. }) => {
+            return txn::batch(request, store, max_key_len);
+        },
+        request => match request.bucket() {
+            Some(bucket) => (request, get_bucket::<Vec<u8>, Vec<u8>>(bucket, store)),
+            None => return Response::error(cmd, Status::InvalidBucket),
+        },
+    };
+    let bucket = match bucket {
+        Ok(b) => b,
+        Err(status) => {
+            return Response::error(cmd, status);
+        }
+    };
+
+    match request {
+        Request::Get { key, .. } => {
+            match read_live(&bucket, &key) {
+                Ok(Some(record)) => Response::Get { version: record.version, value: record.value },
+                Ok(None) => Response::error(cmd, Status::NotFound),
+                Err(status) => Response::error(cmd, status),
+            }
+        },
+        Request::Set { key, value, ttl, .. } => {
+            match write_versioned(store, &bucket, &key, value, ttl, None) {
+                Ok(version) => Response::Set { version },
+                Err(status) => Response::error(cmd, status),
+            }
+        },
+        Request::Cas { key, expected, value, ttl, .. } => {
+            match write_versioned(store, &bucket, &key, value, ttl, Some(expected)) {
+                Ok(version) => Response::Cas { version },
+                Err(status) => Response::error(cmd, status),
+            }
+        },
+        Request::Del { key, .. } => {
+            let resp = match bucket.remove(&key) {
+                Ok(_) => Response::Del,
+                Err(err) => {
+                    println!("{:?}", err);
+                    Response::error(cmd, Status::DeleteFailed)
                 }
-                let flush_op = bucket.flush();
-                if flush_op.is_err() {
-                    println!("Failed to flush");
+            };
+            flush(&bucket);
+            resp
+        },
+        // Stops early rather than answer more than `MAX_PAGE_BYTES`.
+        Request::GetAll { limit, .. } => {
+            let now = record::now();
+            let mut items: Vec<KeyValMap> = vec![];
+            let mut bytes = 0;
+            for curs in bucket.iter() {
+                if items.len() == usize::from(limit) {
+                    break;
                 }
-            },
-            3 => { // DEL Key
-                let value = bucket.remove(key);
-                match value {
-                    Ok(_) => {
-                        stream.write_all(&[cmd, 0]).unwrap();
-                    },
+                let item = match curs.and_then(|curs| Ok((curs.key()?, curs.value()?))) {
+                    Ok(item) => item,
                     Err(err) => {
-                        stream.write_all(&[cmd, 135]).unwrap();
                         println!("{:?}", err);
+                        return Response::error(Command::GetAll, Status::ReadFailed);
                     }
-                }
-                let flush_op = bucket.flush();
-                if flush_op.is_err() {
-                    println!("Failed to flush");
-                }
-            },
-            4 => { // GET All
-                let mut iter = bucket.iter();
-                let ic = buf[18];
-                let mut ia = 0;
-                let mut items: Vec<KeyValMap> = vec![];
-                while ia < ic {
-                    ia += 1;
-                    if let Some(Ok(curs)) = iter.next() {
-                        let item = KeyValMap {
-                            key: curs.key().unwrap(),
-                            val: curs.value().unwrap(),
-                        };
+                };
+                match Record::live(item.1, now) {
+                    Ok(Some(record)) => {
+                        let item = KeyValMap { key: item.0, val: record.value, version: record.version };
+                        bytes += item.encoded_len();
+                        if bytes > MAX_PAGE_BYTES {
+                            break;
+                        }
                         items.push(item);
-                    } else {
-                        break;
-                    }
+                    },
+                    Ok(None) => {},
+                    Err(status) => return Response::error(Command::GetAll, status),
                 }
-                let mut serialized_items = serde_json::to_vec(&items).unwrap();
-                let mut resp: Vec<u8> = vec![cmd, 0];
-                resp.append(&mut serialized_items);
-                stream.write_all(&resp).unwrap();
-            },
-            _ => {
-                println!("UNKNOWN CMD!");
-                stream.write_all(&[cmd, 128]).unwrap();
-            },
+            }
+            Response::GetAll(items)
+        },
+        Request::Scan { scan: params, .. } => {
+            match scan(&bucket, params, max_key_len) {
+                Ok(page) => Response::Scan(page),
+                Err(status) => Response::error(cmd, status),
+            }
+        },
+        Request::Expire { key, ttl, .. } => {
+            match set_ttl(&bucket, &key, Some(ttl)) {
+                Ok(()) => Response::Expire,
+                Err(status) => Response::error(cmd, status),
+            }
+        },
+        Request::Persist { key, .. } => {
+            match set_ttl(&bucket, &key, None) {
+                Ok(()) => Response::Persist,
+                Err(status) => Response::error(cmd, status),
+            }
+        },
+        Request::Ttl { key, .. } => {
+            match read_live(&bucket, &key) {
+                Ok(Some(record)) => Response::Ttl(record.ttl(record::now())),
+                Ok(None) => Response::error(cmd, Status::NotFound),
+                Err(status) => Response::error(cmd, status),
+            }
+        },
+        Request::Txn(_)
+        | Request::MGet { .. }
+        | Request::MSet { .. }
+        | Request::MDel { .. } => unreachable!("TXN and batches are handled above"),
+        Request::Subscribe { .. } | Request::Snapshot { .. } => unreachable!("handled by handle_client"),
+    }
+}
+
+/// Removes expired records from every bucket. A record rewritten since it
+/// was read fails the compare-and-swap and is left alone.
+fn sweep(store: &Arc<RwLock<Store>>, gate: &RwLock<()>) {
+    let _writes = gate.read().unwrap_or_else(PoisonError::into_inner);
+    let now = record::now();
+    for star_bucket in StarBucket::ALL {
+        let bucket = match get_bucket::<Vec<u8>, Vec<u8>>(star_bucket, store) {
+            Ok(b) => b,
+            Err(status) => {
+                println!("Sweeper skipped {}: {}", star_bucket.name(), status);
+                continue;
+            }
+        };
+        let mut count = 0;
+        for curs in bucket.iter() {
+            let Ok((key, raw)) = curs.and_then(|curs| Ok((curs.key::<Vec<u8>>()?, curs.value::<Vec<u8>>()?))) else {
+                continue;
+            };
+            let expired = matches!(Record::decode(raw.clone()), Ok(record) if record.is_expired(now));
+            if expired && bucket.compare_and_swap(&key, Some(&raw), None).is_ok() {
+                count += 1;
+            }
+        }
+        if count > 0 {
+            flush(&bucket);
+            println!("Swept {} expired keys from {}", count, star_bucket.name());
         }
     }
+}
+
+fn spawn_sweeper(store: Arc<RwLock<Store>>, gate: Arc<RwLock<()>>, interval: Duration) {
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        sweep(&store, &gate);
+    });
+}
+
+fn handle_client(
+    mut stream: UnixStream,
+    store: Arc<RwLock<Store>>,
+    gate: Arc<RwLock<()>>,
+    config: Arc<StarConfig>,
+    watchers: Arc<Watchers>,
+) {
+    let addr = match stream.peer_addr() {
+        Ok(addr) => addr,
+        Err(_) => SocketAddr::from_pathname("/unkwonw").unwrap()
+    };
+    println!("Incomming: {:?}", addr);
+    match serve(&mut stream, &store, &gate, &config) {
+        // The connection moves to a subscription thread and frees this worker.
+        Ok(Some(subscribe)) => return watchers.spawn(stream, subscribe, &store, config.max_key_len),
+        Ok(None) => {},
+        Err(err) => println!("Closing {:?}: {}", addr, err),
+    }
+    let _ = stream.shutdown(net::Shutdown::Both);
     println!("DONE! {:?}", addr);
 }
 
+/// Answers frames until the client hangs up or sends SUBSCRIBE, whose frame
+/// is returned if nothing was sent after it. Every command holds `gate`
+/// shared while it runs; SNAPSHOT takes it exclusively to see no half-done
+/// write.
+fn serve(stream: &mut UnixStream, store: &Arc<RwLock<Store>>, gate: &Arc<RwLock<()>>, config: &StarConfig) -> Result<Option<Frame>, ClientError> {
+    let mut decoder = FrameDecoder::default();
+    let max_key_len = config.max_key_len;
+    let mut buf = vec![0; config.buffer_size];
+    loop {
+        let count = stream.read(&mut buf).map_err(ClientError::Read)?;
+        if count == 0 { // 0 means EOF package
+            return Ok(None);
+        }
+        decoder.extend(&buf[..count]);
+
+        while let Some(frame) = decoder.next_frame()? {
+            if frame.payload.first() == Some(&(Command::Subscribe as u8)) {
+                if decoder.is_empty() {
+                    return Ok(Some(frame));
+                }
+                return refuse_pipelined(stream, frame, decoder);
+            }
+            let resp = if frame.payload.first() == Some(&(Command::Snapshot as u8)) {
+                snapshot::handle(&frame.payload, store, gate, config)
+            } else {
+                let _writes = gate.read().unwrap_or_else(PoisonError::into_inner);
+                handle_command(&frame.payload, store, max_key_len)
+            }.encode();
+            stream
+                .write_all(&Frame::encode_response(frame.request_id, resp))
+                .map_err(ClientError::Write)?;
+        }
+    }
+}
+
+/// A subscribed connection only carries events, so frames sent right
+/// behind SUBSCRIBE would never be answered. The SUBSCRIBE and every
+/// complete frame after it get `Malformed`, and the connection is closed.
+fn refuse_pipelined(stream: &mut UnixStream, subscribe: Frame, mut decoder: FrameDecoder) -> Result<Option<Frame>, ClientError> {
+    let mut frames = vec![subscribe];
+    while let Some(frame) = decoder.next_frame()? {
+        frames.push(frame);
+    }
+    for frame in frames {
+        let cmd = frame.payload.first().copied().unwrap_or(0);
+        let resp = Response::Error { cmd, status: Status::Malformed }.encode();
+        stream
+            .write_all(&Frame::encode_response(frame.request_id, resp))
+            .map_err(ClientError::Write)?;
+    }
+    Ok(None)
+}
+
+/// `star restore <file>`: rebuilds start.bin from a snapshot. Star must not
+/// be running, sled refuses to open the store twice.
+fn restore(config: &StarConfig, file: &str) {
+    let store = match Store::new(Config::new(&config.data)) {
+        Ok(store) => store,
+        Err(err) => {
+            println!("Failed to open start.bin, is star still running? {:?}", err);
+            process::exit(1);
+        }
+    };
+    match snapshot::restore(&store, Path::new(file)) {
+        Ok(entries) => println!("Restored {} entries from {}", entries, file),
+        Err(err) => {
+            println!("Failed to restore {}: {}", file, err);
+            process::exit(1);
+        }
+    }
+    if let Err(err) = migrate::run(&store) {
+        println!("Failed to migrate restored store: {:?}", err);
+        process::exit(1);
+    }
+}
+
+/// `star snapshot <name> [--compress] [--overwrite]`: asks the running star
+/// for a snapshot, written to `name` inside its `snapshot_dir`.
+fn request_snapshot(config: &StarConfig, name: &str, compress: bool, overwrite: bool) {
+    let path = config.snapshot_dir.join(name);
+    let req = Request::Snapshot { name: name.to_owned(), compress, overwrite };
+    let mut stream = match UnixStream::connect(&config.socket) {
+        Ok(stream) => stream,
+        Err(err) => {
+            println!("Failed to connect to star: {}", err);
+            process::exit(1);
+        }
+    };
+    let mut decoder = FrameDecoder::default();
+    let mut buf = [0; 4096];
+    let raw = match req.encode() {
+        Ok(payload) => Frame::new(0, payload).encode().map_err(|err| err.to_string()),
+        Err(status) => Err(status.to_string()),
+    };
+    let raw = match raw {
+        Ok(raw) => raw,
+        Err(err) => {
+            println!("Failed to encode snapshot request: {}", err);
+            process::exit(1);
+        }
+    };
+    if let Err(err) = stream.write_all(&raw) {
+        println!("Failed to send snapshot request: {}", err);
+        process::exit(1);
+    }
+    let frame = loop {
+        match decoder.next_frame() {
+            Ok(Some(frame)) => break frame,
+            Ok(None) => {},
+            Err(err) => {
+                println!("Bad frame: {}", err);
+                process::exit(1);
+            }
+        }
+        match stream.read(&mut buf) {
+            Ok(count) if count > 0 => decoder.extend(&buf[..count]),
+            _ => {
+                println!("Star hung up");
+                process::exit(1);
+            }
+        }
+    };
+    match Response::decode(&frame.payload) {
+        Ok(Response::Snapshot(info)) => println!(
+            "Wrote {} entries, {} bytes, crc32 {:08x} to {}",
+            info.entries, info.bytes, info.checksum, path.display(),
+        ),
+        Ok(Response::Error { status, .. }) => {
+            println!("Snapshot failed: {}", status);
+            match status {
+                Status::Forbidden => println!("Set allow_snapshots in [star] to enable snapshots"),
+                Status::BadPath => println!("The name must be a relative path inside {}", config.snapshot_dir.display()),
+                Status::Exists => println!("{} exists, pass --overwrite to replace it", path.display()),
+                _ => {},
+            }
+            process::exit(1);
+        }
+        Ok(rsp) => {
+            println!("Snapshot failed: {:?}", rsp);
+            process::exit(1);
+        }
+        Err(status) => {
+            println!("Snapshot failed: {}", status);
+            process::exit(1);
+        }
+    }
+}
+
+const SNAPSHOT_FLAGS: [&str; 2] = ["--compress", "--overwrite"];
+
 fn main() {
-    let socket = Path::new("/tmp/sentinel/star.sock");
+    let config = match StarConfig::load() {
+        Ok(config) => config,
+        Err(err) => {
+            println!("Invalid config: {}", err);
+            process::exit(2);
+        }
+    };
+    let args: Vec<String> = std::env::args().collect();
+    match args.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+        [_, "restore", file] => return restore(&config, file),
+        [_, "snapshot", name, flags @ ..] if flags.iter().all(|flag| SNAPSHOT_FLAGS.contains(flag)) => {
+            return request_snapshot(&config, name, flags.contains(&"--compress"), flags.contains(&"--overwrite"));
+        },
+        [_] => {},
+        _ => {
+            println!("usage: star [restore <file> | snapshot <name> [--compress] [--overwrite]]");
+            process::exit(2);
+        }
+    }
 
-    if socket.exists() {
-        fs::remove_file(&socket).unwrap();
+    if let Err(err) = config.create_dirs() {
+        println!("{}", err);
+        process::exit(1);
     }
+    let socket = config.socket.clone();
+    let listener = match daemon::bind(&socket) {
+        Err(err) => panic!("failed to bind socket: {}", err),
+        Ok(listener) => listener,
+    };
+    let shutdown = match Shutdown::on_signal(socket.clone()) {
+        Err(err) => panic!("failed to install signal handlers: {}", err),
+        Ok(shutdown) => shutdown,
+    };
 
-    let cfg = Config::new("/tmp/sentinel/start.bin");
+    let cfg = Config::new(&config.data);
 
     let store = Arc::new(RwLock::new(Store::new(cfg).unwrap()));
+    if let Err(err) = migrate::run(&store.read().unwrap()) {
+        panic!("failed to migrate start.bin: {:?}", err);
+    }
+    let gate = Arc::new(RwLock::new(()));
+    spawn_sweeper(Arc::clone(&store), Arc::clone(&gate), config.sweep_interval);
+    let config = Arc::new(config);
 
-    let listener = match UnixListener::bind(&socket) {
-        Err(_) => panic!("failed to bind socket"),
-        Ok(listener) => listener,
+    let watchers = Watchers::new(config.max_subscriptions, Arc::clone(&shutdown));
+    let pool = {
+        let store = Arc::clone(&store);
+        let gate = Arc::clone(&gate);
+        let config = Arc::clone(&config);
+        let watchers = Arc::clone(&watchers);
+        WorkerPool::new("star", config.max_connections, config.max_queued, move |stream| {
+            handle_client(stream, Arc::clone(&store), Arc::clone(&gate), Arc::clone(&config), Arc::clone(&watchers));
+        })
+    };
+    let pool = match pool {
+        Err(err) => panic!("failed to start workers: {}", err),
+        Ok(pool) => pool,
     };
+    pool.report_every(config.metrics_interval);
 
     println!("Star started, waiting for clients");
 
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                stream.set_read_timeout(Some(Duration::from_secs(30))).unwrap();
-                let store_instance = Arc::clone(&store);
-                thread::spawn(move || handle_client(stream, store_instance));
-            }
-            Err(err) => {
-                println!("Error before spawn");
-                println!("{:?}", err);
-            }
+    pool.accept(&listener, &shutdown, config.read_timeout);
+
+    let busy = shutdown.drain(config.shutdown_timeout);
+    if busy > 0 {
+        println!("{} clients still busy after {:?}, closing anyway", busy, config.shutdown_timeout);
+    }
+    // Waits for writes still running, the sweeper's included.
+    let _writes = gate.write().unwrap_or_else(PoisonError::into_inner);
+    for star_bucket in StarBucket::ALL {
+        if let Ok(bucket) = get_bucket::<Vec<u8>, Vec<u8>>(star_bucket, &store) {
+            flush(&bucket);
         }
     }
+    if let Err(err) = fs::remove_file(&socket) {
+        println!("Failed to remove {}: {}", socket.display(), err);
+    }
+    println!("Star stopped");
 }
 
 // use idgenerator::{IdGeneratorOptions, IdInstance};
@@ -204,3 +676,210 @@ fn main() {
 // let _ = IdInstance::init(options).unwrap();
 // let id = IdInstance::next_id();
 // let key_for_storage = id.to_be_bytes();
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::PathBuf,
+        time::{SystemTime, UNIX_EPOCH},
+    };
+    use super::*;
+
+    pub fn temp_path(name: &str) -> PathBuf {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos();
+        std::env::temp_dir().join(format!("star-{}-{}-{}", name, process::id(), nanos))
+    }
+
+    pub fn temp_store(name: &str) -> Store {
+        Store::new(Config::new(temp_path(name)).temporary(true)).unwrap()
+    }
+
+    pub fn shared_store(name: &str) -> Arc<RwLock<Store>> {
+        Arc::new(RwLock::new(temp_store(name)))
+    }
+
+    pub const MAX_KEY_LEN: usize = 1024;
+
+    pub fn send(store: &Arc<RwLock<Store>>, request: Request) -> Response {
+        handle_command(&request.encode().unwrap(), store, MAX_KEY_LEN)
+    }
+
+    pub fn set(store: &Arc<RwLock<Store>>, key: &[u8], value: &[u8], ttl: Option<u32>) -> u64 {
+        let request = Request::Set { bucket: StarBucket::Kv, key: key.to_vec(), value: value.to_vec(), ttl };
+        match send(store, request) {
+            Response::Set { version } => version,
+            other => panic!("SET answered {:?}", other),
+        }
+    }
+
+    fn get(store: &Arc<RwLock<Store>>, key: &[u8]) -> Response {
+        send(store, Request::Get { bucket: StarBucket::Kv, key: key.to_vec() })
+    }
+
+    /// Follows the cursor to the end, returning the keys of every page.
+    fn scan_pages(store: &Arc<RwLock<Store>>, reverse: bool, limit: u16) -> Vec<Vec<Vec<u8>>> {
+        let mut pages = vec![];
+        let mut cursor = None;
+        loop {
+            let scan = Scan { limit, reverse, cursor, ..Scan::default() };
+            let page = match send(store, Request::Scan { bucket: StarBucket::Kv, scan }) {
+                Response::Scan(page) => page,
+                other => panic!("SCAN answered {:?}", other),
+            };
+            pages.push(page.items.into_iter().map(|item| item.key).collect());
+            match page.cursor {
+                Some(next) => cursor = Some(next),
+                None => return pages,
+            }
+        }
+    }
+
+    fn keys(names: &[&[u8]]) -> Vec<Vec<u8>> {
+        names.iter().map(|name| name.to_vec()).collect()
+    }
+
+    #[test]
+    fn scan_pages_end_at_max_page_bytes() {
+        let store = shared_store("scan");
+        // Two of these fill a page, a third does not fit.
+        let header = KeyValMap { key: b"a".to_vec(), val: vec![], version: 0 }.encoded_len();
+        let value = vec![7; MAX_PAGE_BYTES / 2 - header];
+        for key in [b"a", b"b", b"c", b"d", b"e"] {
+            set(&store, key, &value, None);
+        }
+
+        let forward = scan_pages(&store, false, MAX_SCAN_LIMIT);
+        assert_eq!(forward, vec![keys(&[b"a", b"b"]), keys(&[b"c", b"d"]), keys(&[b"e"])]);
+        let backward = scan_pages(&store, true, MAX_SCAN_LIMIT);
+        assert_eq!(backward, vec![keys(&[b"e", b"d"]), keys(&[b"c", b"b"]), keys(&[b"a"])]);
+    }
+
+    #[test]
+    fn scan_cursor_keeps_its_direction() {
+        let store = shared_store("scan-direction");
+        for key in [b"a", b"b", b"c"] {
+            set(&store, key, b"v", None);
+        }
+        let scan = Scan { limit: 1, ..Scan::default() };
+        let Response::Scan(page) = send(&store, Request::Scan { bucket: StarBucket::Kv, scan }) else {
+            panic!("SCAN failed");
+        };
+        let scan = Scan { limit: 1, reverse: true, cursor: page.cursor, ..Scan::default() };
+        let response = send(&store, Request::Scan { bucket: StarBucket::Kv, scan });
+        assert_eq!(response, Response::error(Command::Scan, Status::Malformed));
+    }
+
+    #[test]
+    fn keys_expire_between_reads() {
+        let store = shared_store("ttl");
+        set(&store, b"a", b"1", None);
+        let expires_at = record::now() + 1;
+        set(&store, b"b", b"2", Some(1));
+        set(&store, b"c", b"3", None);
+        assert!(matches!(get(&store, b"b"), Response::Get { .. }));
+
+        let scan = Scan { limit: 1, ..Scan::default() };
+        let Response::Scan(first) = send(&store, Request::Scan { bucket: StarBucket::Kv, scan }) else {
+            panic!("SCAN failed");
+        };
+        assert_eq!(first.items[0].key, b"a");
+        while record::now() < expires_at {
+            thread::sleep(Duration::from_millis(50));
+        }
+
+        // The record is still on disk; the sweeper has not run.
+        let scan = Scan { limit: 1, cursor: first.cursor, ..Scan::default() };
+        let Response::Scan(second) = send(&store, Request::Scan { bucket: StarBucket::Kv, scan }) else {
+            panic!("SCAN failed");
+        };
+        assert_eq!(second.items[0].key, b"c");
+        assert_eq!(get(&store, b"b"), Response::error(Command::Get, Status::NotFound));
+        let ttl = send(&store, Request::Ttl { bucket: StarBucket::Kv, key: b"b".to_vec() });
+        assert_eq!(ttl, Response::error(Command::Ttl, Status::NotFound));
+        let mget = send(&store, Request::MGet { bucket: StarBucket::Kv, keys: keys(&[b"a", b"b"]) });
+        let Response::MGet(found) = mget else {
+            panic!("MGET failed");
+        };
+        assert!(found[0].is_some() && found[1].is_none());
+        // An expired key counts as missing for CAS.
+        let cas = Request::Cas { bucket: StarBucket::Kv, key: b"b".to_vec(), expected: 0, value: b"4".to_vec(), ttl: None };
+        assert!(matches!(send(&store, cas), Response::Cas { .. }));
+    }
+
+    pub fn test_config() -> StarConfig {
+        StarConfig {
+            socket: temp_path("socket"),
+            data: temp_path("data"),
+            read_timeout: Duration::from_secs(5),
+            buffer_size: 65536,
+            shutdown_timeout: Duration::from_secs(1),
+            max_connections: 1,
+            max_queued: 1,
+            max_subscriptions: 1,
+            metrics_interval: Duration::from_secs(60),
+            max_key_len: MAX_KEY_LEN,
+            sweep_interval: Duration::from_secs(60),
+            snapshot_dir: temp_path("snapshots"),
+            allow_snapshots: false,
+        }
+    }
+
+    fn frame(request_id: u32, request: Request) -> Vec<u8> {
+        Frame::new(request_id, request.encode().unwrap()).encode().unwrap()
+    }
+
+    fn answers(client: &mut UnixStream) -> Vec<(u32, Response)> {
+        let mut raw = vec![];
+        client.read_to_end(&mut raw).unwrap();
+        let mut decoder = FrameDecoder::default();
+        decoder.extend(&raw);
+        let mut answers = vec![];
+        while let Some(frame) = decoder.next_frame().unwrap() {
+            answers.push((frame.request_id, Response::decode(&frame.payload).unwrap()));
+        }
+        answers
+    }
+
+    #[test]
+    fn subscribe_is_handed_over_alone() {
+        let store = shared_store("subscribe");
+        let (mut client, mut server) = UnixStream::pair().unwrap();
+        let subscribe = Request::Subscribe { bucket: StarBucket::Kv, key: b"k".to_vec(), exact: true };
+        client.write_all(&frame(1, subscribe.clone())).unwrap();
+        let handed = serve(&mut server, &store, &Arc::new(RwLock::new(())), &test_config()).unwrap();
+        assert_eq!(handed.map(|frame| frame.payload), Some(subscribe.encode().unwrap()));
+    }
+
+    #[test]
+    fn frames_behind_subscribe_are_refused() {
+        let store = shared_store("pipelined");
+        let (mut client, mut server) = UnixStream::pair().unwrap();
+        let subscribe = Request::Subscribe { bucket: StarBucket::Kv, key: b"k".to_vec(), exact: true };
+        let get = Request::Get { bucket: StarBucket::Kv, key: b"k".to_vec() };
+        client.write_all(&[frame(1, subscribe), frame(2, get)].concat()).unwrap();
+        let handed = serve(&mut server, &store, &Arc::new(RwLock::new(())), &test_config()).unwrap();
+        assert!(handed.is_none());
+        drop(server);
+        assert_eq!(answers(&mut client), vec![
+            (1, Response::error(Command::Subscribe, Status::Malformed)),
+            (2, Response::error(Command::Get, Status::Malformed)),
+        ]);
+    }
+
+    #[test]
+    fn cas_on_a_stale_version_conflicts() {
+        let store = shared_store("cas");
+        let first = set(&store, b"k", b"1", None);
+        let second = set(&store, b"k", b"2", None);
+        assert!(second > first);
+
+        let cas = |expected| Request::Cas { bucket: StarBucket::Kv, key: b"k".to_vec(), expected, value: b"3".to_vec(), ttl: None };
+        assert_eq!(send(&store, cas(first)), Response::error(Command::Cas, Status::Conflict));
+        assert_eq!(send(&store, cas(0)), Response::error(Command::Cas, Status::Conflict));
+        assert_eq!(get(&store, b"k"), Response::Get { version: second, value: b"2".to_vec() });
+        let Response::Cas { version } = send(&store, cas(second)) else {
+            panic!("CAS on the current version failed");
+        };
+        assert_eq!(get(&store, b"k"), Response::Get { version, value: b"3".to_vec() });
+    }
+}
@@ -0,0 +1,272 @@
+// On-disk format upgrades for start.bin, run once at startup.
+//
+// The current format version lives in the `meta` bucket under `format`.
+// Stores written before variable-length keys have no version at all.
+//
+// Every step commits its writes together with its progress in `meta`, so a
+// store interrupted mid-upgrade picks up where it stopped instead of
+// rewriting data that was already converted.
+
+use kv::{Batch, Bucket, Error, Store};
+use sentinel_proto::star::Bucket as StarBucket;
+
+use crate::record::{self, Record};
+
+pub const META_BUCKET: &str = "meta";
+const FORMAT_KEY: &[u8] = b"format";
+/// Keys are stored as sent, up to the configured maximum length.
+const FORMAT_VARIABLE_KEYS: u8 = 2;
+/// Values are wrapped in a record carrying their expiry.
+const FORMAT_RECORDS: u8 = 3;
+/// Records also carry a version.
+const FORMAT_VERSIONS: u8 = 4;
+
+type Raw = Bucket<'static, Vec<u8>, Vec<u8>>;
+
+fn format(meta: &Raw) -> Result<u8, Error> {
+    Ok(meta.get(&FORMAT_KEY.to_vec())?
+        .and_then(|raw| raw.first().copied())
+        .unwrap_or(1))
+}
+
+/// Set in `meta` once a bucket holds versioned records, until the whole
+/// store is at `FORMAT_VERSIONS`.
+fn wrapped_key(bucket: StarBucket) -> Vec<u8> {
+    format!("wrapped:{}", bucket.name()).into_bytes()
+}
+
+pub fn run(store: &Store) -> Result<(), Error> {
+    let meta = store.bucket::<Vec<u8>, Vec<u8>>(Some(META_BUCKET))?;
+    let mut version = format(&meta)?;
+    if version < FORMAT_VARIABLE_KEYS {
+        let kv = store.bucket::<Vec<u8>, Vec<u8>>(Some(StarBucket::Kv.name()))?;
+        let count = rekey_kv(&kv, &meta)?;
+        println!("Migrated {} kv entries to variable-length keys", count);
+        version = FORMAT_VARIABLE_KEYS;
+    }
+    if version < FORMAT_VERSIONS {
+        let mut count = 0;
+        for bucket in StarBucket::ALL {
+            count += wrap_records(store, &meta, bucket, version >= FORMAT_RECORDS)?;
+        }
+        println!("Migrated {} values to versioned records", count);
+        let mut batch = Batch::new();
+        batch.set(&FORMAT_KEY.to_vec(), &vec![FORMAT_VERSIONS])?;
+        for bucket in StarBucket::ALL {
+            batch.remove(&wrapped_key(bucket))?;
+        }
+        meta.batch(batch)?;
+    }
+    meta.flush()?;
+    Ok(())
+}
+
+/// With 16-byte keys only, satellite stored `/kv` entries under the hash of
+/// their key as `[key_len: u16 BE][key][value]`. Those are moved back to
+/// their original key with the plain value. Every other bucket keeps its
+/// 16-byte keys, which are still valid keys under the new format.
+fn rekey_kv(kv: &Raw, meta: &Raw) -> Result<usize, Error> {
+    let mut moves = vec![];
+    for item in kv.iter() {
+        let item = item?;
+        let key: Vec<u8> = item.key()?;
+        let raw: Vec<u8> = item.value()?;
+        if key.len() != 16 || raw.len() < 2 {
+            continue;
+        }
+        let key_len = usize::from(u16::from_be_bytes([raw[0], raw[1]]));
+        let Some(natural) = raw.get(2..2 + key_len) else {
+            continue;
+        };
+        moves.push((key, natural.to_vec(), raw[2 + key_len..].to_vec()));
+    }
+    // The moved entries and the new format land together, so a rerun never
+    // reads an already moved value as a hashed one.
+    kv.transaction2::<_, Error, _>(meta, |kv, meta| {
+        for (hashed, natural, value) in &moves {
+            kv.remove(hashed)?;
+            kv.set(natural, value)?;
+        }
+        meta.set(&FORMAT_KEY.to_vec(), &vec![FORMAT_VARIABLE_KEYS])?;
+        Ok(())
+    })?;
+    kv.flush()?;
+    meta.flush()?;
+    Ok(moves.len())
+}
+
+/// Rewrites every value of a bucket as a current record with a fresh
+/// version, unless an earlier run already did. Plain values become
+/// persistent records; format 3 records, which start with just
+/// `expires_at: u64 BE`, keep their expiry.
+fn wrap_records(store: &Store, meta: &Raw, name: StarBucket, has_expiry: bool) -> Result<usize, Error> {
+    let marker = wrapped_key(name);
+    if meta.contains(&marker)? {
+        return Ok(0);
+    }
+    let bucket = store.bucket::<Vec<u8>, Vec<u8>>(Some(name.name()))?;
+    let mut records = vec![];
+    for item in bucket.iter() {
+        let item = item?;
+        let key: Vec<u8> = item.key()?;
+        let mut value: Vec<u8> = item.value()?;
+        let mut expires_at = 0;
+        if has_expiry && value.len() >= 8 {
+            let mut raw = [0; 8];
+            raw.copy_from_slice(&value[..8]);
+            expires_at = u64::from_be_bytes(raw);
+            value.drain(..8);
+        }
+        // sled can't hand out IDs inside a transaction, see `write_versioned`.
+        let record = Record { expires_at, version: record::next_version(store)?, value };
+        records.push((key, record.encode()));
+    }
+    bucket.transaction2::<_, Error, _>(meta, |bucket, meta| {
+        for (key, record) in &records {
+            bucket.set(key, record)?;
+        }
+        meta.set(&marker, &vec![1])?;
+        Ok(())
+    })?;
+    bucket.flush()?;
+    meta.flush()?;
+    Ok(records.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::temp_store;
+
+    use super::*;
+
+    fn bucket(store: &Store, name: &str) -> Raw {
+        store.bucket::<Vec<u8>, Vec<u8>>(Some(name)).unwrap()
+    }
+
+    fn set(store: &Store, name: &str, key: &[u8], value: &[u8]) {
+        bucket(store, name).set(&key.to_vec(), &value.to_vec()).unwrap();
+    }
+
+    fn get(store: &Store, name: &str, key: &[u8]) -> Option<Vec<u8>> {
+        bucket(store, name).get(&key.to_vec()).unwrap()
+    }
+
+    fn record(store: &Store, name: &str, key: &[u8]) -> Record {
+        Record::decode(get(store, name, key).unwrap()).unwrap()
+    }
+
+    fn set_format(store: &Store, version: u8) {
+        set(store, META_BUCKET, FORMAT_KEY, &[version]);
+    }
+
+    /// A `/kv` entry as satellite stored it before variable-length keys.
+    fn hashed_entry(key: &[u8], value: &[u8]) -> Vec<u8> {
+        let mut raw = (key.len() as u16).to_be_bytes().to_vec();
+        raw.extend_from_slice(key);
+        raw.extend_from_slice(value);
+        raw
+    }
+
+    fn expiring(expires_at: u64, value: &[u8]) -> Vec<u8> {
+        let mut raw = expires_at.to_be_bytes().to_vec();
+        raw.extend_from_slice(value);
+        raw
+    }
+
+    fn dump(store: &Store) -> Vec<(String, Vec<u8>, Vec<u8>)> {
+        let mut entries = vec![];
+        for name in StarBucket::ALL.iter().map(|bucket| bucket.name()).chain([META_BUCKET]) {
+            for item in bucket(store, name).iter() {
+                let item = item.unwrap();
+                entries.push((name.to_owned(), item.key().unwrap(), item.value().unwrap()));
+            }
+        }
+        entries
+    }
+
+    fn assert_current(store: &Store) {
+        assert_eq!(get(store, META_BUCKET, FORMAT_KEY), Some(vec![FORMAT_VERSIONS]));
+        for name in StarBucket::ALL {
+            assert_eq!(get(store, META_BUCKET, &wrapped_key(name)), None);
+        }
+    }
+
+    #[test]
+    fn format_1_store_is_rekeyed_and_wrapped() {
+        let store = temp_store("migrate-1");
+        set(&store, "kv", &[7; 16], &hashed_entry(b"greeting", b"hello"));
+        set(&store, "config", b"role:ops", b"{}");
+        run(&store).unwrap();
+
+        assert_eq!(get(&store, "kv", &[7; 16]), None);
+        let greeting = record(&store, "kv", b"greeting");
+        assert_eq!((greeting.expires_at, greeting.value.as_slice()), (0, &b"hello"[..]));
+        assert_eq!(record(&store, "config", b"role:ops").value, b"{}");
+        assert_current(&store);
+    }
+
+    #[test]
+    fn format_3_store_keeps_expiry() {
+        let store = temp_store("migrate-3");
+        set_format(&store, FORMAT_RECORDS);
+        set(&store, "session", b"s1", &expiring(4_000_000_000, b"token"));
+        set(&store, "kv", b"plain", &expiring(0, b"value"));
+        run(&store).unwrap();
+
+        let session = record(&store, "session", b"s1");
+        assert_eq!((session.expires_at, session.value.as_slice()), (4_000_000_000, &b"token"[..]));
+        let plain = record(&store, "kv", b"plain");
+        assert_eq!((plain.expires_at, plain.value.as_slice()), (0, &b"value"[..]));
+        assert!(plain.version > 0 && session.version > 0);
+        assert_current(&store);
+    }
+
+    #[test]
+    fn half_migrated_store_is_not_rekeyed_again() {
+        let store = temp_store("migrate-half");
+        // A 16-byte natural key whose value happens to parse as a hashed entry.
+        let lookalike = hashed_entry(b"x", b"payload");
+        set(&store, "kv", &[9; 16], &hashed_entry(b"moved", &lookalike));
+        rekey_kv(&bucket(&store, "kv"), &bucket(&store, META_BUCKET)).unwrap();
+        assert_eq!(get(&store, META_BUCKET, FORMAT_KEY), Some(vec![FORMAT_VARIABLE_KEYS]));
+        set(&store, "kv", &[5; 16], &lookalike);
+        run(&store).unwrap();
+
+        assert_eq!(record(&store, "kv", b"moved").value, lookalike);
+        assert_eq!(record(&store, "kv", &[5; 16]).value, lookalike);
+        assert_eq!(get(&store, "kv", b"x"), None);
+        assert_current(&store);
+    }
+
+    #[test]
+    fn running_twice_changes_nothing() {
+        let store = temp_store("migrate-twice");
+        set_format(&store, FORMAT_RECORDS);
+        set(&store, "user", b"root", &expiring(0, b"{}"));
+        set(&store, "session", b"s1", &expiring(4_000_000_000, b"token"));
+        run(&store).unwrap();
+        let first = dump(&store);
+        run(&store).unwrap();
+        assert_eq!(dump(&store), first);
+    }
+
+    #[test]
+    fn interrupted_run_skips_finished_buckets() {
+        let store = temp_store("migrate-interrupted");
+        set_format(&store, FORMAT_RECORDS);
+        set(&store, "config", b"role:ops", &expiring(4_000_000_000, b"{}"));
+        set(&store, "user", b"root", &expiring(0, b"{}"));
+        // The first bucket finished before the process stopped.
+        let meta = bucket(&store, META_BUCKET);
+        assert_eq!(wrap_records(&store, &meta, StarBucket::Config, true).unwrap(), 1);
+        let config = get(&store, "config", b"role:ops");
+        run(&store).unwrap();
+
+        assert_eq!(get(&store, "config", b"role:ops"), config);
+        let role = record(&store, "config", b"role:ops");
+        assert_eq!((role.expires_at, role.value.as_slice()), (4_000_000_000, &b"{}"[..]));
+        let root = record(&store, "user", b"root");
+        assert_eq!((root.expires_at, root.value.as_slice()), (0, &b"{}"[..]));
+        assert_current(&store);
+    }
+}
@@ -0,0 +1,85 @@
+// Envelope of every value star stores:
+// `[expires_at: u64 BE][version: u64 BE][value]`.
+//
+// `expires_at` is in unix seconds, 0 for keys that never expire. Expired
+// records stay on disk until the sweeper removes them, so every read has
+// to check `is_expired` itself. `version` only grows for a given key, and
+// is drawn from the store-wide ID generator so a re-created key does not
+// start over from 1.
+
+use std::time::SystemTime;
+use kv::Store;
+use sentinel_proto::Status;
+
+pub const HEADER_LEN: usize = 16;
+
+#[derive(Debug)]
+pub struct Record {
+    pub expires_at: u64,
+    pub version: u64,
+    pub value: Vec<u8>,
+}
+
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs()
+}
+
+/// Never 0, which CAS uses for "does not exist".
+pub fn next_version(store: &Store) -> Result<u64, kv::Error> {
+    Ok(store.generate_id()? + 1)
+}
+
+impl Record {
+    pub fn new(value: Vec<u8>, ttl: Option<u32>, version: u64) -> Record {
+        let mut record = Record { expires_at: 0, version, value };
+        record.expire(ttl);
+        record
+    }
+
+    /// Replaces the TTL; `None` makes the record persistent.
+    pub fn expire(&mut self, ttl: Option<u32>) {
+        self.expires_at = ttl.map(|ttl| now() + u64::from(ttl)).unwrap_or(0);
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at != 0 && self.expires_at <= now
+    }
+
+    /// Seconds left, `None` for persistent records.
+    pub fn ttl(&self, now: u64) -> Option<u32> {
+        if self.expires_at == 0 {
+            return None;
+        }
+        Some(u32::try_from(self.expires_at.saturating_sub(now)).unwrap_or(u32::MAX))
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(HEADER_LEN + self.value.len());
+        out.extend_from_slice(&self.expires_at.to_be_bytes());
+        out.extend_from_slice(&self.version.to_be_bytes());
+        out.extend_from_slice(&self.value);
+        out
+    }
+
+    pub fn decode(mut raw: Vec<u8>) -> Result<Record, Status> {
+        if raw.len() < HEADER_LEN {
+            return Err(Status::Malformed);
+        }
+        let value = raw.split_off(HEADER_LEN);
+        let mut expires_at = [0; 8];
+        let mut version = [0; 8];
+        expires_at.copy_from_slice(&raw[..8]);
+        version.copy_from_slice(&raw[8..]);
+        Ok(Record {
+            expires_at: u64::from_be_bytes(expires_at),
+            version: u64::from_be_bytes(version),
+            value,
+        })
+    }
+
+    /// Decodes a stored value, treating expired records as missing.
+    pub fn live(raw: Vec<u8>, now: u64) -> Result<Option<Record>, Status> {
+        let record = Record::decode(raw)?;
+        Ok(if record.is_expired(now) { None } else { Some(record) })
+    }
+}
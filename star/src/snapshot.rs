@@ -0,0 +1,416 @@
+// SNAPSHOT and `star restore`: a consistent export of every bucket to one
+// file, and rebuilding a store from it.
+//
+// SNAPSHOT is an admin command, refused unless `allow_snapshots` is set. It
+// only writes below `snapshot_dir`, and only replaces an existing file when
+// asked to.
+//
+// File layout: `[magic "STARSNAP", version u8, flags u8]`, the body
+// (deflate-compressed if flag 1 is set), then `[entries u64 BE, crc32 u32 BE]`
+// where the CRC covers every byte before it. The body lists the buckets as
+// `[name_len u8, name]`, each followed by its entries
+// `[1, key_len u32 BE, key, value_len u32 BE, value]` and a closing 0; an
+// empty name ends the list. Values are copied raw, records and all, and the
+// `meta` bucket is included, so restoring an older snapshot goes through
+// the usual migrations.
+
+use std::{
+    fmt,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Component, Path, PathBuf},
+    sync::{Arc, PoisonError, RwLock},
+};
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+use kv::{Batch, Store};
+use sentinel_proto::{
+    star::{Bucket as StarBucket, Command, Request, Response, SnapshotInfo},
+    Status,
+};
+
+use crate::{config::StarConfig, migrate::META_BUCKET};
+
+const MAGIC: &[u8; 8] = b"STARSNAP";
+const VERSION: u8 = 1;
+const COMPRESSED: u8 = 1;
+const HEADER_LEN: u64 = 10;
+const TRAILER_LEN: u64 = 12;
+/// Entries written per batch while restoring.
+const RESTORE_BATCH: usize = 1000;
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    Store(kv::Error),
+    /// Not a snapshot, or one written by a newer star.
+    Format,
+    /// The file does not match its checksum.
+    Checksum,
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Io(err) => write!(f, "i/o error: {}", err),
+            SnapshotError::Store(err) => write!(f, "store error: {:?}", err),
+            SnapshotError::Format => write!(f, "not a star snapshot"),
+            SnapshotError::Checksum => write!(f, "checksum mismatch"),
+        }
+    }
+}
+
+impl From<io::Error> for SnapshotError {
+    fn from(err: io::Error) -> Self {
+        SnapshotError::Io(err)
+    }
+}
+
+impl From<kv::Error> for SnapshotError {
+    fn from(err: kv::Error) -> Self {
+        SnapshotError::Store(err)
+    }
+}
+
+fn bucket_names() -> impl Iterator<Item = &'static str> {
+    StarBucket::ALL.into_iter().map(StarBucket::name).chain([META_BUCKET])
+}
+
+/// Counts and checksums everything written through it.
+struct Checksummed<W> {
+    inner: W,
+    hasher: crc32fast::Hasher,
+    bytes: u64,
+}
+
+impl<W: Write> Write for Checksummed<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let count = self.inner.write(buf)?;
+        self.hasher.update(&buf[..count]);
+        self.bytes += count as u64;
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn put_bytes(out: &mut impl Write, bytes: &[u8]) -> io::Result<()> {
+    // Keys and values are bounded by the frame size, far below 4 GiB.
+    out.write_all(&(bytes.len() as u32).to_be_bytes())?;
+    out.write_all(bytes)
+}
+
+fn take_bytes(input: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut len = [0; 4];
+    input.read_exact(&mut len)?;
+    let mut bytes = vec![0; u32::from_be_bytes(len) as usize];
+    input.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn write_body(store: &Store, out: &mut impl Write) -> Result<u64, SnapshotError> {
+    let mut entries = 0;
+    for name in bucket_names() {
+        let bucket = store.bucket::<Vec<u8>, Vec<u8>>(Some(name))?;
+        out.write_all(&[name.len() as u8])?;
+        out.write_all(name.as_bytes())?;
+        for item in bucket.iter() {
+            let item = item?;
+            out.write_all(&[1])?;
+            put_bytes(out, &item.key::<Vec<u8>>()?)?;
+            put_bytes(out, &item.value::<Vec<u8>>()?)?;
+            entries += 1;
+        }
+        out.write_all(&[0])?;
+    }
+    out.write_all(&[0])?;
+    Ok(entries)
+}
+
+/// Exports every bucket to `path`. The caller has to keep writers out for
+/// the snapshot to be consistent. The file is written next to `path` and
+/// renamed into place once complete.
+pub fn write(store: &Store, path: &Path, compress: bool) -> Result<SnapshotInfo, SnapshotError> {
+    let partial = path.with_file_name(format!(
+        "{}.partial",
+        path.file_name().ok_or(SnapshotError::Format)?.to_string_lossy(),
+    ));
+    let mut out = Checksummed {
+        inner: BufWriter::new(File::create(&partial)?),
+        hasher: crc32fast::Hasher::new(),
+        bytes: 0,
+    };
+    out.write_all(MAGIC)?;
+    out.write_all(&[VERSION, if compress { COMPRESSED } else { 0 }])?;
+    let entries = if compress {
+        let mut encoder = DeflateEncoder::new(&mut out, Compression::default());
+        let entries = write_body(store, &mut encoder)?;
+        encoder.finish()?;
+        entries
+    } else {
+        write_body(store, &mut out)?
+    };
+    out.write_all(&entries.to_be_bytes())?;
+
+    let checksum = out.hasher.clone().finalize();
+    let bytes = out.bytes + 4;
+    let mut file = out.inner;
+    file.write_all(&checksum.to_be_bytes())?;
+    file.into_inner().map_err(|err| err.into_error())?.sync_all()?;
+    fs::rename(&partial, path)?;
+    Ok(SnapshotInfo { entries, bytes, checksum })
+}
+
+fn verify_checksum(file: &mut File, len: u64) -> Result<(), SnapshotError> {
+    let mut hasher = crc32fast::Hasher::new();
+    let mut input = BufReader::new(file.take(len - 4));
+    let mut buf = [0; 65536];
+    loop {
+        let count = input.read(&mut buf)?;
+        if count == 0 {
+            break;
+        }
+        hasher.update(&buf[..count]);
+    }
+    let mut stored = [0; 4];
+    input.into_inner().into_inner().read_exact(&mut stored)?;
+    if hasher.finalize() != u32::from_be_bytes(stored) {
+        return Err(SnapshotError::Checksum);
+    }
+    Ok(())
+}
+
+fn read_body(store: &Store, input: &mut impl Read) -> Result<u64, SnapshotError> {
+    let mut entries = 0;
+    let mut byte = [0; 1];
+    loop {
+        input.read_exact(&mut byte)?;
+        if byte[0] == 0 {
+            return Ok(entries);
+        }
+        let mut name = vec![0; usize::from(byte[0])];
+        input.read_exact(&mut name)?;
+        let name = String::from_utf8(name).map_err(|_| SnapshotError::Format)?;
+        let bucket = store.bucket::<Vec<u8>, Vec<u8>>(Some(&name))?;
+        bucket.clear()?;
+        let mut batch = Batch::new();
+        let mut pending = 0;
+        loop {
+            input.read_exact(&mut byte)?;
+            if byte[0] == 0 {
+                break;
+            }
+            batch.set(&take_bytes(input)?, &take_bytes(input)?)?;
+            pending += 1;
+            entries += 1;
+            if pending == RESTORE_BATCH {
+                bucket.batch(std::mem::replace(&mut batch, Batch::new()))?;
+                pending = 0;
+            }
+        }
+        bucket.batch(batch)?;
+        bucket.flush()?;
+    }
+}
+
+/// Replaces the content of every bucket in the snapshot with the file's.
+/// The checksum is verified before anything is touched. Returns the number
+/// of entries restored; the caller still has to run the migrations.
+pub fn restore(store: &Store, path: &Path) -> Result<u64, SnapshotError> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    if len < HEADER_LEN + TRAILER_LEN {
+        return Err(SnapshotError::Format);
+    }
+    verify_checksum(&mut file, len)?;
+
+    file.seek(SeekFrom::Start(0))?;
+    let mut header = [0; HEADER_LEN as usize];
+    file.read_exact(&mut header)?;
+    if &header[..8] != MAGIC || header[8] != VERSION {
+        return Err(SnapshotError::Format);
+    }
+    let body = BufReader::new((&file).take(len - HEADER_LEN - TRAILER_LEN));
+    let entries = if header[9] & COMPRESSED != 0 {
+        read_body(store, &mut DeflateDecoder::new(body))?
+    } else {
+        read_body(store, &mut { body })?
+    };
+
+    let mut expected = [0; 8];
+    file.seek(SeekFrom::End(-(TRAILER_LEN as i64)))?;
+    file.read_exact(&mut expected)?;
+    if entries != u64::from_be_bytes(expected) {
+        return Err(SnapshotError::Format);
+    }
+    Ok(entries)
+}
+
+/// Maps a SNAPSHOT name to a file below `dir`. Only plain relative paths
+/// are accepted: no root, no `.` or `..`.
+pub fn resolve(dir: &Path, name: &str) -> Result<PathBuf, Status> {
+    let relative = Path::new(name);
+    let plain = relative.components().all(|component| matches!(component, Component::Normal(_)));
+    if name.is_empty() || !plain {
+        return Err(Status::BadPath);
+    }
+    Ok(dir.join(relative))
+}
+
+/// Serves a SNAPSHOT frame. Takes `gate` exclusively, so every command
+/// in flight finishes first and none starts until the file is written.
+pub fn handle(payload: &[u8], store: &Arc<RwLock<Store>>, gate: &RwLock<()>, config: &StarConfig) -> Response {
+    let cmd = Command::Snapshot;
+    let (name, compress, overwrite) = match Request::decode(payload) {
+        Ok(Request::Snapshot { name, compress, overwrite }) => (name, compress, overwrite),
+        Ok(_) => return Response::error(cmd, Status::Malformed),
+        Err(status) => return Response::error(cmd, status),
+    };
+    println!("Got: {:?}", cmd);
+    if !config.allow_snapshots {
+        println!("Refused snapshot to {:?}, allow_snapshots is off", name);
+        return Response::error(cmd, Status::Forbidden);
+    }
+    let path = match resolve(&config.snapshot_dir, &name) {
+        Ok(path) => path,
+        Err(status) => {
+            println!("Refused snapshot to {:?}: {}", name, status);
+            return Response::error(cmd, status);
+        }
+    };
+    let _writes = gate.write().unwrap_or_else(PoisonError::into_inner);
+    if !overwrite && path.symlink_metadata().is_ok() {
+        return Response::error(cmd, Status::Exists);
+    }
+    let readable = match store.read() {
+        Ok(r) => r,
+        Err(err) => {
+            println!("{:?}", err);
+            return Response::error(cmd, Status::StoreUnavailable);
+        }
+    };
+    let written = match path.parent() {
+        Some(parent) => fs::create_dir_all(parent).map_err(SnapshotError::Io),
+        None => Ok(()),
+    };
+    match written.and_then(|()| write(&readable, &path, compress)) {
+        Ok(info) => {
+            println!("Snapshot of {} entries written to {}", info.entries, path.display());
+            Response::Snapshot(info)
+        },
+        Err(err) => {
+            println!("Snapshot to {} failed: {}", path.display(), err);
+            Response::error(cmd, Status::WriteFailed)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::{shared_store, temp_path, temp_store, test_config};
+
+    use super::*;
+
+    fn dump(store: &Store) -> Vec<(&'static str, Vec<u8>, Vec<u8>)> {
+        let mut entries = vec![];
+        for name in bucket_names() {
+            let bucket = store.bucket::<Vec<u8>, Vec<u8>>(Some(name)).unwrap();
+            for item in bucket.iter() {
+                let item = item.unwrap();
+                entries.push((name, item.key().unwrap(), item.value().unwrap()));
+            }
+        }
+        entries
+    }
+
+    fn fill(store: &Store) {
+        for (i, star_bucket) in StarBucket::ALL.into_iter().enumerate() {
+            let bucket = store.bucket::<Vec<u8>, Vec<u8>>(Some(star_bucket.name())).unwrap();
+            for n in 0..(i * 200) as u32 {
+                let value: Vec<u8> = (0..n % 300).map(|b| (b * 7 + n) as u8).collect();
+                bucket.set(&n.to_be_bytes().to_vec(), &value).unwrap();
+            }
+        }
+        let meta = store.bucket::<Vec<u8>, Vec<u8>>(Some(META_BUCKET)).unwrap();
+        meta.set(&b"format".to_vec(), &vec![4]).unwrap();
+    }
+
+    #[test]
+    fn round_trip() {
+        let source = temp_store("source");
+        fill(&source);
+        for compress in [false, true] {
+            let path = temp_path("snapshot");
+            let info = write(&source, &path, compress).unwrap();
+            assert_eq!(info.bytes, fs::metadata(&path).unwrap().len());
+
+            let target = temp_store("target");
+            let stale = target.bucket::<Vec<u8>, Vec<u8>>(Some(StarBucket::Kv.name())).unwrap();
+            stale.set(&b"stale".to_vec(), &b"gone after restore".to_vec()).unwrap();
+            assert_eq!(restore(&target, &path).unwrap(), info.entries);
+            assert_eq!(dump(&target), dump(&source));
+            assert_eq!(dump(&target).len() as u64, info.entries);
+            fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn rejects_corrupt_file_untouched() {
+        let source = temp_store("source");
+        fill(&source);
+        let path = temp_path("snapshot");
+        write(&source, &path, true).unwrap();
+        let mut raw = fs::read(&path).unwrap();
+        let middle = raw.len() / 2;
+        raw[middle] ^= 0xFF;
+        fs::write(&path, raw).unwrap();
+
+        let target = temp_store("target");
+        let kv = target.bucket::<Vec<u8>, Vec<u8>>(Some(StarBucket::Kv.name())).unwrap();
+        kv.set(&b"kept".to_vec(), &b"1".to_vec()).unwrap();
+        assert!(matches!(restore(&target, &path), Err(SnapshotError::Checksum)));
+        assert_eq!(dump(&target).len(), 1);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn names_stay_inside_the_directory() {
+        let dir = Path::new("/var/lib/star/snapshots");
+        assert_eq!(resolve(dir, "nightly.snap"), Ok(dir.join("nightly.snap")));
+        assert_eq!(resolve(dir, "daily/monday.snap"), Ok(dir.join("daily/monday.snap")));
+        for name in ["", ".", "/etc/passwd", "../start.bin", "daily/../../start.bin", "./nightly.snap"] {
+            assert_eq!(resolve(dir, name), Err(Status::BadPath), "{:?}", name);
+        }
+    }
+
+    fn request(name: &str, overwrite: bool) -> Vec<u8> {
+        Request::Snapshot { name: name.to_owned(), compress: false, overwrite }.encode().unwrap()
+    }
+
+    #[test]
+    fn snapshots_are_off_unless_allowed() {
+        let store = shared_store("snapshot-off");
+        let gate = RwLock::new(());
+        let config = test_config();
+        let response = handle(&request("nightly.snap", false), &store, &gate, &config);
+        assert_eq!(response, Response::error(Command::Snapshot, Status::Forbidden));
+        assert!(!config.snapshot_dir.exists());
+    }
+
+    #[test]
+    fn existing_files_need_overwrite() {
+        let store = shared_store("snapshot-overwrite");
+        let gate = RwLock::new(());
+        let config = StarConfig { allow_snapshots: true, ..test_config() };
+        let first = handle(&request("daily/nightly.snap", false), &store, &gate, &config);
+        assert!(matches!(first, Response::Snapshot(_)));
+        let again = handle(&request("daily/nightly.snap", false), &store, &gate, &config);
+        assert_eq!(again, Response::error(Command::Snapshot, Status::Exists));
+        let replaced = handle(&request("daily/nightly.snap", true), &store, &gate, &config);
+        assert!(matches!(replaced, Response::Snapshot(_)));
+        let escape = handle(&request("../escape.snap", true), &store, &gate, &config);
+        assert_eq!(escape, Response::error(Command::Snapshot, Status::BadPath));
+        fs::remove_dir_all(&config.snapshot_dir).unwrap();
+    }
+}
@@ -0,0 +1,257 @@
+// TXN: a batch of ops over up to three buckets, applied all-or-nothing
+// through the kv crate's (sled's) multi-tree transactions. MGET, MSET and
+// MDEL are served as single-bucket TXNs.
+//
+// Answers are not paged. One that would not fit in a frame goes out as
+// `Status::TooLarge` instead, and the client splits the batch.
+
+use std::sync::{Arc, RwLock};
+use kv::{Bucket, Store, Transaction, TransactionError};
+use sentinel_proto::{
+    star::{item_fits, Bucket as StarBucket, Command, KeyValMap, Request, Response, TxnOp, TxnOutcome, TxnResult, MAX_TXN_BUCKETS},
+    Status,
+};
+
+use crate::{check_key, flush, get_bucket, record::{self, Record}};
+
+type Txn<'a, 'b> = Transaction<'a, 'b, Vec<u8>, Vec<u8>>;
+
+enum Abort {
+    /// A CHECK failed; carries the results up to and including it.
+    Check(Vec<TxnResult>),
+    Store(kv::Error),
+}
+
+impl From<kv::Error> for Abort {
+    fn from(err: kv::Error) -> Self {
+        Abort::Store(err)
+    }
+}
+
+fn lift(err: TransactionError<kv::Error>) -> TransactionError<Abort> {
+    match err {
+        TransactionError::Abort(err) => TransactionError::Abort(Abort::Store(err)),
+        TransactionError::Storage(err) => TransactionError::Storage(err),
+    }
+}
+
+fn live_version(txn: &Txn, key: &Vec<u8>) -> Result<Option<Record>, TransactionError<Abort>> {
+    Ok(txn.get(key).map_err(lift)?.and_then(|raw| Record::live(raw, record::now()).ok().flatten()))
+}
+
+/// Runs the ops against the open transactions. `slots[i]` is the index in
+/// `txns` of op `i`'s bucket and `fresh[i]` a pre-drawn version for SETs.
+/// sled may call this more than once if the transaction has to be retried.
+fn apply(ops: &[TxnOp], slots: &[usize], fresh: &[u64], txns: &[&Txn]) -> Result<Vec<TxnResult>, TransactionError<Abort>> {
+    let mut results = Vec::with_capacity(ops.len());
+    for (i, op) in ops.iter().enumerate() {
+        let txn = txns[slots[i]];
+        let key = op.key().to_vec();
+        let result = match op {
+            TxnOp::Get { .. } => match live_version(txn, &key)? {
+                Some(record) => TxnResult::Get { version: record.version, value: Some(record.value) },
+                None => TxnResult::Get { version: 0, value: None },
+            },
+            TxnOp::Check { version, .. } => {
+                let current = live_version(txn, &key)?.map(|record| record.version).unwrap_or(0);
+                let ok = current == *version;
+                results.push(TxnResult::Check { version: current, ok });
+                if !ok {
+                    return Err(TransactionError::Abort(Abort::Check(results)));
+                }
+                continue;
+            },
+            TxnOp::Set { value, ttl, .. } => {
+                let current = live_version(txn, &key)?.map(|record| record.version).unwrap_or(0);
+                let version = fresh[i].max(current + 1);
+                txn.set(&key, &Record::new(value.clone(), *ttl, version).encode()).map_err(lift)?;
+                TxnResult::Set { version }
+            },
+            TxnOp::Del { .. } => {
+                let existed = live_version(txn, &key)?.is_some();
+                txn.remove(&key).map_err(lift)?;
+                TxnResult::Del { existed }
+            },
+        };
+        results.push(result);
+    }
+    Ok(results)
+}
+
+fn execute(ops: Vec<TxnOp>, store: &Arc<RwLock<Store>>, max_key_len: usize) -> Result<TxnOutcome, Status> {
+    let mut names: Vec<StarBucket> = vec![];
+    let mut slots = Vec::with_capacity(ops.len());
+    for op in &ops {
+        check_key(op.key(), max_key_len)?;
+        if let TxnOp::Set { key, value, .. } = op {
+            if !item_fits(key, value) {
+                return Err(Status::TooLarge);
+            }
+        }
+        let slot = match names.iter().position(|name| *name == op.bucket()) {
+            Some(slot) => slot,
+            None => {
+                names.push(op.bucket());
+                names.len() - 1
+            },
+        };
+        slots.push(slot);
+    }
+    if names.len() > MAX_TXN_BUCKETS {
+        return Err(Status::TooManyBuckets);
+    }
+    if ops.is_empty() {
+        return Ok(TxnOutcome { committed: true, results: vec![] });
+    }
+
+    let mut buckets: Vec<Bucket<Vec<u8>, Vec<u8>>> = vec![];
+    for name in &names {
+        buckets.push(get_bucket(*name, store)?);
+    }
+    // sled can't hand out IDs inside a transaction, see `write_versioned`.
+    let fresh = match store.read() {
+        Ok(readable) => {
+            let mut fresh = Vec::with_capacity(ops.len());
+            for op in &ops {
+                let version = match op {
+                    TxnOp::Set { .. } => record::next_version(&readable),
+                    _ => Ok(0),
+                };
+                match version {
+                    Ok(version) => fresh.push(version),
+                    Err(err) => {
+                        println!("{:?}", err);
+                        return Err(Status::WriteFailed);
+                    }
+                }
+            }
+            fresh
+        },
+        Err(err) => {
+            println!("{:?}", err);
+            return Err(Status::StoreUnavailable);
+        }
+    };
+
+    let outcome = match buckets.as_slice() {
+        [a] => a.transaction(|a| apply(&ops, &slots, &fresh, &[&a])),
+        [a, b] => a.transaction2(b, |a, b| apply(&ops, &slots, &fresh, &[&a, &b])),
+        [a, b, c] => a.transaction3(b, c, |a, b, c| apply(&ops, &slots, &fresh, &[&a, &b, &c])),
+        _ => unreachable!("bucket count is checked above"),
+    };
+    match outcome {
+        Ok(results) => {
+            for bucket in &buckets {
+                flush(bucket);
+            }
+            Ok(TxnOutcome { committed: true, results })
+        },
+        Err(Abort::Check(results)) => Ok(TxnOutcome { committed: false, results }),
+        Err(Abort::Store(err)) => {
+            println!("{:?}", err);
+            Err(Status::WriteFailed)
+        }
+    }
+}
+
+pub fn run(ops: Vec<TxnOp>, store: &Arc<RwLock<Store>>, max_key_len: usize) -> Response {
+    match execute(ops, store, max_key_len) {
+        Ok(outcome) => Response::Txn(outcome),
+        Err(status) => Response::error(Command::Txn, status),
+    }
+}
+
+/// MGET, MSET and MDEL run as a single-bucket TXN, so a batch reads one
+/// consistent state or is written all at once, with a single flush.
+pub fn batch(request: Request, store: &Arc<RwLock<Store>>, max_key_len: usize) -> Response {
+    let cmd = request.command();
+    let ops: Vec<TxnOp> = match request {
+        Request::MGet { bucket, keys } => keys.into_iter().map(|key| TxnOp::Get { bucket, key }).collect(),
+        Request::MSet { bucket, items, ttl } => items.into_iter()
+            .map(|(key, value)| TxnOp::Set { bucket, key, value, ttl })
+            .collect(),
+        Request::MDel { bucket, keys } => keys.into_iter().map(|key| TxnOp::Del { bucket, key }).collect(),
+        _ => return Response::error(cmd, Status::UnknownCommand),
+    };
+    let keys: Vec<Vec<u8>> = match cmd {
+        Command::MGet => ops.iter().map(|op| op.key().to_vec()).collect(),
+        _ => vec![],
+    };
+    let results = match execute(ops, store, max_key_len) {
+        Ok(outcome) => outcome.results,
+        Err(status) => return Response::error(cmd, status),
+    };
+    match cmd {
+        Command::MGet => Response::MGet(results.into_iter().zip(keys).map(|(result, key)| match result {
+            TxnResult::Get { version, value: Some(val) } => Some(KeyValMap { key, val, version }),
+            _ => None,
+        }).collect()),
+        Command::MSet => Response::MSet(results.into_iter().map(|result| match result {
+            TxnResult::Set { version } => version,
+            _ => 0,
+        }).collect()),
+        _ => Response::MDel(results.into_iter().map(|result| matches!(result, TxnResult::Del { existed: true })).collect()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::{send, shared_store, MAX_KEY_LEN};
+
+    use super::*;
+
+    fn get(store: &Arc<RwLock<Store>>, bucket: StarBucket, key: &[u8]) -> Response {
+        send(store, Request::Get { bucket, key: key.to_vec() })
+    }
+
+    #[test]
+    fn failed_check_leaves_every_bucket_untouched() {
+        let store = shared_store("txn");
+        let setup = vec![
+            TxnOp::Set { bucket: StarBucket::Kv, key: b"stock".to_vec(), value: b"5".to_vec(), ttl: None },
+            TxnOp::Set { bucket: StarBucket::Config, key: b"limit".to_vec(), value: b"10".to_vec(), ttl: None },
+            TxnOp::Set { bucket: StarBucket::User, key: b"root".to_vec(), value: b"{}".to_vec(), ttl: None },
+        ];
+        let Response::Txn(outcome) = run(setup, &store, MAX_KEY_LEN) else {
+            panic!("setup TXN failed");
+        };
+        assert!(outcome.committed);
+        let before = [
+            get(&store, StarBucket::Kv, b"stock"),
+            get(&store, StarBucket::Config, b"limit"),
+            get(&store, StarBucket::User, b"root"),
+        ];
+
+        let ops = vec![
+            TxnOp::Set { bucket: StarBucket::Kv, key: b"stock".to_vec(), value: b"4".to_vec(), ttl: None },
+            TxnOp::Set { bucket: StarBucket::Config, key: b"new".to_vec(), value: b"1".to_vec(), ttl: None },
+            TxnOp::Del { bucket: StarBucket::User, key: b"root".to_vec() },
+            TxnOp::Check { bucket: StarBucket::Kv, key: b"missing".to_vec(), version: 1 },
+            TxnOp::Del { bucket: StarBucket::Config, key: b"limit".to_vec() },
+        ];
+        let Response::Txn(outcome) = run(ops, &store, MAX_KEY_LEN) else {
+            panic!("TXN failed");
+        };
+        assert!(!outcome.committed);
+        assert_eq!(outcome.results.len(), 4);
+        assert_eq!(outcome.results[3], TxnResult::Check { version: 0, ok: false });
+
+        let after = [
+            get(&store, StarBucket::Kv, b"stock"),
+            get(&store, StarBucket::Config, b"limit"),
+            get(&store, StarBucket::User, b"root"),
+        ];
+        assert_eq!(after, before);
+        assert_eq!(get(&store, StarBucket::Config, b"new"), Response::error(Command::Get, Status::NotFound));
+    }
+
+    #[test]
+    fn more_than_three_buckets_are_refused() {
+        let store = shared_store("txn-buckets");
+        let ops = [StarBucket::Kv, StarBucket::Config, StarBucket::User, StarBucket::Group]
+            .into_iter()
+            .map(|bucket| TxnOp::Get { bucket, key: b"k".to_vec() })
+            .collect();
+        assert_eq!(run(ops, &store, MAX_KEY_LEN), Response::error(Command::Txn, Status::TooManyBuckets));
+    }
+}
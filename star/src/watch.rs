@@ -0,0 +1,173 @@
+// SUBSCRIBE: hands the connection over to a sled watch and streams change
+// events until the client hangs up.
+//
+// Subscriptions run on threads of their own, not on the connection
+// workers, so long-lived watchers never starve regular clients. At most
+// `max_subscriptions` run at once; past that SUBSCRIBE answers `Busy`.
+//
+// Expired keys only produce a `del` event once the sweeper removes them.
+
+use std::{
+    io::{ErrorKind, Read, Write},
+    net,
+    os::unix::net::UnixStream,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::RecvTimeoutError,
+        Arc, RwLock,
+    },
+    thread,
+    time::Duration,
+};
+use kv::{Event, Store};
+use sentinel_proto::{
+    daemon::{ClientError, Shutdown},
+    frame::Frame,
+    star::{ChangeEvent, ChangeOp, Command, Request, Response},
+    Status,
+};
+
+use crate::{check_key, get_bucket, record::Record};
+
+/// How often an idle subscription checks whether the client is still there.
+const PEER_CHECK: Duration = Duration::from_secs(1);
+
+fn send(stream: &mut UnixStream, request_id: u32, resp: Response) -> Result<(), ClientError> {
+    stream
+        .write_all(&Frame::encode_response(request_id, resp.encode()))
+        .map_err(ClientError::Write)
+}
+
+/// A subscribed client only listens, so anything but "would block" means
+/// it either hung up or broke the protocol.
+fn peer_alive(stream: &mut UnixStream) -> bool {
+    if stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let alive = matches!(stream.read(&mut [0; 1]), Err(err) if err.kind() == ErrorKind::WouldBlock);
+    alive && stream.set_nonblocking(false).is_ok()
+}
+
+fn to_event(event: Event<Vec<u8>, Vec<u8>>) -> Option<ChangeEvent> {
+    match event {
+        Event::Set(item) => {
+            let key: Vec<u8> = item.key().ok()?;
+            let raw: Vec<u8> = item.value().ok()?;
+            let version = Record::decode(raw).map(|record| record.version).unwrap_or(0);
+            Some(ChangeEvent { key, op: ChangeOp::Set, version })
+        },
+        Event::Remove(key) => Some(ChangeEvent { key: key.to_vec(), op: ChangeOp::Del, version: 0 }),
+    }
+}
+
+pub struct Watchers {
+    active: AtomicUsize,
+    max: usize,
+    shutdown: Arc<Shutdown>,
+}
+
+/// Holds one of the `Watchers` slots until dropped.
+struct Slot(Arc<Watchers>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Watchers {
+    pub fn new(max: usize, shutdown: Arc<Shutdown>) -> Arc<Watchers> {
+        Arc::new(Watchers { active: AtomicUsize::new(0), max, shutdown })
+    }
+
+    /// Takes over the connection a SUBSCRIBE `frame` arrived on and serves
+    /// it on a new thread, which shutdown waits for like for any client.
+    pub fn spawn(self: &Arc<Self>, mut conn: UnixStream, frame: Frame, store: &Arc<RwLock<Store>>, max_key_len: usize) {
+        if self.active.fetch_add(1, Ordering::Relaxed) >= self.max {
+            self.active.fetch_sub(1, Ordering::Relaxed);
+            println!("Refused subscription, {} already running", self.max);
+            let _ = send(&mut conn, frame.request_id, Response::error(Command::Subscribe, Status::Busy));
+            let _ = conn.shutdown(net::Shutdown::Both);
+            return;
+        }
+        let slot = Slot(Arc::clone(self));
+        let store = Arc::clone(store);
+        let spawned = thread::Builder::new().name("star-watch".to_owned()).spawn(move || {
+            let client = match slot.0.shutdown.track(&conn) {
+                Ok(client) => client,
+                Err(err) => {
+                    println!("Failed to track subscriber: {}", err);
+                    return;
+                }
+            };
+            if let Err(err) = stream(&mut conn, frame.request_id, &frame.payload, &store, max_key_len) {
+                println!("Subscription ended: {}", err);
+            }
+            let _ = conn.shutdown(net::Shutdown::Both);
+            drop(client);
+            drop(slot);
+        });
+        // On failure the closure is dropped, closing the connection and
+        // freeing the slot.
+        if let Err(err) = spawned {
+            println!("Failed to start subscription thread: {}", err);
+        }
+    }
+}
+
+/// Serves a SUBSCRIBE frame. Returns once the subscription ends; the
+/// connection is not usable for other commands afterwards.
+fn stream(
+    stream: &mut UnixStream,
+    request_id: u32,
+    payload: &[u8],
+    store: &Arc<RwLock<Store>>,
+    max_key_len: usize,
+) -> Result<(), ClientError> {
+    let cmd = Command::Subscribe;
+    let (bucket, key, exact) = match Request::decode(payload) {
+        Ok(Request::Subscribe { bucket, key, exact }) => (bucket, key, exact),
+        Ok(_) => return Ok(()),
+        Err(status) => return send(stream, request_id, Response::error(cmd, status)),
+    };
+    if exact {
+        if let Err(status) = check_key(&key, max_key_len) {
+            return send(stream, request_id, Response::error(cmd, status));
+        }
+    }
+    let watch = get_bucket::<Vec<u8>, Vec<u8>>(bucket, store)
+        .and_then(|b| b.watch_prefix(Some(&key)).map_err(|err| {
+            println!("{:?}", err);
+            Status::ReadFailed
+        }));
+    let mut watch = match watch {
+        Ok(watch) => watch,
+        Err(status) => return send(stream, request_id, Response::error(cmd, status)),
+    };
+    send(stream, request_id, Response::Subscribed)?;
+    println!("Subscribed to {} {:?}", bucket.name(), String::from_utf8_lossy(&key));
+
+    let ended = loop {
+        match watch.next_timeout(PEER_CHECK) {
+            Ok(event) => {
+                let Some(event) = to_event(event) else {
+                    continue;
+                };
+                if exact && event.key != key {
+                    continue;
+                }
+                if let Err(err) = send(stream, request_id, Response::Event(event)) {
+                    break Err(err);
+                }
+            },
+            Err(RecvTimeoutError::Timeout) => {
+                if !peer_alive(stream) {
+                    break Ok(());
+                }
+            },
+            Err(RecvTimeoutError::Disconnected) => break Ok(()),
+        }
+    };
+    println!("Unsubscribed from {}", bucket.name());
+    ended
+}
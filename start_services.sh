@@ -1,12 +1,5 @@
 #!/bin/bash
 
-# Check the environment variable
-if [[ -z "$JWT_SECRET" ]]; then
-    # If not set, generate a random 64-character string
-    JWT_SECRET=$(openssl rand -base64 64)
-    export JWT_SECRET
-fi
-
 # Function to start a service
 start_service() {
     directory="$1"
@@ -0,0 +1,109 @@
+// Store's `[store]` table of the sentinel config, see
+// `sentinel_proto::config` for where it is read from.
+
+use std::{collections::BTreeMap, path::PathBuf, time::Duration};
+use serde::{Deserialize, Serialize};
+use sentinel_proto::config::{ensure_parent, require_positive, ConfigError, Settings};
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+struct Section {
+    /// sled database directory for statements.
+    data: PathBuf,
+    /// SQLite database file.
+    database: PathBuf,
+    read_timeout_secs: u64,
+    transaction_timeout_secs: u64,
+    buffer_size: usize,
+    shutdown_timeout_secs: u64,
+    max_connections: usize,
+    max_queued: usize,
+    metrics_secs: u64,
+    /// Tables DEFINE accepts statements over, by role.
+    tables: BTreeMap<String, Vec<String>>,
+    /// Directory of `<version>_<name>.up.sql` and `.down.sql` files.
+    migrations: PathBuf,
+    migrate_on_start: bool,
+}
+
+impl Default for Section {
+    fn default() -> Self {
+        Section {
+            data: PathBuf::from("store.bin"),
+            database: PathBuf::from("store.db"),
+            read_timeout_secs: 30,
+            transaction_timeout_secs: 10,
+            buffer_size: 65536,
+            shutdown_timeout_secs: 10,
+            max_connections: 64,
+            max_queued: 64,
+            metrics_secs: 60,
+            tables: BTreeMap::new(),
+            migrations: PathBuf::from("migrations"),
+            migrate_on_start: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct StoreConfig {
+    pub socket: PathBuf,
+    pub data: PathBuf,
+    pub database: PathBuf,
+    /// Idle clients are dropped after this long.
+    pub read_timeout: Duration,
+    /// Clients idle this long with a transaction open are dropped and the
+    /// transaction rolled back.
+    pub transaction_timeout: Duration,
+    /// Bytes read from a client socket at once.
+    pub buffer_size: usize,
+    /// How long connected clients get to finish on shutdown.
+    pub shutdown_timeout: Duration,
+    /// Connections served at once, one worker thread each.
+    pub max_connections: usize,
+    /// Connections waiting for a worker before new ones are refused.
+    pub max_queued: usize,
+    /// How often connection metrics are printed.
+    pub metrics_interval: Duration,
+    /// Tables DEFINE accepts statements over, by role. A role not listed
+    /// cannot define anything.
+    pub tables: BTreeMap<String, Vec<String>>,
+    pub migrations: PathBuf,
+    /// Applies pending migrations at startup. Otherwise they are only
+    /// listed, and `store migrate` applies them.
+    pub migrate_on_start: bool,
+}
+
+impl StoreConfig {
+    pub fn load() -> Result<StoreConfig, ConfigError> {
+        let settings = Settings::load()?;
+        let section: Section = settings.section("store")?;
+        require_positive("store", "read_timeout_secs", section.read_timeout_secs)?;
+        require_positive("store", "transaction_timeout_secs", section.transaction_timeout_secs)?;
+        require_positive("store", "buffer_size", section.buffer_size as u64)?;
+        require_positive("store", "max_connections", section.max_connections as u64)?;
+        require_positive("store", "metrics_secs", section.metrics_secs)?;
+        Ok(StoreConfig {
+            socket: settings.socket("store")?,
+            data: settings.path(&section.data),
+            database: settings.path(&section.database),
+            read_timeout: Duration::from_secs(section.read_timeout_secs),
+            transaction_timeout: Duration::from_secs(section.transaction_timeout_secs),
+            buffer_size: section.buffer_size,
+            shutdown_timeout: Duration::from_secs(section.shutdown_timeout_secs),
+            max_connections: section.max_connections,
+            max_queued: section.max_queued,
+            metrics_interval: Duration::from_secs(section.metrics_secs),
+            tables: section.tables,
+            migrations: settings.path(&section.migrations),
+            migrate_on_start: section.migrate_on_start,
+        })
+    }
+
+    /// Creates the directories the socket and both databases live in.
+    pub fn create_dirs(&self) -> Result<(), ConfigError> {
+        ensure_parent(&self.socket)?;
+        ensure_parent(&self.data)?;
+        ensure_parent(&self.database)
+    }
+}
@@ -0,0 +1,129 @@
+// EXEC: runs a registered statement on a pooled SQLite connection, or on
+// the client's own one while it has a transaction open. The statement's
+// tables were checked for the role it was registered with, so it only runs
+// for callers holding that role.
+//
+// Every parameter in the SQL must be a named one (`:var`, `@var` or `$var`)
+// for a declared variable; values are bound by declaration order.
+
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::{types::{Value, ValueRef}, Statement as Prepared};
+use sentinel_proto::{
+    store::{Command, ExecResult, Response, SqlValue, Statement},
+    Key, Status,
+};
+
+use crate::{
+    statements::{self, Statements},
+    transaction::Transaction,
+};
+
+fn to_sql(value: SqlValue) -> Value {
+    match value {
+        SqlValue::Null => Value::Null,
+        SqlValue::Integer(value) => Value::Integer(value),
+        SqlValue::Real(value) => Value::Real(value),
+        SqlValue::Text(text) => Value::Text(text),
+        SqlValue::Blob(blob) => Value::Blob(blob),
+    }
+}
+
+fn from_sql(value: ValueRef) -> SqlValue {
+    match value {
+        ValueRef::Null => SqlValue::Null,
+        ValueRef::Integer(value) => SqlValue::Integer(value),
+        ValueRef::Real(value) => SqlValue::Real(value),
+        ValueRef::Text(text) => SqlValue::Text(String::from_utf8_lossy(text).into_owned()),
+        ValueRef::Blob(blob) => SqlValue::Blob(blob.to_vec()),
+    }
+}
+
+fn failed(err: rusqlite::Error) -> Status {
+    println!("{}", err);
+    Status::QueryFailed
+}
+
+fn bind(prepared: &mut Prepared, statement: &Statement, params: Vec<SqlValue>) -> Result<(), Status> {
+    let mut params: Vec<Option<SqlValue>> = params.into_iter().map(Some).collect();
+    for index in 1..=prepared.parameter_count() {
+        // Positional `?` parameters have no name and cannot be bound.
+        let Some(name) = prepared.parameter_name(index) else {
+            println!("Statement {} has a positional parameter", statement.name);
+            return Err(Status::Malformed);
+        };
+        let Some(var) = statement.vars.iter().position(|var| *var == name[1..]) else {
+            println!("Statement {} uses undeclared {}", statement.name, name);
+            return Err(Status::Malformed);
+        };
+        // SQLite gives every distinct name one index, so each value is
+        // taken once.
+        let value = params[var].take().unwrap_or(SqlValue::Null);
+        prepared.raw_bind_parameter(index, to_sql(value)).map_err(failed)?;
+    }
+    Ok(())
+}
+
+fn run(prepared: &mut Prepared, statement: &Statement, params: Vec<SqlValue>) -> Result<ExecResult, Status> {
+    bind(prepared, statement, params)?;
+    if prepared.column_count() == 0 {
+        let changes = prepared.raw_execute().map_err(failed)?;
+        return Ok(ExecResult::Changes(changes as u64));
+    }
+    let columns: Vec<String> = prepared.column_names().into_iter().map(String::from).collect();
+    let mut rows = Vec::new();
+    let mut cursor = prepared.raw_query();
+    while let Some(row) = cursor.next().map_err(failed)? {
+        let mut values = Vec::with_capacity(columns.len());
+        for column in 0..columns.len() {
+            values.push(from_sql(row.get_ref(column).map_err(failed)?));
+        }
+        rows.push(values);
+    }
+    Ok(ExecResult::Rows { columns, rows })
+}
+
+pub fn exec(
+    bucket: &Statements,
+    pool: &Pool<SqliteConnectionManager>,
+    tx: Option<&Transaction>,
+    id: Key,
+    roles: &[String],
+    params: Vec<SqlValue>,
+) -> Response {
+    let cmd = Command::Exec;
+    let statement = match statements::get(bucket, id) {
+        Ok(statement) => statement,
+        Err(status) => return Response::error(cmd, status),
+    };
+    if !roles.contains(&statement.role) {
+        println!("Refused {}: caller lacks role {}", statement.name, statement.role);
+        return Response::error(cmd, Status::Forbidden);
+    }
+    if params.len() != statement.vars.len() {
+        return Response::error(cmd, Status::BadLength);
+    }
+    let pooled;
+    let conn = match tx {
+        Some(tx) => tx.conn(),
+        None => match pool.get() {
+            Ok(conn) => {
+                pooled = conn;
+                &*pooled
+            },
+            Err(err) => {
+                println!("{}", err);
+                return Response::error(cmd, Status::StoreUnavailable);
+            },
+        },
+    };
+    let mut prepared = match conn.prepare_cached(&statement.statement) {
+        Ok(prepared) => prepared,
+        Err(err) => return Response::error(cmd, failed(err)),
+    };
+    println!("Executing {}", statement.name);
+    match run(&mut prepared, &statement, params) {
+        Ok(result) => Response::Exec(result),
+        Err(status) => Response::error(cmd, status),
+    }
+}
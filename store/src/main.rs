@@ -1,38 +1,41 @@
-#![feature(box_into_inner)]
-
 use std::{
     fs,
-    thread,
-    io::{Write, Read},
+    io::{self, Write, Read},
+    net,
     path::Path,
-    time::Duration,
+    process,
     sync::{Arc, RwLock},
-    os::unix::net::{UnixStream, UnixListener, SocketAddr}
+    os::unix::net::{UnixStream, SocketAddr}
 };
 use r2d2_sqlite::SqliteConnectionManager;
 use r2d2::Pool;
-use serde::{Deserialize, Serialize};
+use rusqlite::Connection;
 use kv::{Config, Store, Bucket, Value, Key};
+use sentinel_proto::{
+    daemon::{self, ClientError, Shutdown, WorkerPool},
+    frame::{Frame, FrameDecoder},
+    store::{Request, Response},
+    Status,
+};
+use config::StoreConfig;
+use migrate::MigrationError;
+use policy::Policy;
+use transaction::{IdleTransaction, Transaction};
 
-#[derive(Debug, Serialize, Deserialize)]
-struct StatementVariables {
-    key: String,
-    value: String,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct ReceivedStatement {
-    statement: String,
-    vars: Vec<String>,
-}
+mod config;
+mod exec;
+mod migrate;
+mod policy;
+mod statements;
+mod transaction;
 
-fn get_bucket<'a, K: Key<'a>, V: Value>(store: &Arc<RwLock<Store>>) -> Result<Bucket<'a, K, V>, u8> {
+fn get_bucket<'a, K: Key<'a>, V: Value>(store: &Arc<RwLock<Store>>) -> Result<Bucket<'a, K, V>, Status> {
     let readable = match store.read() {
         Ok(r) => r,
         Err(err) => {
             println!("{:?}", err);
             println!("Failed to get readable!");
-            return Err(130);
+            return Err(Status::StoreUnavailable);
         }
     };
     match readable.bucket::<K, V>(Some("Default")) {
@@ -40,111 +43,261 @@ fn get_bucket<'a, K: Key<'a>, V: Value>(store: &Arc<RwLock<Store>>) -> Result<Bu
         Err(err) => {
             println!("{:?}", err);
             println!("Failed to get bucket!");
-            return Err(131);
+            Err(Status::BucketUnavailable)
         }
     }
-} 
+}
 
-fn handle_client(mut stream: UnixStream, _pool: Pool<SqliteConnectionManager>, store: Arc<RwLock<Store>>) {
+fn handle_command(
+    buf: &[u8],
+    store: &Arc<RwLock<Store>>,
+    pool: &Pool<SqliteConnectionManager>,
+    policy: &Policy,
+    tx: &mut Option<Transaction>,
+) -> Response {
+    let request = match Request::decode(buf) {
+        Ok(request) => request,
+        Err(status) => {
+            println!("Bad request: {}", status);
+            return Response::Error { cmd: buf.first().copied().unwrap_or(0), status };
+        }
+    };
+    let cmd = request.command();
+    println!("Got: {:?}", cmd);
+    let bucket = match get_bucket::<Vec<u8>, Vec<u8>>(store) {
+        Ok(b) => b,
+        Err(status) => {
+            return Response::error(cmd, status);
+        }
+    };
+    match request {
+        Request::Define { name, role, statement, vars } => statements::define(&bucket, policy, name, role, statement, vars),
+        Request::List => statements::list(&bucket),
+        Request::Drop { id } => statements::drop(&bucket, id),
+        Request::Exec { id, roles, params } => exec::exec(&bucket, pool, tx.as_ref(), id, &roles, params),
+        Request::Begin => transaction::begin(pool, tx),
+        Request::Commit => transaction::commit(tx),
+        Request::Rollback => transaction::rollback(tx),
+    }
+}
+
+fn handle_client(
+    mut stream: UnixStream,
+    pool: Pool<SqliteConnectionManager>,
+    store: Arc<RwLock<Store>>,
+    policy: Arc<Policy>,
+    config: Arc<StoreConfig>,
+) {
     let addr = match stream.peer_addr() {
         Ok(addr) => addr,
         Err(_) => SocketAddr::from_pathname("/unkwonw").unwrap()
     };
     println!("Incomming: {:?}", addr);
+    if let Err(err) = serve(&mut stream, &pool, &store, &policy, &config) {
+        println!("Closing {:?}: {}", addr, err);
+    }
+    let _ = stream.shutdown(net::Shutdown::Both);
+    println!("DONE! {:?}", addr);
+}
+
+/// Answers frames until the client hangs up. A transaction still open
+/// when this returns is rolled back.
+fn serve(
+    stream: &mut UnixStream,
+    pool: &Pool<SqliteConnectionManager>,
+    store: &Arc<RwLock<Store>>,
+    policy: &Policy,
+    config: &StoreConfig,
+) -> Result<(), ClientError<IdleTransaction>> {
+    let mut decoder = FrameDecoder::default();
+    let mut buf = vec![0; config.buffer_size];
+    let mut tx = None;
+    let mut timeout = config.read_timeout;
     loop {
-        let mut buf: [u8; 1048594] = [0; 1048594];
+        // An open transaction holds SQLite's write lock, so its client gets
+        // less time to go quiet.
+        let wanted = if tx.is_some() { config.transaction_timeout } else { config.read_timeout };
+        if wanted != timeout {
+            stream.set_read_timeout(Some(wanted)).map_err(ClientError::Read)?;
+            timeout = wanted;
+        }
         let count = match stream.read(&mut buf) {
-            Ok(size) => size,
-            Err(err) => {
-                println!("Error on read!");
-                println!("{:?}", err.to_string());
-                break;
-            }
+            Ok(count) => count,
+            Err(err) if tx.is_some() && matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                return Err(ClientError::Daemon(IdleTransaction(timeout)));
+            },
+            Err(err) => return Err(ClientError::Read(err)),
         };
         if count == 0 { // 0 means EOF package
-            break;
+            return Ok(());
+        }
+        decoder.extend(&buf[..count]);
+
+        while let Some(frame) = decoder.next_frame()? {
+            let resp = handle_command(&frame.payload, store, pool, policy, &mut tx).encode();
+            stream
+                .write_all(&Frame::encode_response(frame.request_id, resp))
+                .map_err(ClientError::Write)?;
+        }
+    }
+}
+
+/// Moves store.db to `target` and prints each step, or only the pending
+/// ones on a dry run.
+fn migrate(conn: &mut Connection, dir: &Path, target: Option<u32>, dry_run: bool) -> Result<(), MigrationError> {
+    let migrations = migrate::load(dir)?;
+    let steps = migrate::run(conn, &migrations, target, dry_run)?;
+    if steps.is_empty() {
+        println!("Schema is up to date");
+    }
+    for step in &steps {
+        if dry_run {
+            println!("Pending migration: {}", step);
+        } else {
+            println!("Migrated: {}", step);
+        }
+    }
+    Ok(())
+}
+
+/// `store migrate [<version>] [--dry-run]`: migrates store.db to `version`,
+/// the newest migration by default and 0 to undo them all. Store must not be
+/// running, as it reads the tables' columns for its policy only at startup;
+/// holding store.bin open, which sled refuses to share, keeps the two apart.
+/// A dry run changes nothing and works either way.
+fn migrate_command(config: &StoreConfig, args: &[&str]) {
+    let mut target = None;
+    let mut dry_run = false;
+    for arg in args {
+        match (*arg, arg.parse()) {
+            ("--dry-run", _) => dry_run = true,
+            (_, Ok(version)) if target.is_none() => target = Some(version),
+            _ => usage(),
         }
-        let cmd = buf[0];
-        println!("Got: {:?}", cmd);
-        let bucket = match get_bucket::<Vec<u8>, Vec<u8>>(&store) {
-            Ok(b) => b,
-            Err(flag) => {
-                stream.write_all(&[cmd, flag]).unwrap();
-                continue;
+    }
+    let _stopped = if dry_run {
+        None
+    } else {
+        match Store::new(Config::new(&config.data)) {
+            Ok(store) => Some(store),
+            Err(err) => {
+                println!("Failed to open {}, is store still running? {:?}", config.data.display(), err);
+                process::exit(1);
             }
-        };
-        if count < 17 {
-            stream.write_all(&[cmd, 136]).unwrap();
-            println!("Bad length!");
-            continue;
         }
-        let key = &buf[1..17].to_vec();
-        match cmd {
-            1 => { // Define new statement
-                let value = bucket.get(key);
-                match value {
-                    Ok(res) => {
-                        match res {
-                            Some(val) => {
-                                let mut resp: Vec<u8> = vec![cmd, 0];
-                                resp.append(&mut val.clone());
-                                stream.write_all(&resp).unwrap();
-                            },
-                            None => {
-                                stream.write_all(&[cmd, 132]).unwrap();
-                            }
-                        }
-                    },
-                    Err(err) => {
-                        stream.write_all(&[cmd, 133]).unwrap();
-                        println!("{:?}", err);
-                    }
-                }
-            },
-            _ => {
-                println!("UNKNOWN CMD!");
-                stream.write_all(&[cmd, 128]).unwrap();
-            },
+    };
+    let mut conn = match Connection::open(&config.database) {
+        Ok(conn) => conn,
+        Err(err) => {
+            println!("Failed to open {}: {}", config.database.display(), err);
+            process::exit(1);
         }
+    };
+    if let Err(err) = migrate(&mut conn, &config.migrations, target, dry_run) {
+        println!("Migration failed: {}", err);
+        process::exit(1);
     }
-    println!("DONE! {:?}", addr);
-    println!("Done");
 }
 
-fn main() {
-    let socket = Path::new("/tmp/sentinel/store.sock");
+fn usage() -> ! {
+    println!("usage: store [migrate [<version>] [--dry-run]]");
+    process::exit(2);
+}
 
-    if socket.exists() {
-        fs::remove_file(&socket).unwrap();
+fn main() {
+    let config = match StoreConfig::load() {
+        Ok(config) => config,
+        Err(err) => {
+            println!("Invalid config: {}", err);
+            process::exit(2);
+        }
+    };
+    if let Err(err) = config.create_dirs() {
+        println!("{}", err);
+        process::exit(1);
+    }
+    let args: Vec<String> = std::env::args().collect();
+    match args.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+        [_, "migrate", rest @ ..] => return migrate_command(&config, rest),
+        [_] => {},
+        _ => usage(),
     }
 
-    let manager = SqliteConnectionManager::file("/tmp/sentinel/store.db");
-    let pool = r2d2::Pool::new(manager).unwrap();
+    let manager = SqliteConnectionManager::file(&config.database);
+    // Every worker may pin a connection for a transaction.
+    let pool = r2d2::Pool::builder()
+        .max_size(config.max_connections as u32)
+        .build(manager)
+        .unwrap();
+    let policy = {
+        let mut conn = match pool.get() {
+            Err(err) => panic!("failed to open {}: {}", config.database.display(), err),
+            Ok(conn) => conn,
+        };
+        // Only checks the applied migrations and lists the pending ones
+        // unless migrate_on_start is set.
+        if let Err(err) = migrate(&mut conn, &config.migrations, None, !config.migrate_on_start) {
+            println!("Refusing to start: {}", err);
+            process::exit(1);
+        }
+        match Policy::load(&conn, &config.tables) {
+            Ok(policy) => Arc::new(policy),
+            Err(err) => {
+                println!("Failed to read the schema: {}", err);
+                process::exit(1);
+            },
+        }
+    };
+
+    let socket = config.socket.as_path();
+    let listener = match daemon::bind(socket) {
+        Err(err) => panic!("failed to bind socket: {}", err),
+        Ok(listener) => listener,
+    };
+    let shutdown = match Shutdown::on_signal(socket.to_path_buf()) {
+        Err(err) => panic!("failed to install signal handlers: {}", err),
+        Ok(shutdown) => shutdown,
+    };
 
-    let cfg = Config::new("/tmp/sentinel/store.bin");
+    let cfg = Config::new(&config.data);
     let store = Arc::new(RwLock::new(Store::new(cfg).unwrap()));
 
-    let listener = match UnixListener::bind(&socket) {
-        Err(_) => panic!("failed to bind socket"),
-        Ok(listener) => listener,
+    let workers = {
+        let pool = pool.clone();
+        let store = Arc::clone(&store);
+        let config = Arc::new(config.clone());
+        WorkerPool::new("store", config.max_connections, config.max_queued, move |stream| {
+            handle_client(stream, pool.clone(), Arc::clone(&store), Arc::clone(&policy), Arc::clone(&config));
+        })
+    };
+    let workers = match workers {
+        Err(err) => panic!("failed to start workers: {}", err),
+        Ok(workers) => workers,
     };
+    workers.report_every(config.metrics_interval);
 
-    println!("Star started, waiting for clients");
+    println!("Store started, waiting for clients");
 
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                stream.set_read_timeout(Some(Duration::from_secs(30))).unwrap();
-                let pool = pool.clone();
-                let store_instance = Arc::clone(&store);
-                thread::spawn(move || handle_client(stream, pool, store_instance));
-            }
-            Err(err) => {
-                println!("Error before spawn");
-                println!("{:?}", err);
-            }
+    workers.accept(&listener, &shutdown, config.read_timeout);
+
+    let busy = shutdown.drain(config.shutdown_timeout);
+    if busy > 0 {
+        println!("{} clients still busy after {:?}, closing anyway", busy, config.shutdown_timeout);
+    }
+    if let Ok(bucket) = get_bucket::<Vec<u8>, Vec<u8>>(&store) {
+        if let Err(err) = bucket.flush() {
+            println!("Failed to flush: {:?}", err);
         }
     }
+    // Closes the idle SQLite connections; connections still lent out close
+    // when their client thread ends, and idle workers let go of their pool
+    // handle once the queue is gone.
+    drop(workers);
+    drop(pool);
+    if let Err(err) = fs::remove_file(socket) {
+        println!("Failed to remove {}: {}", socket.display(), err);
+    }
+    println!("Store stopped");
 }
 
 
@@ -0,0 +1,378 @@
+// Schema migrations for store.db.
+//
+// Migrations are pairs of `<version>_<name>.up.sql` and
+// `<version>_<name>.down.sql` in the migrations directory, applied in
+// version order. Each applied version is recorded in `schema_migrations`
+// with a checksum of both files, and nothing runs while an applied
+// migration has been edited or removed since.
+//
+// A run is one transaction, so either the whole plan applies or none of it
+// does. Migration files must not open or commit transactions themselves.
+
+use std::{
+    collections::BTreeMap,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use rusqlite::{params, Connection, TransactionBehavior};
+
+const CREATE_TABLE: &str = "CREATE TABLE IF NOT EXISTS schema_migrations (
+    version INTEGER PRIMARY KEY,
+    name TEXT NOT NULL,
+    checksum TEXT NOT NULL,
+    applied_at INTEGER NOT NULL
+)";
+
+#[derive(Debug)]
+pub struct Migration {
+    pub version: u32,
+    pub name: String,
+    up: String,
+    down: String,
+    checksum: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Up,
+    Down,
+}
+
+/// One migration of a plan, in the direction it runs.
+#[derive(Debug)]
+pub struct Step<'a> {
+    pub migration: &'a Migration,
+    pub direction: Direction,
+}
+
+impl fmt::Display for Step<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let direction = match self.direction {
+            Direction::Up => "up",
+            Direction::Down => "down",
+        };
+        write!(f, "{} {} {}", direction, self.migration.version, self.migration.name)
+    }
+}
+
+#[derive(Debug)]
+pub enum MigrationError {
+    Read(PathBuf, io::Error),
+    /// A `.sql` file that is not named `<version>_<name>.up.sql` or
+    /// `.down.sql`.
+    BadName(PathBuf),
+    Duplicate { version: u32 },
+    /// Only one of the up and down files exists.
+    Unpaired { version: u32, name: String },
+    Edited { version: u32, name: String },
+    Missing { version: u32, name: String },
+    /// A pending migration sorts before one that is already applied.
+    OutOfOrder { version: u32, name: String, applied: u32 },
+    UnknownTarget(u32),
+    Failed { step: String, err: rusqlite::Error },
+    Sqlite(rusqlite::Error),
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MigrationError::Read(path, err) => write!(f, "failed to read {}: {}", path.display(), err),
+            MigrationError::BadName(path) => write!(
+                f, "{} is not named <version>_<name>.up.sql or <version>_<name>.down.sql", path.display(),
+            ),
+            MigrationError::Duplicate { version } => write!(f, "version {} is used by more than one migration", version),
+            MigrationError::Unpaired { version, name } => write!(
+                f, "migration {} {} needs both an up and a down file", version, name,
+            ),
+            MigrationError::Edited { version, name } => write!(f, "applied migration {} {} has been edited", version, name),
+            MigrationError::Missing { version, name } => write!(f, "applied migration {} {} is missing", version, name),
+            MigrationError::OutOfOrder { version, name, applied } => write!(
+                f, "migration {} {} is older than the applied version {}", version, name, applied,
+            ),
+            MigrationError::UnknownTarget(version) => write!(f, "there is no migration {}", version),
+            MigrationError::Failed { step, err } => write!(f, "{} failed: {}", step, err),
+            MigrationError::Sqlite(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for MigrationError {
+    fn from(err: rusqlite::Error) -> Self {
+        MigrationError::Sqlite(err)
+    }
+}
+
+/// A row of `schema_migrations`.
+struct Applied {
+    version: u32,
+    name: String,
+    checksum: String,
+}
+
+/// 64-bit FNV-1a over both files, so editing either one is noticed.
+fn checksum(up: &str, down: &str) -> String {
+    const OFFSET: u64 = 0xcbf29ce484222325;
+    const PRIME: u64 = 0x100000001b3;
+    let bytes = up.bytes().chain([0]).chain(down.bytes());
+    format!("{:016x}", bytes.fold(OFFSET, |hash, byte| (hash ^ u64::from(byte)).wrapping_mul(PRIME)))
+}
+
+/// Splits `0002_add_email.up.sql` into `(2, "add_email", Up)`. Version 0
+/// is taken, it stands for the empty schema.
+fn parse_file_name(file: &str) -> Option<(u32, &str, Direction)> {
+    let (stem, direction) = match file.strip_suffix(".up.sql") {
+        Some(stem) => (stem, Direction::Up),
+        None => (file.strip_suffix(".down.sql")?, Direction::Down),
+    };
+    let (version, name) = stem.split_once('_')?;
+    if name.is_empty() || !version.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    match version.parse() {
+        Ok(0) | Err(_) => None,
+        Ok(version) => Some((version, name, direction)),
+    }
+}
+
+/// Reads every migration in `dir`, sorted by version. A missing directory
+/// holds no migrations; other files than `.sql` ones are ignored.
+pub fn load(dir: &Path) -> Result<Vec<Migration>, MigrationError> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(MigrationError::Read(dir.to_path_buf(), err)),
+    };
+    let mut files: BTreeMap<u32, (String, Option<String>, Option<String>)> = BTreeMap::new();
+    for entry in entries {
+        let path = entry.map_err(|err| MigrationError::Read(dir.to_path_buf(), err))?.path();
+        let Some(file) = path.file_name().and_then(|file| file.to_str()) else {
+            continue;
+        };
+        if !file.ends_with(".sql") {
+            continue;
+        }
+        let Some((version, name, direction)) = parse_file_name(file) else {
+            return Err(MigrationError::BadName(path));
+        };
+        let sql = fs::read_to_string(&path).map_err(|err| MigrationError::Read(path.clone(), err))?;
+        let pair = files.entry(version).or_insert_with(|| (name.to_string(), None, None));
+        if pair.0 != name {
+            return Err(MigrationError::Duplicate { version });
+        }
+        match direction {
+            Direction::Up => pair.1 = Some(sql),
+            Direction::Down => pair.2 = Some(sql),
+        }
+    }
+    files
+        .into_iter()
+        .map(|(version, pair)| match pair {
+            (name, Some(up), Some(down)) => Ok(Migration { version, checksum: checksum(&up, &down), name, up, down }),
+            (name, _, _) => Err(MigrationError::Unpaired { version, name }),
+        })
+        .collect()
+}
+
+fn applied(conn: &Connection) -> Result<Vec<Applied>, MigrationError> {
+    let mut stmt = conn.prepare("SELECT version, name, checksum FROM schema_migrations ORDER BY version")?;
+    let rows = stmt.query_map([], |row| {
+        Ok(Applied { version: row.get(0)?, name: row.get(1)?, checksum: row.get(2)? })
+    })?;
+    Ok(rows.collect::<Result<_, _>>()?)
+}
+
+/// Every applied migration must still be on disk, unchanged, and nothing
+/// pending may sort before the newest applied one.
+fn verify(migrations: &[Migration], applied: &[Applied]) -> Result<(), MigrationError> {
+    for row in applied {
+        match migrations.iter().find(|m| m.version == row.version) {
+            None => return Err(MigrationError::Missing { version: row.version, name: row.name.clone() }),
+            Some(m) if m.checksum != row.checksum => {
+                return Err(MigrationError::Edited { version: m.version, name: m.name.clone() });
+            },
+            Some(_) => {},
+        }
+    }
+    let current = applied.last().map_or(0, |row| row.version);
+    for m in migrations {
+        if m.version < current && !applied.iter().any(|row| row.version == m.version) {
+            return Err(MigrationError::OutOfOrder { version: m.version, name: m.name.clone(), applied: current });
+        }
+    }
+    Ok(())
+}
+
+/// The steps that take the schema from its applied version to `target`,
+/// the newest migration when `None`, or 0 to undo everything.
+fn plan<'a>(
+    migrations: &'a [Migration],
+    applied: &[Applied],
+    target: Option<u32>,
+) -> Result<Vec<Step<'a>>, MigrationError> {
+    let current = applied.last().map_or(0, |row| row.version);
+    let target = target.unwrap_or_else(|| migrations.last().map_or(0, |m| m.version));
+    if target != 0 && !migrations.iter().any(|m| m.version == target) {
+        return Err(MigrationError::UnknownTarget(target));
+    }
+    Ok(if target >= current {
+        migrations
+            .iter()
+            .filter(|m| m.version > current && m.version <= target)
+            .map(|migration| Step { migration, direction: Direction::Up })
+            .collect()
+    } else {
+        migrations
+            .iter()
+            .rev()
+            .filter(|m| m.version > target && m.version <= current)
+            .map(|migration| Step { migration, direction: Direction::Down })
+            .collect()
+    })
+}
+
+/// Verifies the applied migrations and moves the schema to `target`,
+/// returning the steps taken. A dry run only returns the plan.
+pub fn run<'a>(
+    conn: &mut Connection,
+    migrations: &'a [Migration],
+    target: Option<u32>,
+    dry_run: bool,
+) -> Result<Vec<Step<'a>>, MigrationError> {
+    // Taking the write lock up front keeps a second run, from the CLI say,
+    // from planning against the same versions.
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    tx.execute(CREATE_TABLE, [])?;
+    let applied = applied(&tx)?;
+    verify(migrations, &applied)?;
+    let steps = plan(migrations, &applied, target)?;
+    if dry_run {
+        return Ok(steps);
+    }
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs() as i64);
+    for step in &steps {
+        let m = step.migration;
+        let result = match step.direction {
+            Direction::Up => tx.execute_batch(&m.up).and_then(|_| tx.execute(
+                "INSERT INTO schema_migrations (version, name, checksum, applied_at) VALUES (?1, ?2, ?3, ?4)",
+                params![m.version, m.name, m.checksum, now],
+            )),
+            Direction::Down => tx.execute_batch(&m.down).and_then(|_| tx.execute(
+                "DELETE FROM schema_migrations WHERE version = ?1",
+                params![m.version],
+            )),
+        };
+        if let Err(err) = result {
+            return Err(MigrationError::Failed { step: step.to_string(), err });
+        }
+    }
+    tx.commit()?;
+    Ok(steps)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn migration(version: u32, name: &str, up: &str, down: &str) -> Migration {
+        Migration {
+            version,
+            name: name.to_string(),
+            up: up.to_string(),
+            down: down.to_string(),
+            checksum: checksum(up, down),
+        }
+    }
+
+    fn migrations() -> Vec<Migration> {
+        vec![
+            migration(1, "users", "CREATE TABLE users (id INTEGER PRIMARY KEY);", "DROP TABLE users;"),
+            migration(2, "add_email", "ALTER TABLE users ADD COLUMN email TEXT;", "ALTER TABLE users DROP COLUMN email;"),
+        ]
+    }
+
+    fn versions(conn: &Connection) -> Vec<u32> {
+        applied(conn).unwrap().iter().map(|row| row.version).collect()
+    }
+
+    fn has_table(conn: &Connection, table: &str) -> bool {
+        let sql = "SELECT count(*) FROM sqlite_master WHERE type = 'table' AND name = ?1";
+        conn.query_row(sql, [table], |row| row.get::<_, i64>(0)).unwrap() == 1
+    }
+
+    fn steps(steps: &[Step]) -> Vec<String> {
+        steps.iter().map(Step::to_string).collect()
+    }
+
+    #[test]
+    fn applies_pending_migrations() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        let migrations = migrations();
+        let taken = run(&mut conn, &migrations, None, false).unwrap();
+        assert_eq!(steps(&taken), ["up 1 users", "up 2 add_email"]);
+        assert_eq!(versions(&conn), [1, 2]);
+        conn.execute("INSERT INTO users (id, email) VALUES (1, 'a@b')", []).unwrap();
+        assert!(run(&mut conn, &migrations, None, false).unwrap().is_empty());
+    }
+
+    #[test]
+    fn refuses_edited_migrations() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        run(&mut conn, &migrations(), Some(1), false).unwrap();
+        let mut edited = migrations();
+        edited[0] = migration(1, "users", "CREATE TABLE users (id INTEGER PRIMARY KEY, name TEXT);", "DROP TABLE users;");
+        let err = run(&mut conn, &edited, None, false).unwrap_err();
+        assert!(matches!(err, MigrationError::Edited { version: 1, .. }), "{}", err);
+        assert_eq!(versions(&conn), [1]);
+
+        let err = run(&mut conn, &edited[1..], None, false).unwrap_err();
+        assert!(matches!(err, MigrationError::Missing { version: 1, .. }), "{}", err);
+    }
+
+    #[test]
+    fn moves_down_to_a_target() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        let migrations = migrations();
+        run(&mut conn, &migrations, None, false).unwrap();
+        let taken = run(&mut conn, &migrations, Some(1), false).unwrap();
+        assert_eq!(steps(&taken), ["down 2 add_email"]);
+        assert_eq!(versions(&conn), [1]);
+        let taken = run(&mut conn, &migrations, Some(0), false).unwrap();
+        assert_eq!(steps(&taken), ["down 1 users"]);
+        assert!(versions(&conn).is_empty());
+        assert!(!has_table(&conn, "users"));
+    }
+
+    #[test]
+    fn failed_step_keeps_nothing() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        let mut migrations = migrations();
+        migrations.push(migration(3, "broken", "CREATE TABLE orders (id INTEGER); NOT SQL;", "DROP TABLE orders;"));
+        let err = run(&mut conn, &migrations, None, false).unwrap_err();
+        assert!(matches!(err, MigrationError::Failed { .. }), "{}", err);
+        assert!(!has_table(&conn, "users"));
+        assert!(!has_table(&conn, "orders"));
+    }
+
+    #[test]
+    fn dry_run_only_plans() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        let migrations = migrations();
+        let planned = run(&mut conn, &migrations, None, true).unwrap();
+        assert_eq!(steps(&planned), ["up 1 users", "up 2 add_email"]);
+        assert!(!has_table(&conn, "users"));
+        assert!(!has_table(&conn, "schema_migrations"));
+
+        run(&mut conn, &migrations, None, false).unwrap();
+        let planned = run(&mut conn, &migrations, Some(0), true).unwrap();
+        assert_eq!(steps(&planned), ["down 2 add_email", "down 1 users"]);
+        assert_eq!(versions(&conn), [1, 2]);
+    }
+
+    #[test]
+    fn rejects_unknown_targets() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        let err = run(&mut conn, &migrations(), Some(7), true).unwrap_err();
+        assert!(matches!(err, MigrationError::UnknownTarget(7)), "{}", err);
+    }
+}
@@ -0,0 +1,568 @@
+// What DEFINE lets through: exactly one SELECT, INSERT, UPDATE or DELETE
+// over tables listed for the statement's role, calling only the functions
+// in `FUNCTIONS`, with every value passed as a declared variable. Schema
+// changes only run as migrations. EXEC then runs the statement only for
+// callers holding that role.
+//
+// The SQL is parsed with sqlparser's SQLite dialect and walked by hand.
+// Anything the walk does not know is rejected rather than let through
+// unchecked, so new syntax needs a deliberate change here.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use rusqlite::Connection;
+use sqlparser::{
+    ast::{
+        Assignment, Expr, Function, FunctionArg, FunctionArgExpr, JoinConstraint, JoinOperator, ObjectName, OnConflictAction,
+        OnInsert, Query, Select, SelectItem, SetExpr, Statement, TableFactor, TableWithJoins, Value,
+    },
+    dialect::SQLiteDialect,
+    parser::Parser,
+    tokenizer::{Token, Tokenizer},
+};
+use sentinel_proto::store::Rejection;
+
+/// Lowercased names of the scalar and aggregate functions statements may
+/// call. Anything reaching outside the database (`load_extension`) or
+/// making large values out of small ones (`randomblob`, `zeroblob`) is
+/// left out.
+const FUNCTIONS: &[&str] = &[
+    "abs", "avg", "char", "coalesce", "count", "date", "datetime", "glob", "group_concat", "hex", "ifnull", "iif",
+    "instr", "json", "json_array", "json_array_length", "json_extract", "json_object", "json_type", "json_valid",
+    "julianday", "length", "like", "lower", "ltrim", "max", "min", "nullif", "quote", "replace", "round", "rtrim",
+    "sign", "strftime", "substr", "substring", "sum", "time", "total", "trim", "typeof", "unhex", "unicode",
+    "unixepoch", "upper",
+];
+
+pub struct Policy {
+    /// Lowercased table names by role.
+    tables: HashMap<String, HashSet<String>>,
+    /// Lowercased column names by lowercased table name.
+    columns: HashMap<String, HashSet<String>>,
+}
+
+impl Policy {
+    /// Reads the columns of every listed table from `conn`, so the schema
+    /// must be migrated first.
+    pub fn load(conn: &Connection, tables: &BTreeMap<String, Vec<String>>) -> rusqlite::Result<Policy> {
+        let tables: HashMap<String, HashSet<String>> = tables
+            .iter()
+            .map(|(role, tables)| (role.clone(), tables.iter().map(|table| table.to_lowercase()).collect()))
+            .collect();
+        let mut query = conn.prepare("SELECT name FROM pragma_table_info(?1)")?;
+        let mut columns = HashMap::new();
+        for table in tables.values().flatten() {
+            let names = query
+                .query_map([table], |row| row.get::<_, String>(0))?
+                .map(|name| name.map(|name| name.to_lowercase()))
+                .collect::<rusqlite::Result<HashSet<_>>>()?;
+            columns.insert(table.clone(), names);
+        }
+        Ok(Policy { tables, columns })
+    }
+
+    /// Checks a statement `role` wants to register with variables `vars`.
+    pub fn check(&self, role: &str, sql: &str, vars: &[String]) -> Result<(), Rejection> {
+        let allowed = self.tables.get(role).ok_or_else(|| Rejection::UnknownRole { role: role.to_owned() })?;
+        let statement = parse_one(sql)?;
+        let mut check = Check { role, allowed, columns: &self.columns, vars, used: HashSet::new(), ctes: Vec::new() };
+        check.statement(&statement)?;
+        match vars.iter().find(|var| !check.used.contains(var.as_str())) {
+            Some(unused) => Err(Rejection::UnusedVariable { name: unused.clone() }),
+            None => Ok(()),
+        }
+    }
+}
+
+/// sqlparser's SQLite dialect does not parse ATTACH, DETACH or PRAGMA at
+/// all, so they are picked out by their first word before parsing.
+fn forbidden_command(sql: &str) -> Result<(), Rejection> {
+    let tokens = Tokenizer::new(&SQLiteDialect {}, sql)
+        .tokenize()
+        .map_err(|err| Rejection::Syntax { message: err.to_string() })?;
+    let mut starts_statement = true;
+    for token in tokens {
+        match token {
+            // Comments included.
+            Token::Whitespace(_) => continue,
+            Token::SemiColon => {
+                starts_statement = true;
+                continue;
+            },
+            Token::Word(word) if starts_statement && word.quote_style.is_none() => {
+                match word.value.to_uppercase().as_str() {
+                    "ATTACH" | "DETACH" => return Err(Rejection::Attach),
+                    "PRAGMA" => return Err(Rejection::Pragma),
+                    _ => {},
+                }
+            },
+            _ => {},
+        }
+        starts_statement = false;
+    }
+    Ok(())
+}
+
+fn parse_one(sql: &str) -> Result<Statement, Rejection> {
+    forbidden_command(sql)?;
+    let mut statements = Parser::parse_sql(&SQLiteDialect {}, sql)
+        .map_err(|err| Rejection::Syntax { message: err.to_string() })?;
+    match statements.len() {
+        1 => Ok(statements.remove(0)),
+        0 => Err(Rejection::Syntax { message: "no statement".to_owned() }),
+        count => Err(Rejection::MultipleStatements { count }),
+    }
+}
+
+/// The statement's leading keywords, e.g. "CREATE TABLE", for rejections.
+fn kind(statement: &Statement) -> String {
+    statement.to_string().split_whitespace().take(2).collect::<Vec<_>>().join(" ").to_uppercase()
+}
+
+fn unsupported(construct: impl ToString) -> Rejection {
+    Rejection::Unsupported { construct: construct.to_string() }
+}
+
+struct Check<'a> {
+    role: &'a str,
+    allowed: &'a HashSet<String>,
+    columns: &'a HashMap<String, HashSet<String>>,
+    vars: &'a [String],
+    used: HashSet<&'a str>,
+    /// Names of the CTEs in scope, innermost last.
+    ctes: Vec<String>,
+}
+
+impl<'a> Check<'a> {
+    fn statement(&mut self, statement: &Statement) -> Result<(), Rejection> {
+        match statement {
+            Statement::Query(query) => self.query(query),
+            Statement::Insert { table_name, source, on, returning, partitioned, .. } => {
+                if partitioned.is_some() {
+                    return Err(unsupported("PARTITION"));
+                }
+                self.table(table_name)?;
+                self.query(source)?;
+                match on {
+                    Some(OnInsert::OnConflict(conflict)) => {
+                        if let OnConflictAction::DoUpdate(update) = &conflict.action {
+                            self.assignments(&update.assignments)?;
+                            self.opt_expr(update.selection.as_ref())?;
+                        }
+                    },
+                    Some(OnInsert::DuplicateKeyUpdate(assignments)) => self.assignments(assignments)?,
+                    Some(other) => return Err(unsupported(other)),
+                    None => {},
+                }
+                self.items(returning.as_deref().unwrap_or_default())
+            },
+            Statement::Update { table, assignments, from, selection, returning } => {
+                self.from(table)?;
+                self.assignments(assignments)?;
+                if let Some(from) = from {
+                    self.from(from)?;
+                }
+                self.opt_expr(selection.as_ref())?;
+                self.items(returning.as_deref().unwrap_or_default())
+            },
+            Statement::Delete { tables, from, using, selection, returning } => {
+                for table in tables {
+                    self.table(table)?;
+                }
+                for from in from.iter().chain(using.iter().flatten()) {
+                    self.from(from)?;
+                }
+                self.opt_expr(selection.as_ref())?;
+                self.items(returning.as_deref().unwrap_or_default())
+            },
+            Statement::CreateTable { .. }
+            | Statement::CreateVirtualTable { .. }
+            | Statement::CreateView { .. }
+            | Statement::CreateIndex { .. }
+            | Statement::AlterTable { .. }
+            | Statement::AlterIndex { .. }
+            | Statement::AlterView { .. }
+            | Statement::Drop { .. }
+            | Statement::Truncate { .. } => Err(Rejection::Ddl { statement: kind(statement) }),
+            other => Err(Rejection::UnsupportedStatement { statement: kind(other) }),
+        }
+    }
+
+    fn query(&mut self, query: &Query) -> Result<(), Rejection> {
+        if query.fetch.is_some() || !query.locks.is_empty() {
+            return Err(unsupported(query));
+        }
+        let scope = self.ctes.len();
+        if let Some(with) = &query.with {
+            for cte in &with.cte_tables {
+                let name = cte.alias.name.value.to_lowercase();
+                // A recursive CTE refers to itself, a plain one only to
+                // those before it.
+                if with.recursive {
+                    self.ctes.push(name);
+                    self.query(&cte.query)?;
+                } else {
+                    self.query(&cte.query)?;
+                    self.ctes.push(name);
+                }
+            }
+        }
+        self.set_expr(&query.body)?;
+        for order in &query.order_by {
+            self.expr(&order.expr)?;
+        }
+        self.opt_expr(query.limit.as_ref())?;
+        self.opt_expr(query.offset.as_ref().map(|offset| &offset.value))?;
+        self.ctes.truncate(scope);
+        Ok(())
+    }
+
+    fn set_expr(&mut self, body: &SetExpr) -> Result<(), Rejection> {
+        match body {
+            SetExpr::Select(select) => self.select(select),
+            SetExpr::Query(query) => self.query(query),
+            SetExpr::SetOperation { left, right, .. } => {
+                self.set_expr(left)?;
+                self.set_expr(right)
+            },
+            SetExpr::Values(values) => values.rows.iter().flatten().try_for_each(|expr| self.expr(expr)),
+            other => Err(unsupported(other)),
+        }
+    }
+
+    fn select(&mut self, select: &Select) -> Result<(), Rejection> {
+        let unchecked = select.top.is_some()
+            || select.into.is_some()
+            || !select.lateral_views.is_empty()
+            || !select.cluster_by.is_empty()
+            || !select.distribute_by.is_empty()
+            || !select.sort_by.is_empty()
+            || !select.named_window.is_empty()
+            || select.qualify.is_some();
+        if unchecked {
+            return Err(unsupported(select));
+        }
+        for from in &select.from {
+            self.from(from)?;
+        }
+        self.items(&select.projection)?;
+        self.opt_expr(select.selection.as_ref())?;
+        for expr in &select.group_by {
+            self.expr(expr)?;
+        }
+        self.opt_expr(select.having.as_ref())
+    }
+
+    /// A projection or a RETURNING clause.
+    fn items(&mut self, items: &[SelectItem]) -> Result<(), Rejection> {
+        for item in items {
+            match item {
+                SelectItem::UnnamedExpr(expr) | SelectItem::ExprWithAlias { expr, .. } => self.expr(expr)?,
+                SelectItem::QualifiedWildcard(..) | SelectItem::Wildcard(_) => {},
+            }
+        }
+        Ok(())
+    }
+
+    fn from(&mut self, from: &TableWithJoins) -> Result<(), Rejection> {
+        self.table_factor(&from.relation)?;
+        for join in &from.joins {
+            self.table_factor(&join.relation)?;
+            let constraint = match &join.join_operator {
+                JoinOperator::Inner(constraint)
+                | JoinOperator::LeftOuter(constraint)
+                | JoinOperator::RightOuter(constraint)
+                | JoinOperator::FullOuter(constraint) => constraint,
+                JoinOperator::CrossJoin => continue,
+                other => return Err(unsupported(format!("{:?}", other))),
+            };
+            if let JoinConstraint::On(expr) = constraint {
+                self.expr(expr)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn table_factor(&mut self, factor: &TableFactor) -> Result<(), Rejection> {
+        match factor {
+            // Table-valued functions such as json_each are checked like
+            // tables, so they need listing too.
+            TableFactor::Table { name, args, with_hints, .. } => {
+                if !with_hints.is_empty() {
+                    return Err(unsupported(factor));
+                }
+                self.table(name)?;
+                for arg in args.iter().flatten() {
+                    self.function_arg(arg)?;
+                }
+                Ok(())
+            },
+            TableFactor::Derived { subquery, .. } => self.query(subquery),
+            TableFactor::NestedJoin { table_with_joins, .. } => self.from(table_with_joins),
+            other => Err(unsupported(other)),
+        }
+    }
+
+    /// Tables are matched case-insensitively; only the `main` schema is
+    /// allowed as a qualifier.
+    fn table(&mut self, name: &ObjectName) -> Result<(), Rejection> {
+        let table = match name.0.as_slice() {
+            [table] => table.value.to_lowercase(),
+            [schema, table] if schema.value.eq_ignore_ascii_case("main") => table.value.to_lowercase(),
+            _ => return Err(Rejection::TableNotAllowed { table: name.to_string(), role: self.role.to_owned() }),
+        };
+        if name.0.len() == 1 && self.ctes.contains(&table) {
+            return Ok(());
+        }
+        if !self.allowed.contains(&table) {
+            return Err(Rejection::TableNotAllowed { table: name.to_string(), role: self.role.to_owned() });
+        }
+        Ok(())
+    }
+
+    fn assignments(&mut self, assignments: &[Assignment]) -> Result<(), Rejection> {
+        assignments.iter().try_for_each(|assignment| self.expr(&assignment.value))
+    }
+
+    fn opt_expr(&mut self, expr: Option<&Expr>) -> Result<(), Rejection> {
+        expr.map_or(Ok(()), |expr| self.expr(expr))
+    }
+
+    fn function_arg(&mut self, arg: &FunctionArg) -> Result<(), Rejection> {
+        match arg {
+            FunctionArg::Named { arg: FunctionArgExpr::Expr(expr), .. }
+            | FunctionArg::Unnamed(FunctionArgExpr::Expr(expr)) => self.expr(expr),
+            _ => Ok(()),
+        }
+    }
+
+    fn placeholder(&mut self, placeholder: &str) -> Result<(), Rejection> {
+        let name = match placeholder.chars().next() {
+            Some(':' | '@' | '$') => &placeholder[1..],
+            _ => return Err(Rejection::PositionalPlaceholder { placeholder: placeholder.to_owned() }),
+        };
+        match self.vars.iter().find(|var| *var == name) {
+            Some(var) => {
+                self.used.insert(var);
+                Ok(())
+            },
+            None => Err(Rejection::UndeclaredVariable { name: name.to_owned() }),
+        }
+    }
+
+    /// Whether `name` is a column of some table the role may use. Columns
+    /// of CTEs and subqueries do not count.
+    fn is_column(&self, name: &str) -> bool {
+        let name = name.to_lowercase();
+        self.allowed
+            .iter()
+            .any(|table| self.columns.get(table).is_some_and(|columns| columns.contains(&name)))
+    }
+
+    fn function(&mut self, function: &Function) -> Result<(), Rejection> {
+        let allowed = match function.name.0.as_slice() {
+            [name] => FUNCTIONS.contains(&name.value.to_lowercase().as_str()),
+            _ => false,
+        };
+        if !allowed {
+            return Err(Rejection::FunctionNotAllowed { function: function.name.to_string() });
+        }
+        if function.over.is_some() {
+            return Err(unsupported(function));
+        }
+        for arg in &function.args {
+            self.function_arg(arg)?;
+        }
+        function.order_by.iter().try_for_each(|order| self.expr(&order.expr))
+    }
+
+    fn expr(&mut self, expr: &Expr) -> Result<(), Rejection> {
+        match expr {
+            // SQLite binds `$name` as a parameter, sqlparser reads it as
+            // an identifier.
+            Expr::Identifier(ident) if ident.quote_style.is_none() && ident.value.starts_with('$') => {
+                self.placeholder(&ident.value)
+            },
+            // SQLite falls back to reading it as a string literal.
+            Expr::Identifier(ident) if ident.quote_style == Some('"') && !self.is_column(&ident.value) => {
+                Err(Rejection::QuotedLiteral { name: ident.value.clone() })
+            },
+            Expr::Identifier(_) | Expr::CompoundIdentifier(_) => Ok(()),
+            Expr::Value(Value::Placeholder(placeholder)) => self.placeholder(placeholder),
+            Expr::Value(Value::Null | Value::Boolean(_)) => Ok(()),
+            Expr::Value(literal) => Err(Rejection::Literal { literal: literal.to_string() }),
+            Expr::IsFalse(expr)
+            | Expr::IsNotFalse(expr)
+            | Expr::IsTrue(expr)
+            | Expr::IsNotTrue(expr)
+            | Expr::IsNull(expr)
+            | Expr::IsNotNull(expr)
+            | Expr::IsUnknown(expr)
+            | Expr::IsNotUnknown(expr)
+            | Expr::Nested(expr)
+            | Expr::UnaryOp { expr, .. }
+            | Expr::Cast { expr, .. }
+            | Expr::Collate { expr, .. } => self.expr(expr),
+            Expr::IsDistinctFrom(left, right)
+            | Expr::IsNotDistinctFrom(left, right)
+            | Expr::BinaryOp { left, right, .. } => {
+                self.expr(left)?;
+                self.expr(right)
+            },
+            Expr::Like { expr, pattern, .. } | Expr::ILike { expr, pattern, .. } => {
+                self.expr(expr)?;
+                self.expr(pattern)
+            },
+            Expr::Between { expr, low, high, .. } => {
+                self.expr(expr)?;
+                self.expr(low)?;
+                self.expr(high)
+            },
+            Expr::InList { expr, list, .. } => {
+                self.expr(expr)?;
+                list.iter().try_for_each(|item| self.expr(item))
+            },
+            Expr::InSubquery { expr, subquery, .. } => {
+                self.expr(expr)?;
+                self.query(subquery)
+            },
+            Expr::Exists { subquery, .. } | Expr::Subquery(subquery) => self.query(subquery),
+            Expr::Tuple(items) => items.iter().try_for_each(|item| self.expr(item)),
+            Expr::Case { operand, conditions, results, else_result } => {
+                self.opt_expr(operand.as_deref())?;
+                conditions.iter().chain(results).try_for_each(|expr| self.expr(expr))?;
+                self.opt_expr(else_result.as_deref())
+            },
+            Expr::Function(function) => self.function(function),
+            other => Err(unsupported(other)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> Policy {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE users (id INTEGER PRIMARY KEY, name TEXT, \"Email\" TEXT);
+             CREATE TABLE secrets (id INTEGER PRIMARY KEY, value TEXT);",
+        )
+        .unwrap();
+        let tables = BTreeMap::from([("app".to_string(), vec!["Users".to_string()])]);
+        Policy::load(&conn, &tables).unwrap()
+    }
+
+    fn check(sql: &str, vars: &[&str]) -> Result<(), Rejection> {
+        let vars: Vec<String> = vars.iter().map(|var| var.to_string()).collect();
+        policy().check("app", sql, &vars)
+    }
+
+    #[test]
+    fn allows_checked_statements() {
+        check("SELECT id, name FROM users WHERE id = :id", &["id"]).unwrap();
+        check("SELECT count(*), max(\"id\") FROM main.users WHERE \"email\" = $email", &["email"]).unwrap();
+        check("INSERT INTO users (id, name) VALUES (:id, upper(:name)) RETURNING id", &["id", "name"]).unwrap();
+        check("UPDATE users SET name = :name WHERE id IN (SELECT id FROM users WHERE name IS NULL)", &["name"]).unwrap();
+        check("WITH recent AS (SELECT id FROM users) SELECT id FROM recent", &[]).unwrap();
+    }
+
+    #[test]
+    fn rejects_syntax_errors() {
+        assert!(matches!(check("SELEC id FROM users", &[]), Err(Rejection::Syntax { .. })));
+        assert!(matches!(check("", &[]), Err(Rejection::Syntax { .. })));
+    }
+
+    #[test]
+    fn rejects_multiple_statements() {
+        let rejection = check("SELECT id FROM users; DELETE FROM users", &[]);
+        assert_eq!(rejection, Err(Rejection::MultipleStatements { count: 2 }));
+    }
+
+    #[test]
+    fn rejects_attach_and_pragma() {
+        assert_eq!(check("ATTACH DATABASE 'x.db' AS x", &[]), Err(Rejection::Attach));
+        assert_eq!(check("SELECT 1 FROM users; detach x", &[]), Err(Rejection::Attach));
+        assert_eq!(check("/* */ PRAGMA writable_schema = 1", &[]), Err(Rejection::Pragma));
+    }
+
+    #[test]
+    fn rejects_ddl() {
+        let rejection = check("DROP TABLE users", &[]);
+        assert_eq!(rejection, Err(Rejection::Ddl { statement: "DROP TABLE".to_string() }));
+    }
+
+    #[test]
+    fn rejects_other_statements() {
+        assert!(matches!(check("BEGIN", &[]), Err(Rejection::UnsupportedStatement { .. })));
+        assert!(matches!(check("EXPLAIN SELECT id FROM users", &[]), Err(Rejection::UnsupportedStatement { .. })));
+    }
+
+    #[test]
+    fn rejects_unsupported_constructs() {
+        let rejection = check("SELECT row_number() OVER (ORDER BY id) FROM users", &[]);
+        assert!(matches!(rejection, Err(Rejection::FunctionNotAllowed { .. })));
+        let rejection = check("SELECT count(*) OVER (ORDER BY id) FROM users", &[]);
+        assert!(matches!(rejection, Err(Rejection::Unsupported { .. })));
+    }
+
+    #[test]
+    fn rejects_unknown_roles() {
+        let rejection = policy().check("admin", "SELECT id FROM users", &[]);
+        assert_eq!(rejection, Err(Rejection::UnknownRole { role: "admin".to_string() }));
+    }
+
+    #[test]
+    fn rejects_unlisted_tables() {
+        let not_allowed = |table: &str| Err(Rejection::TableNotAllowed { table: table.to_string(), role: "app".to_string() });
+        assert_eq!(check("SELECT value FROM secrets", &[]), not_allowed("secrets"));
+        assert_eq!(check("SELECT id FROM temp.users", &[]), not_allowed("temp.users"));
+        assert_eq!(check("SELECT id FROM users WHERE id IN (SELECT id FROM secrets)", &[]), not_allowed("secrets"));
+        assert_eq!(check("SELECT key FROM json_each(:doc)", &["doc"]), not_allowed("json_each"));
+    }
+
+    #[test]
+    fn rejects_literals() {
+        let rejection = check("SELECT id FROM users WHERE name = 'root'", &[]);
+        assert_eq!(rejection, Err(Rejection::Literal { literal: "'root'".to_string() }));
+        assert!(matches!(check("SELECT id FROM users LIMIT 1", &[]), Err(Rejection::Literal { .. })));
+    }
+
+    #[test]
+    fn rejects_double_quoted_strings() {
+        let rejection = check("SELECT id FROM users WHERE name = \"root\"", &[]);
+        assert_eq!(rejection, Err(Rejection::QuotedLiteral { name: "root".to_string() }));
+        // A column only of a table the role may not use.
+        let rejection = check("SELECT id FROM users WHERE name = \"value\"", &[]);
+        assert_eq!(rejection, Err(Rejection::QuotedLiteral { name: "value".to_string() }));
+    }
+
+    #[test]
+    fn rejects_functions_not_listed() {
+        for sql in [
+            "SELECT load_extension(:path) FROM users",
+            "SELECT randomblob(:size) FROM users",
+            "SELECT id FROM users WHERE name = main.lower(:name)",
+        ] {
+            assert!(matches!(check(sql, &["path", "size", "name"]), Err(Rejection::FunctionNotAllowed { .. })), "{}", sql);
+        }
+    }
+
+    #[test]
+    fn rejects_positional_placeholders() {
+        let rejection = check("SELECT id FROM users WHERE id = ?", &[]);
+        assert_eq!(rejection, Err(Rejection::PositionalPlaceholder { placeholder: "?".to_string() }));
+    }
+
+    #[test]
+    fn rejects_undeclared_variables() {
+        let rejection = check("SELECT id FROM users WHERE id = :id", &[]);
+        assert_eq!(rejection, Err(Rejection::UndeclaredVariable { name: "id".to_string() }));
+    }
+
+    #[test]
+    fn rejects_unused_variables() {
+        let rejection = check("SELECT id FROM users", &["id"]);
+        assert_eq!(rejection, Err(Rejection::UnusedVariable { name: "id".to_string() }));
+    }
+}
@@ -0,0 +1,129 @@
+// Named statement registry: DEFINE, LIST and DROP over the `Default`
+// bucket, which maps each statement's ID to its JSON-encoded `Statement`.
+
+use std::collections::HashSet;
+use kv::Bucket;
+use sentinel_proto::{
+    store::{is_valid_name, is_valid_var, statement_id, Command, Response, Statement},
+    Key, Status,
+};
+
+use crate::policy::Policy;
+
+pub type Statements<'a> = Bucket<'a, Vec<u8>, Vec<u8>>;
+
+fn decode(raw: &[u8]) -> Result<Statement, Status> {
+    serde_json::from_slice(raw).map_err(|err| {
+        println!("Bad statement record: {}", err);
+        Status::ReadFailed
+    })
+}
+
+fn flush(bucket: &Statements) {
+    if let Err(err) = bucket.flush() {
+        println!("Failed to flush statements: {:?}", err);
+    }
+}
+
+/// Registers `name` once `policy` accepts it for `role`, replacing the
+/// statement it had before. The ID stays the same, so clients holding it
+/// keep working.
+pub fn define(
+    bucket: &Statements,
+    policy: &Policy,
+    name: String,
+    role: String,
+    statement: String,
+    vars: Vec<String>,
+) -> Response {
+    let cmd = Command::Define;
+    if !is_valid_name(&name) || statement.trim().is_empty() {
+        return Response::error(cmd, Status::Malformed);
+    }
+    let mut seen = HashSet::new();
+    if !vars.iter().all(|var| is_valid_var(var) && seen.insert(var)) {
+        return Response::error(cmd, Status::Malformed);
+    }
+    if let Err(rejection) = policy.check(&role, &statement, &vars) {
+        println!("Rejected statement {}: {}", name, rejection);
+        return Response::Rejected(rejection);
+    }
+    let id = statement_id(&name);
+    let existing = match bucket.get(&id.to_vec()) {
+        Ok(Some(raw)) => decode(&raw).map(Some),
+        Ok(None) => Ok(None),
+        Err(err) => {
+            println!("{:?}", err);
+            Err(Status::ReadFailed)
+        },
+    };
+    match existing {
+        // Two names hashing to the same ID; never overwrite the other one.
+        Ok(Some(existing)) if existing.name != name => {
+            println!("Statement {} collides with {}", name, existing.name);
+            return Response::error(cmd, Status::Conflict);
+        },
+        Ok(_) => {},
+        Err(status) => return Response::error(cmd, status),
+    }
+    println!("Defining statement {}", name);
+    // Plain strings and byte arrays cannot fail to serialize.
+    let record = serde_json::to_vec(&Statement { id, name, role, statement, vars }).unwrap_or_default();
+    match bucket.set(&id.to_vec(), &record) {
+        Ok(_) => {
+            flush(bucket);
+            Response::Define { id }
+        },
+        Err(err) => {
+            println!("{:?}", err);
+            Response::error(cmd, Status::WriteFailed)
+        },
+    }
+}
+
+/// The statement registered under `id`, for EXEC.
+pub fn get(bucket: &Statements, id: Key) -> Result<Statement, Status> {
+    match bucket.get(&id.to_vec()) {
+        Ok(Some(raw)) => decode(&raw),
+        Ok(None) => Err(Status::NotFound),
+        Err(err) => {
+            println!("{:?}", err);
+            Err(Status::ReadFailed)
+        },
+    }
+}
+
+/// Every registered statement, ordered by ID.
+pub fn list(bucket: &Statements) -> Response {
+    let mut statements = Vec::new();
+    for item in bucket.iter() {
+        let raw = match item.and_then(|item| item.value::<Vec<u8>>()) {
+            Ok(raw) => raw,
+            Err(err) => {
+                println!("{:?}", err);
+                return Response::error(Command::List, Status::ReadFailed);
+            },
+        };
+        match decode(&raw) {
+            Ok(statement) => statements.push(statement),
+            Err(status) => return Response::error(Command::List, status),
+        }
+    }
+    Response::List(statements)
+}
+
+pub fn drop(bucket: &Statements, id: Key) -> Response {
+    match bucket.remove(&id.to_vec()) {
+        Ok(Some(raw)) => {
+            let name = decode(&raw).map(|statement| statement.name).unwrap_or_default();
+            println!("Dropped statement {}", name);
+            flush(bucket);
+            Response::Drop
+        },
+        Ok(None) => Response::error(Command::Drop, Status::NotFound),
+        Err(err) => {
+            println!("{:?}", err);
+            Response::error(Command::Drop, Status::DeleteFailed)
+        },
+    }
+}
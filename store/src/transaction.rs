@@ -0,0 +1,103 @@
+// BEGIN, COMMIT and ROLLBACK. A transaction pins one pooled SQLite
+// connection to the client that began it, and that client's EXECs run on
+// it until COMMIT or ROLLBACK. Dropping a transaction that is still open,
+// because its client hung up or went idle, rolls it back.
+
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+use r2d2::{Pool, PooledConnection};
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::Connection;
+use sentinel_proto::{
+    store::{Command, Response},
+    Status,
+};
+
+/// Ends the connection of a client that left a transaction open and went
+/// quiet for the given time.
+#[derive(Debug)]
+pub struct IdleTransaction(pub Duration);
+
+impl fmt::Display for IdleTransaction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "transaction idle for {:?}", self.0)
+    }
+}
+
+pub struct Transaction {
+    conn: PooledConnection<SqliteConnectionManager>,
+    started: Instant,
+}
+
+impl Transaction {
+    pub fn conn(&self) -> &Connection {
+        &self.conn
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        if self.conn.is_autocommit() {
+            return;
+        }
+        match self.conn.execute_batch("ROLLBACK") {
+            Ok(()) => println!("Rolled back transaction open for {:?}", self.started.elapsed()),
+            // SQLite already rolled back on its own, or the connection
+            // is broken and r2d2 will not hand it out again.
+            Err(err) => println!("Failed to roll back: {}", err),
+        }
+    }
+}
+
+/// Takes the write lock right away, so two transactions never deadlock
+/// upgrading their read locks.
+pub fn begin(pool: &Pool<SqliteConnectionManager>, tx: &mut Option<Transaction>) -> Response {
+    let cmd = Command::Begin;
+    if tx.is_some() {
+        return Response::error(cmd, Status::TransactionOpen);
+    }
+    let conn = match pool.get() {
+        Ok(conn) => conn,
+        Err(err) => {
+            println!("{}", err);
+            return Response::error(cmd, Status::StoreUnavailable);
+        },
+    };
+    if let Err(err) = conn.execute_batch("BEGIN IMMEDIATE") {
+        println!("{}", err);
+        return Response::error(cmd, Status::QueryFailed);
+    }
+    *tx = Some(Transaction { conn, started: Instant::now() });
+    Response::Begin
+}
+
+/// A COMMIT that fails still ends the transaction, it is rolled back.
+pub fn commit(tx: &mut Option<Transaction>) -> Response {
+    let cmd = Command::Commit;
+    let Some(tx) = tx.take() else {
+        return Response::error(cmd, Status::NoTransaction);
+    };
+    match tx.conn.execute_batch("COMMIT") {
+        Ok(()) => Response::Commit,
+        Err(err) => {
+            println!("{}", err);
+            Response::error(cmd, Status::QueryFailed)
+        },
+    }
+}
+
+pub fn rollback(tx: &mut Option<Transaction>) -> Response {
+    let cmd = Command::Rollback;
+    let Some(tx) = tx.take() else {
+        return Response::error(cmd, Status::NoTransaction);
+    };
+    match tx.conn.execute_batch("ROLLBACK") {
+        Ok(()) => Response::Rollback,
+        Err(err) => {
+            println!("{}", err);
+            Response::error(cmd, Status::QueryFailed)
+        },
+    }
+}